@@ -1,6 +1,11 @@
 use std::fs;
 use std::path::Path;
 
+const PROTO_FILE: &str = "proto/messages.proto";
+const GENERATED_FILE: &str = "src/generated/messages.rs";
+// sidecar recording which .proto the committed generated code was produced from
+const HASH_FILE: &str = "src/generated/messages.proto.hash";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     //out_dir is the directory where the generated code will be saved else it will be saved ..
     // /home/instavc/Desktop/socketserver_project/rust_socket/target/debug/build/rust_socket-526eb674674253c1/out/messages.rs
@@ -9,16 +14,79 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !Path::new(out_dir).exists() {
         fs::create_dir_all(out_dir)?;
     }
+    // once any rerun-if line is printed cargo stops watching the whole package,
+    // so the inputs have to be listed explicitly
+    println!("cargo:rerun-if-changed={}", PROTO_FILE);
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=PROTO_STALE_CHECK");
+
+    // remember what the generated file looked like before regenerating
+    let previous = fs::read_to_string(HASH_FILE).ok();
+
     prost_build::Config::new()
         .out_dir(out_dir)
-        .compile_protos(&["proto/messages.proto"], &["proto/"])?;
+        .compile_protos(&[PROTO_FILE], &["proto/"])?;
       //for first argument, we pass the path to the proto file
         //for second argument, we pass the path to the directory containing the proto file
         //this is because the proto file is not in the same directory as the build.rs file
         //so we need to pass the path to the directory containing the proto file
         //? means error → return error immediately | success → continue
-    Ok(())
+
+    check_generated_is_fresh(previous.as_deref())
 }
 
+// prost-build only rewrites messages.rs when its output changes, so a regeneration
+// that silently didn't happen looks exactly like "nothing to do". To catch it we keep
+// a sidecar with the hash of the .proto and of the generated file from the last run:
+// if the .proto changed but the generated file is byte-for-byte the old one, the
+// compiled code is stale.
+// PROTO_STALE_CHECK=warn turns the failure into a cargo warning (default: error)
+fn check_generated_is_fresh(previous: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let proto_hash = fnv1a(&normalize_whitespace(&fs::read_to_string(PROTO_FILE)?));
+    let generated_hash = fnv1a(&fs::read_to_string(GENERATED_FILE)?);
+    let current = format!("proto={:016x}\ngenerated={:016x}\n", proto_hash, generated_hash);
+
+    if let Some(previous) = previous {
+        let field = |name: &str| {
+            previous
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_string)
+        };
+        let proto_changed = field("proto") != Some(format!("{:016x}", proto_hash));
+        let generated_unchanged = field("generated") == Some(format!("{:016x}", generated_hash));
+
+        if proto_changed && generated_unchanged {
+            let message = format!(
+                "{} changed but {} was not regenerated; the build would compile stale protobuf code \
+                 (delete {} to accept the current files)",
+                PROTO_FILE, GENERATED_FILE, HASH_FILE
+            );
+            if std::env::var("PROTO_STALE_CHECK").as_deref() == Ok("warn") {
+                // leave the sidecar alone so the warning keeps showing until it's fixed
+                println!("cargo:warning={}", message);
+                return Ok(());
+            }
+            return Err(message.into());
+        }
+    }
 
+    if previous != Some(current.as_str()) {
+        fs::write(HASH_FILE, current)?;
+    }
+    Ok(())
+}
 
+// whitespace-only edits to the .proto don't change the generated code, so they
+// shouldn't count as a change
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// small stable hash (FNV-1a, 64 bit); std's DefaultHasher isn't guaranteed to stay
+// the same between Rust versions, and the sidecar is committed
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,751 @@
+use axum::{
+    extract::{
+        rejection::JsonRejection,
+        ws::{close_code, CloseFrame, Message as WsMessage},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::api_error::ApiError;
+use crate::auth;
+use crate::config::{Secret, ServerConfig};
+use crate::generated::Envelope;
+use crate::history;
+use crate::metrics::MetricsSnapshot;
+use crate::room_rates::RoomRate;
+use crate::rooms;
+use crate::runtime_metrics::{self, RuntimeSnapshot};
+use crate::scheduler::ScheduledAnnouncement;
+use crate::shadow::ShadowCopy;
+use crate::{
+    broadcast_system, notification, now_ms, queue_server_message, reload_config, selftest, send_server_message,
+    system_notification, AppState, Client, ConnectionState,
+};
+
+// HTTP API routes, mounted next to /ws on the same listener
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/selftest", get(selftest_handler))
+        .route("/api/announce", post(announce_handler))
+        .route("/api/metrics", get(metrics_handler))
+        .route("/api/debug/runtime", get(runtime_metrics_handler))
+        .route("/api/stats", get(stats_handler))
+        .route("/api/peers", get(peers_handler))
+        .route("/api/tokens", post(token_handler))
+        .route("/api/history/export", get(history_export_handler))
+        .route("/api/schedule", post(schedule_handler).get(list_schedule_handler))
+        .route("/api/schedule/:id", delete(cancel_schedule_handler))
+        .route("/api/maintenance", post(maintenance_handler).get(maintenance_status_handler))
+        .route("/api/admin/command", post(admin_command_handler))
+        .route("/api/config/reload", post(reload_config_handler))
+        .route("/api/shadow", get(shadow_handler))
+        .route("/api/rooms/:room", put(room_settings_handler))
+        .route("/api/rooms/:room/disconnect", post(disconnect_room_handler))
+}
+
+// Admin endpoints require `Authorization: Bearer <ADMIN_TOKEN>`.
+// With no ADMIN_TOKEN configured the admin API is disabled entirely.
+fn require_admin(headers: &HeaderMap, config: &ServerConfig) -> Result<(), ApiError> {
+    require_bearer(headers, config.admin_token.as_ref(), "ADMIN_TOKEN")
+}
+
+// Checks `Authorization: Bearer <token>` against a configured secret.
+// `setting` names the config value, for the "disabled" message.
+fn require_bearer(headers: &HeaderMap, expected: Option<&Secret>, setting: &str) -> Result<(), ApiError> {
+    let Some(expected) = expected else {
+        let message = format!("endpoint is disabled ({} not set)", setting);
+        return Err(ApiError::forbidden(message).with_request_id(headers));
+    };
+    let provided = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if provided.is_some_and(|provided| auth::secret_matches(expected, provided)) {
+        Ok(())
+    } else {
+        warn!("Rejected {} request: missing or wrong token", setting);
+        Err(ApiError::unauthorized("valid bearer token required").with_request_id(headers))
+    }
+}
+
+// Turns axum's plain-text JSON body rejection into our error shape
+fn json_body<T>(body: Result<Json<T>, JsonRejection>, headers: &HeaderMap) -> Result<T, ApiError> {
+    body.map(|Json(value)| value).map_err(|rejection| {
+        ApiError::new(rejection.status(), "invalid_body", rejection.body_text()).with_request_id(headers)
+    })
+}
+
+// GET /api/selftest - end-to-end check of the realtime path (admin only)
+async fn selftest_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<selftest::SelfTestReport>, ApiError> {
+    let config = state.config.current();
+    require_admin(&headers, &config)?;
+    info!("Running self-test against {}", state.listen_addr);
+    Ok(Json(selftest::run(state.listen_addr, &state.shadow, &config).await))
+}
+
+// GET /api/metrics - counters and broadcast fan-out latency percentiles (admin only)
+async fn metrics_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<MetricsSnapshot>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    Ok(Json(state.metrics.snapshot()))
+}
+
+// GET /api/debug/runtime - tokio worker, task and queue metrics (admin only)
+async fn runtime_metrics_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<RuntimeSnapshot>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    Ok(Json(runtime_metrics::snapshot()))
+}
+
+#[derive(Serialize)]
+struct PeersResponse {
+    total: usize,
+    peers: Vec<PeerSummary>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PeerSummary {
+    peer_id: String,
+    display_name: String,
+    room: String,
+    connected_at_ms: u64,
+    // Within the reconnect grace period: socket gone, still shown as present
+    reconnecting: bool,
+}
+
+// GET /api/peers - everyone currently online, in every room, sorted by peer
+// id (admin only)
+async fn peers_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<PeersResponse>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let mut peers: Vec<PeerSummary> = state
+        .peers
+        .lock()
+        .await
+        .values()
+        .map(|peer| PeerSummary {
+            peer_id: peer.peer_id.clone(),
+            display_name: peer.display_name.clone(),
+            room: peer.room.clone(),
+            connected_at_ms: peer.connected_at_ms,
+            reconnecting: peer.connection_state == ConnectionState::Reconnecting,
+        })
+        .collect();
+    peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+    Ok(Json(PeersResponse { total: peers.len(), peers }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenRequest {
+    peer_id: String,
+    // Omitted = never expires
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    token: String,
+    // Unix seconds, 0 = never
+    expires_at: u64,
+}
+
+// POST /api/tokens {"peerId": "...", "ttlSecs": 3600} - mints a client token
+// for the WebSocket handshake (see auth.rs) (admin only). For backends that
+// would rather not hold CLIENT_TOKEN_SECRET themselves.
+async fn token_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    body: Result<Json<TokenRequest>, JsonRejection>,
+) -> Result<Json<TokenResponse>, ApiError> {
+    let config = state.config.current();
+    require_admin(&headers, &config)?;
+    let Some(secret) = &config.client_token_secret else {
+        return Err(ApiError::forbidden("client tokens are disabled (CLIENT_TOKEN_SECRET not set)").with_request_id(&headers));
+    };
+    let request = json_body(body, &headers)?;
+    if let Err(reason) = state.peer_id_rules.validate(&request.peer_id) {
+        return Err(ApiError::bad_request("invalid_peer_id", reason).with_request_id(&headers));
+    }
+
+    let expires_at = request.ttl_secs.map_or(0, |ttl| (now_ms() / 1000).saturating_add(ttl.max(1)));
+    let token = auth::issue(secret, &request.peer_id, expires_at);
+    info!("Issued client token for {}", request.peer_id);
+    Ok(Json(TokenResponse { token, expires_at }))
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    rooms: Vec<RoomRate>,
+}
+
+// GET /api/stats - chat message rates per recently active room, busiest
+// first (admin only)
+async fn stats_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<StatsResponse>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    Ok(Json(StatsResponse { rooms: state.room_rates.snapshot() }))
+}
+
+#[derive(Deserialize)]
+struct AnnounceRequest {
+    message: String,
+    // Retransmit to peers with the reliable capability until they ack
+    // (see reliable.rs)
+    #[serde(default, rename = "requireAck")]
+    require_ack: bool,
+}
+
+#[derive(Serialize)]
+struct AnnounceResponse {
+    delivered: usize,
+}
+
+// POST /api/announce {"message": "...", "requireAck": false} - system announcement to every connected
+// peer, deliberately ignoring any room/tenant scoping. Because it crosses those
+// boundaries it needs its own ANNOUNCE_TOKEN rather than the admin token.
+async fn announce_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    body: Result<Json<AnnounceRequest>, JsonRejection>,
+) -> Result<Json<AnnounceResponse>, ApiError> {
+    require_bearer(&headers, state.config.current().announce_token.as_ref(), "ANNOUNCE_TOKEN")?;
+    let request = json_body(body, &headers)?;
+    if request.message.trim().is_empty() {
+        return Err(ApiError::bad_request("empty_message", "message must not be empty").with_request_id(&headers));
+    }
+
+    let delivered = broadcast_system(&state, &request.message, request.require_ack).await;
+    Ok(Json(AnnounceResponse { delivered }))
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+    room: Option<String>,
+}
+
+// GET /api/history/export?format=json|csv[&room=name] - download the in-memory
+// chat history as a file (admin only), optionally only one room's messages.
+// The export is bounded by CHAT_HISTORY_SIZE, the buffer holds nothing older.
+// 404 when there is nothing to export.
+async fn history_export_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let format = query.format.as_deref().unwrap_or("json");
+    if format != "json" && format != "csv" {
+        let message = format!("unsupported format '{}' (expected json or csv)", format);
+        return Err(ApiError::bad_request("invalid_format", message).with_request_id(&headers));
+    }
+
+    let mut entries = state.history.snapshot();
+    if let Some(room) = &query.room {
+        entries.retain(|entry| &entry.room == room);
+    }
+    if entries.is_empty() {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "no_history", "no chat history to export")
+            .with_request_id(&headers));
+    }
+
+    let disposition = format!("attachment; filename=\"chat-history-{}.{}\"", now_ms(), format);
+    info!("Exported {} chat messages as {}", entries.len(), format);
+    let attachment = [(header::CONTENT_DISPOSITION, disposition)];
+    if format == "csv" {
+        let csv_type = [(header::CONTENT_TYPE, "text/csv; charset=utf-8")];
+        Ok((attachment, csv_type, history::to_csv(&entries)).into_response())
+    } else {
+        Ok((attachment, Json(entries)).into_response())
+    }
+}
+
+// Either a delay or an absolute time
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleRequest {
+    message: String,
+    delay_secs: Option<u64>,
+    send_at_ms: Option<u64>,
+}
+
+// POST /api/schedule {"message": "...", "delaySecs": 600 | "sendAtMs": ...}
+// Queues a system broadcast to every peer (admin only). Returns its id for
+// DELETE /api/schedule/{id}. Pending broadcasts are not persisted and are
+// lost if the server restarts.
+async fn schedule_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    body: Result<Json<ScheduleRequest>, JsonRejection>,
+) -> Result<Json<ScheduledAnnouncement>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let request = json_body(body, &headers)?;
+    if request.message.trim().is_empty() {
+        return Err(ApiError::bad_request("empty_message", "message must not be empty").with_request_id(&headers));
+    }
+    let send_at_ms = match (request.delay_secs, request.send_at_ms) {
+        (Some(delay), None) => now_ms().saturating_add(delay.saturating_mul(1000)),
+        (None, Some(at)) => at,
+        _ => {
+            let message = "exactly one of delaySecs or sendAtMs is required";
+            return Err(ApiError::bad_request("invalid_schedule", message).with_request_id(&headers));
+        }
+    };
+
+    let announcement = state.scheduler.schedule(state.clone(), request.message, send_at_ms);
+    info!(
+        "Scheduled announcement {} for {} ms from now",
+        announcement.id,
+        send_at_ms.saturating_sub(now_ms())
+    );
+    Ok(Json(announcement))
+}
+
+// GET /api/schedule - pending scheduled broadcasts, soonest first (admin only)
+async fn list_schedule_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ScheduledAnnouncement>>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    Ok(Json(state.scheduler.list()))
+}
+
+// DELETE /api/schedule/{id} - cancel a pending broadcast (admin only)
+async fn cancel_schedule_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ScheduledAnnouncement>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    match state.scheduler.cancel(&id) {
+        Some(announcement) => {
+            info!("Cancelled scheduled announcement {}", id);
+            Ok(Json(announcement))
+        }
+        None => {
+            let message = format!("no pending announcement '{}'", id);
+            Err(ApiError::new(StatusCode::NOT_FOUND, "not_found", message).with_request_id(&headers))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MaintenanceStatus {
+    maintenance: bool,
+    // Chat peers plus tunnel ends still connected
+    active_connections: usize,
+}
+
+async fn maintenance_status(state: &AppState) -> MaintenanceStatus {
+    let peers = state.peers.lock().await.len();
+    let tunnel_ends: usize = state.tunnels.lock().await.values().map(Vec::len).sum();
+    MaintenanceStatus {
+        maintenance: state.maintenance.load(Ordering::Relaxed),
+        active_connections: peers + tunnel_ends,
+    }
+}
+
+// POST /api/maintenance {"enabled": true|false} - refuse (or accept again) new
+// WebSocket connections without touching existing ones (admin only).
+// Returns the new state and how many connections are left, to watch the drain.
+async fn maintenance_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    body: Result<Json<MaintenanceRequest>, JsonRejection>,
+) -> Result<Json<MaintenanceStatus>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let request = json_body(body, &headers)?;
+    Ok(Json(set_maintenance(&state, request.enabled).await))
+}
+
+async fn set_maintenance(state: &AppState, enabled: bool) -> MaintenanceStatus {
+    let was = state.maintenance.swap(enabled, Ordering::Relaxed);
+    if was != enabled {
+        info!(
+            "Maintenance mode {}",
+            if enabled { "ON: refusing new connections" } else { "OFF" }
+        );
+    }
+    maintenance_status(state).await
+}
+
+// GET /api/maintenance - current maintenance state and connection count (admin only)
+async fn maintenance_status_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<MaintenanceStatus>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    Ok(Json(maintenance_status(&state).await))
+}
+
+// Body of POST /api/admin/command, e.g. {"command": "kick", "peerId": "alice"}.
+// New admin operations go here as new variants instead of new routes.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", rename_all_fields = "camelCase")]
+enum AdminCommand {
+    Kick { peer_id: String, reason: Option<String> },
+    Broadcast { message: String },
+    SetMaintenance { enabled: bool },
+    // Disconnects everyone in the room
+    DrainRoom { room: String, reason: Option<String> },
+    ReloadConfig,
+}
+
+// Reply: {"command": "<same name>", "result": {...}}
+#[derive(Serialize)]
+#[serde(tag = "command", content = "result", rename_all = "snake_case", rename_all_fields = "camelCase")]
+enum CommandResult {
+    Kick { kicked: String },
+    Broadcast { delivered: usize },
+    SetMaintenance(MaintenanceStatus),
+    DrainRoom { room: String, disconnected: usize },
+    ReloadConfig(ReloadResult),
+}
+
+// POST /api/admin/command - one typed entry point for admin operations (admin only)
+async fn admin_command_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    body: Result<Json<AdminCommand>, JsonRejection>,
+) -> Result<Json<CommandResult>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let result = match json_body(body, &headers)? {
+        AdminCommand::Kick { peer_id, reason } => {
+            if !kick_peer(&state, &peer_id, reason.as_deref()).await {
+                let message = format!("peer '{}' is not connected", peer_id);
+                return Err(ApiError::new(StatusCode::NOT_FOUND, "not_found", message).with_request_id(&headers));
+            }
+            CommandResult::Kick { kicked: peer_id }
+        }
+        AdminCommand::Broadcast { message } => {
+            if message.trim().is_empty() {
+                let error = ApiError::bad_request("empty_message", "message must not be empty");
+                return Err(error.with_request_id(&headers));
+            }
+            CommandResult::Broadcast {
+                delivered: broadcast_system(&state, &message, false).await,
+            }
+        }
+        AdminCommand::SetMaintenance { enabled } => CommandResult::SetMaintenance(set_maintenance(&state, enabled).await),
+        AdminCommand::DrainRoom { room, reason } => {
+            let disconnected = disconnect_room(&state, &room, reason.as_deref()).await;
+            CommandResult::DrainRoom { room, disconnected }
+        }
+        AdminCommand::ReloadConfig => CommandResult::ReloadConfig(reload(&state, &headers)?),
+    };
+    Ok(Json(result))
+}
+
+// Bounds the notice and Close sent to a removed peer, so one that doesn't
+// read can't hold up the admin request
+const KICK_SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Tells the peer why, then starts the close handshake. The peer's own receive
+// loop sees the client's Close reply and does the usual cleanup. False when
+// the peer isn't connected, including one inside its reconnect grace period:
+// there is no socket to close.
+async fn kick_peer(state: &AppState, peer_id: &str, reason: Option<&str>) -> bool {
+    let client = state
+        .peers
+        .lock()
+        .await
+        .get(peer_id)
+        .filter(|peer| peer.connection_state == ConnectionState::Connected)
+        .map(|peer| peer.sender.clone());
+    let Some(client) = client else {
+        return false;
+    };
+    let reason = reason.unwrap_or("removed by an administrator");
+    info!("Kicking {}: {}", peer_id, reason);
+    let notice = system_notification(&format!("You were disconnected: {}", reason));
+    close_with_notice(peer_id, &client, &notice, "kicked").await;
+    true
+}
+
+// Body of PUT /api/rooms/{room}; absent fields stay as they are
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomSettings {
+    // "" opens the room
+    password: Option<String>,
+    // Shown in list_rooms; "" clears it
+    topic: Option<String>,
+    // Peer id patterns allowed to pin messages; [] lets everyone
+    pinners: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomStatus {
+    room: String,
+    protected: bool,
+    peer_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pinners: Option<Vec<String>>,
+}
+
+// PUT /api/rooms/{room} - configure a room, before anyone joins or while in
+// use (admin only). {"password": "..."} protects it and {"password": ""}
+// opens it; peers already inside stay either way (see rooms.rs).
+// {"topic": "..."} sets the topic list_rooms shows, "" clears it.
+// {"pinners": ["mod_*"]} limits who may pin messages, [] lets everyone.
+async fn room_settings_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    body: Result<Json<RoomSettings>, JsonRejection>,
+) -> Result<Json<RoomStatus>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    if let Err(reason) = state.peer_id_rules.validate(&room) {
+        return Err(ApiError::bad_request("invalid_room", reason.replace("peerId", "room")).with_request_id(&headers));
+    }
+    let settings = json_body(body, &headers)?;
+    if settings.topic.as_ref().is_some_and(|topic| topic.chars().count() > rooms::MAX_TOPIC_CHARS) {
+        let message = format!("topic is longer than {} characters", rooms::MAX_TOPIC_CHARS);
+        return Err(ApiError::bad_request("invalid_topic", message).with_request_id(&headers));
+    }
+    if let Some(topic) = &settings.topic {
+        state.rooms.set_topic(&room, Some(topic));
+    }
+    if settings.pinners.is_some() {
+        state.rooms.set_pinners(&room, settings.pinners);
+    }
+    if let Some(password) = &settings.password {
+        state.rooms.set_password(&room, Some(password));
+        info!("Room {} is now {}", room, if password.is_empty() { "open" } else { "password-protected" });
+    }
+    let peer_count = state.peers.lock().await.values().filter(|peer| peer.room == room).count();
+    Ok(Json(RoomStatus {
+        protected: state.rooms.is_protected(&room),
+        topic: state.rooms.topic(&room),
+        pinners: state.rooms.pinners(&room),
+        room,
+        peer_count,
+    }))
+}
+
+#[derive(Deserialize)]
+struct DisconnectRoomQuery {
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DisconnectRoomResponse {
+    room: String,
+    disconnected: usize,
+}
+
+// POST /api/rooms/{room}/disconnect[?reason=...] - disconnect everyone in a
+// room, e.g. to end a session (admin only). Same as the drain_room command.
+async fn disconnect_room_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(query): Query<DisconnectRoomQuery>,
+) -> Result<Json<DisconnectRoomResponse>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let disconnected = disconnect_room(&state, &room, query.reason.as_deref()).await;
+    Ok(Json(DisconnectRoomResponse { room, disconnected }))
+}
+
+// Disconnects every connected peer in `room`, all at once: first a
+// "room_closed" {room, reason} notification to all of them, then each gets
+// the reason as a system message and a Close, like a kick. Returns how many;
+// an empty (or unknown) room is simply 0.
+async fn disconnect_room(state: &AppState, room: &str, reason: Option<&str>) -> usize {
+    let reason = reason.unwrap_or("room closed by an administrator");
+    let mut closed_data = HashMap::new();
+    closed_data.insert("room".to_string(), room.to_string());
+    closed_data.insert("reason".to_string(), reason.to_string());
+    let teardown = notification("room_closed", closed_data);
+
+    let clients: Vec<(String, Client)> = {
+        let peers_guard = state.peers.lock().await;
+        peers_guard
+            .iter()
+            .filter(|(_, peer)| peer.room == room && peer.connection_state == ConnectionState::Connected)
+            .map(|(id, peer)| {
+                queue_server_message(&peer.sender, &teardown, &format!("room_closed → {}", id));
+                (id.clone(), peer.sender.clone())
+            })
+            .collect()
+    };
+    if clients.is_empty() {
+        return 0;
+    }
+    state.shadow.observe(&teardown);
+    info!("Disconnecting room {} ({} peers): {}", room, clients.len(), reason);
+    let notice = system_notification(&format!("You were disconnected: {}", reason));
+    let closes = clients
+        .iter()
+        .map(|(peer_id, client)| close_with_notice(peer_id, client, &notice, "room closed"));
+    futures_util::future::join_all(closes).await;
+    clients.len()
+}
+
+async fn close_with_notice(peer_id: &str, client: &Client, notice: &Envelope, reason: &'static str) {
+    let close = async {
+        send_server_message(client, notice, reason).await;
+        let _ = client
+            .send(WsMessage::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: reason.into(),
+            })))
+            .await;
+    };
+    if tokio::time::timeout(KICK_SEND_TIMEOUT, close).await.is_err() {
+        warn!("Close for {} not sent within {:?}", peer_id, KICK_SEND_TIMEOUT);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReloadResult {
+    // Changed settings that are only read at startup
+    restart_required: Vec<&'static str>,
+}
+
+// POST /api/config/reload - same as SIGHUP: re-read the config and apply it
+// if valid (admin only). 400 with the reason if the new config is rejected.
+async fn reload_config_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<ReloadResult>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    Ok(Json(reload(&state, &headers)?))
+}
+
+fn reload(state: &AppState, headers: &HeaderMap) -> Result<ReloadResult, ApiError> {
+    match reload_config(state) {
+        Ok(restart_required) => Ok(ReloadResult { restart_required }),
+        Err(e) => {
+            warn!("Config reload rejected, keeping the old config: {}", e);
+            Err(ApiError::bad_request("invalid_config", e.to_string()).with_request_id(headers))
+        }
+    }
+}
+
+// Longest capture window for GET /api/shadow
+const MAX_SHADOW_WAIT_MS: u64 = 30_000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ShadowQuery {
+    wait_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ShadowCapture {
+    copies: Vec<ShadowCopy>,
+    // Copies dropped because more arrived than the shadow buffer holds
+    missed: u64,
+}
+
+// GET /api/shadow?waitMs=5000 - attach a shadow peer for the window and
+// return every broadcast it saw (admin only). The shadow is invisible to
+// clients: it is not a connection and never counted as a peer.
+async fn shadow_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Query(query): Query<ShadowQuery>,
+) -> Result<Json<ShadowCapture>, ApiError> {
+    require_admin(&headers, &state.config.current())?;
+    let wait = std::time::Duration::from_millis(query.wait_ms.unwrap_or(5000).min(MAX_SHADOW_WAIT_MS));
+
+    let mut receiver = state.shadow.subscribe();
+    let mut capture = ShadowCapture { copies: Vec::new(), missed: 0 };
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(copy)) => capture.copies.push(copy.into()),
+            Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(missed))) => capture.missed += missed,
+            Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    info!("Shadow capture: {} broadcasts in {:?}", capture.copies.len(), wait);
+    Ok(Json(capture))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use axum::http::Method;
+    use serde_json::json;
+
+    const ADMIN: &str = "admin-secret";
+
+    async fn server() -> TestServer {
+        TestServer::start(ServerConfig { admin_token: Some(Secret::new(ADMIN)), ..Default::default() }).await
+    }
+
+    #[tokio::test]
+    async fn disconnecting_a_room_closes_only_its_peers() {
+        let server = server().await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        let mut cat = server.join("cat", "blue").await;
+
+        let (status, body) = server.http(Method::POST, "/api/rooms/red/disconnect?reason=done", Some(ADMIN), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"room": "red", "disconnected": 2}));
+
+        for client in [&mut ann, &mut bob] {
+            let closed = client.expect("room_closed").await;
+            assert_eq!(closed.data.get("room").map(String::as_str), Some("red"));
+            let notice = client.expect("system").await;
+            assert_eq!(notice.data.get("message").map(String::as_str), Some("You were disconnected: done"));
+            let close = client.expect_close().await;
+            assert_eq!(close.map(|frame| u16::from(frame.code)), Some(close_code::POLICY));
+        }
+        cat.expect_no("room_closed", Duration::from_millis(300)).await;
+    }
+
+    #[tokio::test]
+    async fn disconnecting_an_empty_room_is_a_no_op() {
+        let server = server().await;
+        let (status, body) = server.http(Method::POST, "/api/rooms/nobody/disconnect", Some(ADMIN), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"room": "nobody", "disconnected": 0}));
+    }
+
+    #[tokio::test]
+    async fn disconnecting_a_room_needs_the_admin_token() {
+        let server = server().await;
+        let _ann = server.join("ann", "red").await;
+        let (status, _) = server.http(Method::POST, "/api/rooms/red/disconnect", Some("guess"), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(server.state.peers.lock().await.contains_key("ann"));
+    }
+}
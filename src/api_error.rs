@@ -0,0 +1,139 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+// The one error shape every /api endpoint returns:
+// { "error": { "code": "...", "message": "...", "requestId": "..." } }
+// `code` is a stable machine-readable string, `message` is for humans.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            request_id: None,
+        }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    // Echo the caller's X-Request-Id back in the error body, if it sent one
+    pub fn with_request_id(mut self, headers: &HeaderMap) -> Self {
+        self.request_id = headers
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                code: self.code,
+                message: &self.message,
+                request_id: self.request_id.as_deref(),
+            },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Secret, ServerConfig};
+    use crate::testing::TestServer;
+    use axum::http::Method;
+    use serde_json::json;
+
+    async fn body_json(error: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.expect("response body");
+        (status, serde_json::from_slice(&bytes).expect("JSON body"))
+    }
+
+    #[tokio::test]
+    async fn renders_the_shared_error_shape() {
+        let (status, body) = body_json(ApiError::bad_request("invalid_room", "room is too long")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, json!({"error": {"code": "invalid_room", "message": "room is too long"}}));
+    }
+
+    #[tokio::test]
+    async fn echoes_the_request_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "req-42".parse().unwrap());
+        let (status, body) = body_json(ApiError::unauthorized("valid bearer token required").with_request_id(&headers)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "unauthorized");
+        assert_eq!(body["error"]["requestId"], "req-42");
+    }
+
+    #[tokio::test]
+    async fn endpoints_fail_with_the_shared_shape() {
+        let admin = "admin-secret";
+        let disabled = TestServer::start(ServerConfig::default()).await;
+        let (status, body) = disabled.http(Method::GET, "/api/peers", Some(admin), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "forbidden");
+
+        let config = ServerConfig {
+            admin_token: Some(Secret::new(admin)),
+            announce_token: Some(Secret::new(admin)),
+            ..Default::default()
+        };
+        let server = TestServer::start(config).await;
+        let (status, body) = server.http(Method::GET, "/api/peers", Some("wrong"), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["message"], "valid bearer token required");
+
+        let (status, body) = server.http(Method::POST, "/api/announce", Some(admin), Some(json!({"text": "hi"}))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["code"], "invalid_body");
+        let (status, body) = server.http(Method::POST, "/api/announce", Some(admin), Some(json!({"message": " "}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "empty_message");
+
+        let (status, body) = server.http(Method::DELETE, "/api/schedule/nope", Some(admin), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, json!({"error": {"code": "not_found", "message": "no pending announcement 'nope'"}}));
+    }
+}
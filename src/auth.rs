@@ -0,0 +1,80 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+
+use crate::config::Secret;
+
+// Signed client tokens for the WebSocket handshake. With client_token_secret
+// set, every upgrade must carry one (?token=..., or "Authorization: Bearer"
+// for clients that can set headers) or it gets 401, and the peerId comes
+// from the token instead of ?peerId=, so clients can't pose as each other.
+//
+// Format: <peerId>.<expiresAt>.<signature>, where expiresAt is Unix seconds
+// (0 = never) and signature is base64url(HMAC-SHA256(secret,
+// "<peerId>.<expiresAt>")). Split from the right, so a peerId may contain
+// dots. The app backend mints them with the same secret, or asks
+// POST /api/tokens.
+pub struct TokenClaims {
+    pub peer_id: String,
+}
+
+pub fn issue(secret: &Secret, peer_id: &str, expires_at: u64) -> String {
+    let claims = format!("{}.{}", peer_id, expires_at);
+    let signature = hmac::sign(&key(secret), claims.as_bytes());
+    format!("{}.{}", claims, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+}
+
+// Err is the reason, for the log; clients only learn that the token is bad
+pub fn verify(secret: &Secret, token: &str, now_secs: u64) -> Result<TokenClaims, &'static str> {
+    let (claims, signature) = token.rsplit_once('.').ok_or("malformed token")?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed signature")?;
+    // Constant-time comparison
+    hmac::verify(&key(secret), claims.as_bytes(), &signature).map_err(|_| "bad signature")?;
+
+    let (peer_id, expires_at) = claims.rsplit_once('.').ok_or("malformed token")?;
+    let expires_at: u64 = expires_at.parse().map_err(|_| "malformed expiry")?;
+    if expires_at != 0 && expires_at <= now_secs {
+        return Err("token expired");
+    }
+    if peer_id.is_empty() {
+        return Err("empty peerId");
+    }
+    Ok(TokenClaims { peer_id: peer_id.to_string() })
+}
+
+// Constant-time check of a presented secret (e.g. an admin bearer token).
+// Both sides go through HMAC keyed with the expected secret and the tags are
+// compared with hmac::verify, so the time taken says nothing about how much
+// of `provided` matched, or about the secret's length.
+pub fn secret_matches(expected: &Secret, provided: &str) -> bool {
+    let key = key(expected);
+    let expected_tag = hmac::sign(&key, expected.expose().as_bytes());
+    hmac::verify(&key, provided.as_bytes(), expected_tag.as_ref()).is_ok()
+}
+
+fn key(secret: &Secret) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.expose().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_secret_must_match_exactly() {
+        let secret = Secret::new("s3cret");
+        assert!(secret_matches(&secret, "s3cret"));
+        assert!(!secret_matches(&secret, "s3cre"));
+        assert!(!secret_matches(&secret, "s3cret2"));
+        assert!(!secret_matches(&secret, ""));
+    }
+
+    #[test]
+    fn issued_tokens_verify_until_they_expire() {
+        let secret = Secret::new("s3cret");
+        let token = issue(&secret, "alice.smith", 100);
+        assert_eq!(verify(&secret, &token, 99).map(|claims| claims.peer_id), Ok("alice.smith".to_string()));
+        assert_eq!(verify(&secret, &token, 100).err(), Some("token expired"));
+        assert_eq!(verify(&Secret::new("other"), &token, 99).err(), Some("bad signature"));
+    }
+}
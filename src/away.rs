@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::generated::{DataItem, EventData};
+use crate::history::ChatHistory;
+
+// Joins/leaves remembered for summaries; a peer away for longer than this
+// covers gets a summary marked incomplete
+const PRESENCE_LOG_SIZE: usize = 1000;
+// Departed peers remembered; past this the oldest departure is forgotten
+const MAX_TRACKED_DEPARTURES: usize = 10_000;
+// Presence changes listed in one summary (the counts still cover all of them)
+const MAX_SUMMARY_PEERS: usize = 20;
+
+// "While you were away" summaries. When a peer is gone for good (after any
+// reconnect grace period), we note how far chat history and the presence log
+// had got. If it connects again with ?caps=away_summary, it gets one
+// "away_summary" notification right after registering (and any replay):
+//   data: awaySecs, messages (chat messages relayed meanwhile), lastSeenSeq
+//         (usable as resume_from_seq), peersJoined, peersLeft, and
+//         truncated=true when not every change is listed or known
+//   items: the latest presence changes, {peerId, displayName, change}
+// Only the room the peer connects to now is summarized. Messages are counted
+// from chat history, so ones it no longer holds are missing (truncated=true).
+#[derive(Default)]
+pub struct AwayTracker {
+    // std Mutex: never held across .await
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_seq: u64,
+    events: VecDeque<PresenceEvent>,
+    departures: HashMap<String, Departure>,
+}
+
+struct PresenceEvent {
+    seq: u64,
+    change: &'static str,
+    peer_id: String,
+    display_name: String,
+    room: String,
+}
+
+struct Departure {
+    left_at: Instant,
+    last_message_id: u64,
+    // Presence log position when the peer left
+    presence_seq: u64,
+}
+
+impl AwayTracker {
+    pub fn record_joined(&self, peer_id: &str, display_name: &str, room: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.log("joined", peer_id, display_name, room);
+    }
+
+    // `last_message_id`: the newest chat messageId when the peer left
+    pub fn record_left(&self, peer_id: &str, display_name: &str, room: &str, last_message_id: u64) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let presence_seq = inner.log("left", peer_id, display_name, room);
+        let departure = Departure {
+            left_at: Instant::now(),
+            last_message_id,
+            presence_seq,
+        };
+        inner.departures.insert(peer_id.to_string(), departure);
+        if inner.departures.len() > MAX_TRACKED_DEPARTURES {
+            let oldest = inner
+                .departures
+                .iter()
+                .min_by_key(|(_, departure)| departure.left_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                inner.departures.remove(&oldest);
+            }
+        }
+    }
+
+    // The summary for a peer that is connecting again, if we saw it leave.
+    // Taken, so each absence is summarized at most once.
+    pub fn take_summary(&self, peer_id: &str, room: &str, history: &ChatHistory) -> Option<EventData> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let departure = inner.departures.remove(peer_id)?;
+
+        // What a replay from lastSeenSeq would deliver
+        let (missed, messages_gap) = history.since(departure.last_message_id);
+        let messages = missed
+            .iter()
+            .filter(|entry| entry.room == room && entry.to_peer_id.as_ref().is_none_or(|to| to == peer_id))
+            .count();
+
+        // Events after the peer's own "left", or a gap if the log moved past it
+        let incomplete = inner
+            .events
+            .front()
+            .is_some_and(|oldest| oldest.seq > departure.presence_seq + 1);
+        let changes: Vec<&PresenceEvent> = inner
+            .events
+            .iter()
+            .filter(|event| event.seq > departure.presence_seq && event.peer_id != peer_id && event.room == room)
+            .collect();
+        let joined = changes.iter().filter(|event| event.change == "joined").count();
+        let listed = &changes[changes.len().saturating_sub(MAX_SUMMARY_PEERS)..];
+
+        let mut data = HashMap::new();
+        data.insert("awaySecs".to_string(), departure.left_at.elapsed().as_secs().to_string());
+        data.insert("messages".to_string(), messages.to_string());
+        data.insert("lastSeenSeq".to_string(), departure.last_message_id.to_string());
+        data.insert("peersJoined".to_string(), joined.to_string());
+        data.insert("peersLeft".to_string(), (changes.len() - joined).to_string());
+        if incomplete || messages_gap || listed.len() < changes.len() {
+            data.insert("truncated".to_string(), "true".to_string());
+        }
+        let items = listed
+            .iter()
+            .map(|event| {
+                let mut item = HashMap::new();
+                item.insert("peerId".to_string(), event.peer_id.clone());
+                item.insert("displayName".to_string(), event.display_name.clone());
+                item.insert("change".to_string(), event.change.to_string());
+                DataItem { data: item }
+            })
+            .collect();
+        Some(EventData {
+            method: "away_summary".to_string(),
+            data,
+            items,
+            payload: Vec::new(),
+            checksum: Vec::new(),
+        })
+    }
+}
+
+impl Inner {
+    // Appends to the presence log and returns the event's seq
+    fn log(&mut self, change: &'static str, peer_id: &str, display_name: &str, room: &str) -> u64 {
+        self.next_seq += 1;
+        if self.events.len() == PRESENCE_LOG_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(PresenceEvent {
+            seq: self.next_seq,
+            change,
+            peer_id: peer_id.to_string(),
+            display_name: display_name.to_string(),
+            room: room.to_string(),
+        });
+        self.next_seq
+    }
+}
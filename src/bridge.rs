@@ -0,0 +1,167 @@
+use futures_util::{SinkExt, StreamExt};
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+use tracing::{debug, info, warn};
+
+use crate::config::ServerConfig;
+use crate::generated::{DataItem, Envelope, EventData};
+use crate::{notification_envelope, queue_server_message, AppState, DEFAULT_ROOM};
+
+// Requests waiting to go upstream. When full (upstream down or slow), new
+// ones are dropped rather than slowing down local traffic.
+const OUTBOUND_BUFFER: usize = 256;
+// Reconnect backoff: doubles from the first value up to the cap
+const RECONNECT_BACKOFF_START: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+// Optional bridge to an upstream server of the same kind (federation).
+// This server connects to upstream_url as an ordinary WebSocket client, then:
+// - requests local peers send with a bridged method are also sent upstream
+//   (with the sender's displayName), so the upstream relays them to its peers
+// - notifications with a bridged method that arrive from upstream are relayed
+//   to every local peer, marked bridged=true (messageId becomes
+//   upstreamMessageId, since it's the upstream's numbering; messageUuid
+//   and sentAtMs are kept as the upstream set them)
+// Everything crosses at most one bridge: forwarded requests carry
+// bridged=true and are not forwarded again, notifications already marked
+// bridged are not relayed down again, and what came down is never sent back
+// up, so messages can't loop (not even with a server bridged to itself). Bridge in one
+// direction only though: if two servers each use the other as upstream, their
+// peers get every message twice.
+// Only the default room is bridged: the bridge is a single connection, so
+// other rooms would all end up mixed together upstream.
+// The bridge runs in its own task: an upstream outage only means bridged
+// messages are dropped, local relaying carries on as usual.
+pub struct UpstreamBridge {
+    // None when no upstream is configured
+    outbound: Option<mpsc::Sender<Envelope>>,
+    methods: HashSet<String>,
+}
+
+impl UpstreamBridge {
+    // The receiver goes to `run` once AppState exists
+    pub fn new(config: &ServerConfig) -> (Self, Option<mpsc::Receiver<Envelope>>) {
+        let methods = config.upstream_bridge_methods.iter().cloned().collect();
+        if config.upstream_url.is_none() {
+            return (Self { outbound: None, methods }, None);
+        }
+        let (outbound, receiver) = mpsc::channel(OUTBOUND_BUFFER);
+        (Self { outbound: Some(outbound), methods }, Some(receiver))
+    }
+
+    // Called for each request a local peer sent
+    pub fn forward(
+        &self,
+        method: &str,
+        data: &HashMap<String, String>,
+        items: &[DataItem],
+        display_name: &str,
+        room: &str,
+    ) {
+        let Some(outbound) = &self.outbound else {
+            return;
+        };
+        if room != DEFAULT_ROOM {
+            return;
+        }
+        if !self.methods.contains(method) || data.get("bridged").is_some_and(|bridged| bridged == "true") {
+            return;
+        }
+        // Direct messages are for a local peer
+        if data.get("toPeerId").is_some_and(|to| !to.is_empty()) {
+            return;
+        }
+        let mut data = data.clone();
+        data.entry("displayName".to_string()).or_insert_with(|| display_name.to_string());
+        data.insert("bridged".to_string(), "true".to_string());
+        let request = Envelope {
+            event: "request".to_string(),
+            event_data: Some(EventData {
+                method: method.to_string(),
+                data,
+                items: items.to_vec(),
+                payload: Vec::new(),
+                checksum: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        if outbound.try_send(request).is_err() {
+            warn!("Upstream bridge unavailable or backed up, dropped a {}", method);
+        }
+    }
+}
+
+// Keeps the upstream connection up for the lifetime of the server
+pub async fn run(state: AppState, url: String, mut outbound: mpsc::Receiver<Envelope>) {
+    let mut backoff = RECONNECT_BACKOFF_START;
+    loop {
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((socket, _response)) => {
+                info!("Upstream bridge connected to {}", url);
+                backoff = RECONNECT_BACKOFF_START;
+                let (mut sink, mut stream) = socket.split();
+                // Anything queued while disconnected is stale by now
+                while outbound.try_recv().is_ok() {}
+                loop {
+                    tokio::select! {
+                        request = outbound.recv() => {
+                            let Some(request) = request else { return };
+                            if let Err(e) = sink.send(TungsteniteMessage::Binary(request.encode_to_vec())).await {
+                                warn!("Upstream bridge send failed: {}", e);
+                                break;
+                            }
+                        }
+                        frame = stream.next() => match frame {
+                            Some(Ok(TungsteniteMessage::Binary(bytes))) => relay_down(&state, &bytes).await,
+                            Some(Ok(_)) => {}
+                            Some(Err(e)) => {
+                                warn!("Upstream bridge receive failed: {}", e);
+                                break;
+                            }
+                            None => break,
+                        },
+                    }
+                }
+                info!("Upstream bridge disconnected from {}", url);
+            }
+            Err(e) => warn!("Upstream bridge could not connect to {}: {}", url, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+async fn relay_down(state: &AppState, bytes: &[u8]) {
+    let Ok(envelope) = Envelope::decode(bytes) else {
+        debug!("Undecodable frame from upstream ({} bytes)", bytes.len());
+        return;
+    };
+    let Some(mut event_data) = envelope.event_data else {
+        return;
+    };
+    if envelope.event != "notification" || !state.upstream.methods.contains(&event_data.method) {
+        return;
+    }
+    // Already crossed a bridge, or a direct message for an upstream peer
+    if event_data.data.get("bridged").is_some_and(|bridged| bridged == "true")
+        || event_data.data.contains_key("toPeerId")
+    {
+        return;
+    }
+    if let Some(upstream_id) = event_data.data.remove("messageId") {
+        event_data.data.insert("upstreamMessageId".to_string(), upstream_id);
+    }
+    event_data.data.insert("bridged".to_string(), "true".to_string());
+    let method = event_data.method.clone();
+    let message = notification_envelope(event_data);
+    state.shadow.observe(&message);
+
+    let peers_guard = state.peers.lock().await;
+    for (id, peer) in peers_guard.iter().filter(|(_, peer)| peer.room == DEFAULT_ROOM) {
+        let ctx = format!("upstream {} → {}", method, id);
+        queue_server_message(&peer.sender, &message, &ctx);
+    }
+}
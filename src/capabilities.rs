@@ -0,0 +1,72 @@
+// Optional features a client advertises at connect time with
+// `?caps=ack,compression`. The server only uses a feature with peers that
+// declared it, so it never sends frames a client can't handle.
+//
+// Unspecified = the conservative baseline: plain binary protobuf
+// notifications (chat, presence, system, error), which every client handles.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    // Sender gets an "ack" notification after each chat_message is relayed
+    pub ack: bool,
+    // Ack also carries serverReceivedAtUs, the server's wall clock (µs since
+    // epoch) when the frame was read. Combined with the client's own send and
+    // receive times this estimates latency and clock offset. Caveat: it's a
+    // different machine's clock, so one-way latency is only meaningful after
+    // correcting for skew (e.g. NTP-style using the ack round trip).
+    // Implies `ack`.
+    pub server_timestamp: bool,
+    // Peer inflates deflated snapshot frames (peer list, chat replay; see
+    // compression.rs)
+    pub compression: bool,
+    // Peer handles "encrypted_message" notifications (see e2e.rs)
+    pub e2e: bool,
+    // Peer prefixes its binary frames with a kind byte and handles
+    // "raw_relay" notifications (see raw_relay.rs)
+    pub framed: bool,
+    // Peer may also send checksummed raw frames (kind 2, see checksum.rs).
+    // Implies `framed`.
+    pub checksum: bool,
+    // Peer gets a periodic "connection_quality" report (see quality.rs)
+    pub quality: bool,
+    // Peer speaks JSON text frames instead of binary protobuf (see encoding.rs)
+    pub json: bool,
+    // Peer gets an "away_summary" when it connects again after leaving
+    // (see away.rs)
+    pub away_summary: bool,
+    // Peer answers messages marked requiresAck with "delivery_ack", and
+    // gets them again until it does (see reliable.rs)
+    pub reliable: bool,
+}
+
+impl Capabilities {
+    // Parses the comma-separated `caps` query param. Unknown names are ignored
+    // (newer clients may know flags this server doesn't).
+    pub fn parse(raw: Option<&str>) -> Self {
+        let mut caps = Self::default();
+        for name in raw.unwrap_or_default().split(',').map(str::trim) {
+            match name {
+                "" => {}
+                "ack" => caps.ack = true,
+                "server_timestamp" => {
+                    caps.ack = true;
+                    caps.server_timestamp = true;
+                }
+                "compression" => caps.compression = true,
+                "e2e" => caps.e2e = true,
+                "framed" => caps.framed = true,
+                "checksum" => {
+                    caps.framed = true;
+                    caps.checksum = true;
+                }
+                "quality" => caps.quality = true,
+                "json" => caps.json = true,
+                "away_summary" => caps.away_summary = true,
+                "reliable" => caps.reliable = true,
+                // Binary protobuf is the baseline, accepted for explicitness
+                "binary" => {}
+                unknown => tracing::debug!("Ignoring unknown capability '{}'", unknown),
+            }
+        }
+        caps
+    }
+}
@@ -0,0 +1,105 @@
+use serde::Deserialize;
+
+// Integrity checksums for raw_relay frames (see raw_relay.rs).
+//
+// A peer that connects with ?caps=framed,checksum may send kind 2 frames:
+// the checksum of the payload (length set by the algorithm) followed by the
+// payload. The server recomputes it before relaying; on a mismatch the frame
+// is dropped and the sender gets a "checksum_mismatch" error. Relayed copies
+// carry the checksum in EventData.checksum and the algorithm in
+// data.checksumAlgorithm, so recipients can check it again end to end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    // IEEE CRC-32, 4 bytes big-endian. Catches accidental corruption only.
+    #[default]
+    Crc32,
+    // 32 bytes. Slower, but also catches corruption CRC-32 can miss.
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Crc32 => "crc32",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    // Bytes the checksum takes at the start of a kind 2 frame
+    pub fn output_len(self) -> usize {
+        match self {
+            Self::Crc32 => 4,
+            Self::Sha256 => 32,
+        }
+    }
+
+    pub fn compute(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Crc32 => crc32(bytes).to_be_bytes().to_vec(),
+            Self::Sha256 => ring::digest::digest(&ring::digest::SHA256, bytes).as_ref().to_vec(),
+        }
+    }
+}
+
+// Bitwise CRC-32 (reflected, polynomial 0xEDB88320). Frames are small enough
+// that a lookup table isn't worth it.
+fn crc32(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(0xFFFF_FFFFu32, |mut crc, byte| {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+        crc
+    });
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::raw_relay::KIND_RAW_CHECKSUMMED;
+    use crate::testing::TestServer;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+
+    // Kind byte, checksum, payload
+    fn checksummed_frame(checksum: &[u8], payload: &[u8]) -> Message {
+        Message::Binary([&[KIND_RAW_CHECKSUMMED][..], checksum, payload].concat())
+    }
+
+    #[test]
+    fn matches_the_standard_check_values() {
+        assert_eq!(ChecksumAlgorithm::Crc32.compute(b"123456789"), 0xCBF4_3926u32.to_be_bytes());
+        let sha256 = ChecksumAlgorithm::Sha256.compute(b"abc");
+        assert_eq!(sha256.len(), ChecksumAlgorithm::Sha256.output_len());
+        assert_eq!(sha256[..4], [0xba, 0x78, 0x16, 0xbf]);
+    }
+
+    #[tokio::test]
+    async fn corrupted_frames_are_rejected_and_valid_ones_relay() {
+        for algorithm in [ChecksumAlgorithm::Crc32, ChecksumAlgorithm::Sha256] {
+            let server = TestServer::start(ServerConfig { raw_relay_checksum: algorithm, ..Default::default() }).await;
+            let mut ann = server.connect("peerId=ann&caps=checksum").await;
+            ann.expect("peer_list_chunk").await;
+            let mut bob = server.connect("peerId=bob&caps=framed").await;
+            bob.expect("peer_list_chunk").await;
+            let payload = b"file chunk 1";
+            let checksum = algorithm.compute(payload);
+
+            let mut corrupted = payload.to_vec();
+            corrupted[0] ^= 0x01;
+            ann.send(checksummed_frame(&checksum, &corrupted)).await;
+            assert_eq!(ann.expect("error").await.data["code"], "checksum_mismatch");
+            bob.expect_no("raw_relay", Duration::from_millis(300)).await;
+
+            ann.send(checksummed_frame(&checksum, payload)).await;
+            let relayed = bob.expect("raw_relay").await;
+            assert_eq!(relayed.payload, payload);
+            assert_eq!(relayed.checksum, checksum);
+            assert_eq!(relayed.data["checksumAlgorithm"], algorithm.as_str());
+            assert_eq!(relayed.data["fromPeerId"], "ann");
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use axum::extract::ws::{close_code, CloseFrame, Message as WsMessage, WebSocket};
+use semver::Version;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::config::ServerConfig;
+use crate::{error_notification, send_server_message, system_notification, AppState, Client};
+
+// Client app versions, reported with ?appVersion=1.4.2 or a "set_metadata"
+// request ({appVersion}), compared as semver against two optional floors:
+// - min_client_version: older clients get a system message (with
+//   client_upgrade_url if set) and are disconnected
+// - recommended_client_version: older clients stay, but get the same message
+//   as a warning and are flagged as outdated (outdatedClient in peer lists)
+// Clients that don't report a version can't be judged: they are let in but
+// flagged as outdated whenever either floor is configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStatus {
+    Current,
+    Outdated,
+    Unsupported,
+}
+
+pub fn parse(raw: &str) -> Result<Version, String> {
+    Version::parse(raw.trim()).map_err(|e| format!("appVersion '{}' is not a semver version: {}", raw, e))
+}
+
+pub fn classify(version: Option<&Version>, config: &ServerConfig) -> VersionStatus {
+    let (minimum, recommended) = (config.min_client_version.as_ref(), config.recommended_client_version.as_ref());
+    let Some(version) = version else {
+        return if minimum.is_some() || recommended.is_some() {
+            VersionStatus::Outdated
+        } else {
+            VersionStatus::Current
+        };
+    };
+    if minimum.is_some_and(|minimum| version < minimum) {
+        VersionStatus::Unsupported
+    } else if recommended.is_some_and(|recommended| version < recommended) {
+        VersionStatus::Outdated
+    } else {
+        VersionStatus::Current
+    }
+}
+
+// System message text for a client that isn't current
+pub fn upgrade_message(status: VersionStatus, config: &ServerConfig) -> Option<String> {
+    let text = match status {
+        VersionStatus::Current => return None,
+        VersionStatus::Outdated => "A newer version of this app is available, please upgrade",
+        VersionStatus::Unsupported => "This app version is no longer supported, please upgrade",
+    };
+    Some(match &config.client_upgrade_url {
+        Some(url) => format!("{}: {}", text, url),
+        None => text.to_string(),
+    })
+}
+
+// Turns away a client below min_client_version: the system message first,
+// so it can tell the user why, then a policy close
+pub async fn reject(mut socket: WebSocket, notice: WsMessage) {
+    let _ = socket.send(notice).await;
+    let _ = socket
+        .send(WsMessage::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "unsupported client version".into(),
+        })))
+        .await;
+}
+
+// Handles "set_metadata" {appVersion}. Returns false when the new version is
+// below the minimum and the connection must be closed.
+pub async fn handle_set_metadata(state: &AppState, peer_id: &str, client: &Client, data: &HashMap<String, String>) -> bool {
+    let Some(raw) = data.get("appVersion") else {
+        return true;
+    };
+    let version = match parse(raw) {
+        Ok(version) => version,
+        Err(reason) => {
+            send_server_message(client, &error_notification("invalid_metadata", &reason), "set_metadata").await;
+            return true;
+        }
+    };
+    let config = state.config.current();
+    let status = classify(Some(&version), &config);
+    {
+        let mut peers_guard = state.peers.lock().await;
+        if let Some(me) = peers_guard.get_mut(peer_id) {
+            me.app_version = Some(version);
+            me.outdated_client = status != VersionStatus::Current;
+        }
+    }
+    if let Some(message) = upgrade_message(status, &config) {
+        send_server_message(client, &system_notification(&message), "client_version").await;
+    }
+    if status == VersionStatus::Unsupported {
+        warn!("Disconnecting {}: appVersion {} is below the minimum", peer_id, raw);
+        let _ = client
+            .send(WsMessage::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "unsupported client version".into(),
+            })))
+            .await;
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+
+    fn config(minimum: Option<&str>, recommended: Option<&str>) -> ServerConfig {
+        ServerConfig {
+            min_client_version: minimum.map(|version| parse(version).unwrap()),
+            recommended_client_version: recommended.map(|version| parse(version).unwrap()),
+            client_upgrade_url: Some("https://example.com/app".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn status(raw: &str, config: &ServerConfig) -> VersionStatus {
+        classify(Some(&parse(raw).unwrap()), config)
+    }
+
+    #[test]
+    fn below_the_minimum_is_unsupported() {
+        let config = config(Some("2.0.0"), None);
+        assert_eq!(status("1.9.9", &config), VersionStatus::Unsupported);
+        assert_eq!(status("2.0.0-beta.1", &config), VersionStatus::Unsupported);
+        assert_eq!(status("2.0.0", &config), VersionStatus::Current);
+        assert_eq!(status("2.0.1", &config), VersionStatus::Current);
+    }
+
+    #[test]
+    fn below_the_recommended_version_is_outdated() {
+        let config = config(Some("1.0.0"), Some("1.5.0"));
+        assert_eq!(status("0.9.0", &config), VersionStatus::Unsupported);
+        assert_eq!(status("1.4.9", &config), VersionStatus::Outdated);
+        assert_eq!(status("1.5.0", &config), VersionStatus::Current);
+        assert_eq!(status("1.10.0", &config), VersionStatus::Current);
+    }
+
+    #[test]
+    fn unreported_versions_are_outdated_only_when_floors_are_set() {
+        assert_eq!(classify(None, &config(None, None)), VersionStatus::Current);
+        assert_eq!(classify(None, &config(Some("1.0.0"), None)), VersionStatus::Outdated);
+        assert!(parse("latest").is_err());
+    }
+
+    #[test]
+    fn upgrade_messages_point_at_the_upgrade_url() {
+        let config = config(Some("1.0.0"), None);
+        assert_eq!(upgrade_message(VersionStatus::Current, &config), None);
+        let message = upgrade_message(VersionStatus::Unsupported, &config).unwrap();
+        assert!(message.ends_with(": https://example.com/app"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn connections_are_gated_by_app_version() {
+        let server = TestServer::start(config(Some("2.0.0"), Some("2.1.0"))).await;
+
+        let mut old = server.connect("peerId=old&appVersion=1.9.0").await;
+        let notice = old.expect("system").await;
+        assert!(notice.data["message"].contains("no longer supported"));
+        let close = old.expect_close().await.expect("a close frame");
+        assert_eq!(close.reason, "unsupported client version");
+
+        let mut at_minimum = server.connect("peerId=at_minimum&appVersion=2.0.0").await;
+        at_minimum.expect("peer_list_chunk").await;
+        assert!(at_minimum.expect("system").await.data["message"].contains("newer version"));
+        let mut current = server.connect("peerId=current&appVersion=2.1.0").await;
+        let roster = current.expect("peer_list_chunk").await;
+        assert_eq!(roster.items.len(), 1);
+        assert_eq!(roster.items[0].data["peerId"], "at_minimum");
+        assert_eq!(roster.items[0].data["outdatedClient"], "true");
+
+        // Reporting a version below the minimum later also disconnects
+        current.request("set_metadata", &[("appVersion", "1.0.0")]).await;
+        current.expect("system").await;
+        current.expect_close().await;
+    }
+}
@@ -0,0 +1,138 @@
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::encoding::{self, Encoding};
+use crate::generated::{Envelope, EventData};
+use crate::{notification_envelope, send_server_message, Client};
+
+// Snapshot compression, for peers that connect with ?caps=compression.
+//
+// The largest frames a client gets are snapshots: the peer list sent on join
+// and the answers to list_peers / list_rooms (see peer_list.rs), and the chat
+// replay after ?resume_from_seq= (see history.rs). To such a peer, a snapshot
+// frame of at least snapshot_compression_min_bytes goes out as a notification
+// with the same method, data {compressed: "deflate", uncompressedBytes} and
+// as payload the raw DEFLATE (RFC 1951) of the frame it stands for, exactly
+// as the peer would have got it (a protobuf Envelope, or the JSON text with
+// ?caps=json). Clients inflate the payload and handle the result as if it had
+// arrived by itself. Smaller frames, frames that wouldn't shrink, and every
+// frame to peers without the capability are sent as they are.
+
+// Sends one snapshot frame, compressed when that pays off (see above)
+pub async fn send_snapshot(client: &Client, envelope: &Envelope, context: &str, min_bytes: usize) -> bool {
+    let compressed = client.compression.then(|| compress(envelope, client.encoding, min_bytes)).flatten();
+    send_server_message(client, compressed.as_ref().unwrap_or(envelope), context).await
+}
+
+// None when the frame is under `min_bytes` (0 = never compress) or doesn't shrink
+fn compress(envelope: &Envelope, encoding: Encoding, min_bytes: usize) -> Option<Envelope> {
+    if min_bytes == 0 {
+        return None;
+    }
+    let frame = match encoding {
+        Encoding::Protobuf => envelope.encode_to_vec(),
+        Encoding::Json => encoding::to_json(envelope).into_bytes(),
+    };
+    if frame.len() < min_bytes {
+        return None;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&frame).ok()?;
+    let payload = encoder.finish().ok()?;
+    if payload.len() >= frame.len() {
+        return None;
+    }
+
+    let method = envelope.event_data.as_ref().map(|event| event.method.clone()).unwrap_or_default();
+    let mut data = HashMap::new();
+    data.insert("compressed".to_string(), "deflate".to_string());
+    data.insert("uncompressedBytes".to_string(), frame.len().to_string());
+    Some(notification_envelope(EventData { method, data, items: Vec::new(), payload, checksum: Vec::new() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::generated::DataItem;
+    use crate::testing::TestServer;
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    fn inflate(payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        DeflateDecoder::new(payload).read_to_end(&mut frame).expect("valid DEFLATE");
+        frame
+    }
+
+    fn peer_list(peers: usize) -> Envelope {
+        let items = (0..peers)
+            .map(|n| {
+                let data = HashMap::from([
+                    ("peerId".to_string(), format!("peer_{}", n)),
+                    ("displayName".to_string(), format!("Peer number {}", n)),
+                ]);
+                DataItem { data }
+            })
+            .collect();
+        let data = HashMap::from([("chunk".to_string(), "0".to_string()), ("last".to_string(), "true".to_string())]);
+        notification_envelope(EventData {
+            method: "peer_list_chunk".to_string(),
+            data,
+            items,
+            payload: Vec::new(),
+            checksum: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn a_large_peer_list_shrinks_and_inflates_back() {
+        let snapshot = peer_list(1000);
+        for encoding in [Encoding::Protobuf, Encoding::Json] {
+            let plain = match encoding {
+                Encoding::Protobuf => snapshot.encode_to_vec(),
+                Encoding::Json => encoding::to_json(&snapshot).into_bytes(),
+            };
+            let compressed = compress(&snapshot, encoding, 1024).expect("worth compressing");
+            let event = compressed.event_data.expect("event data");
+            assert_eq!(event.method, "peer_list_chunk");
+            assert_eq!(event.data["compressed"], "deflate");
+            assert_eq!(event.data["uncompressedBytes"], plain.len().to_string());
+            assert_eq!(inflate(&event.payload), plain);
+            // Rosters are repetitive: 1000 peers shrink from about 54 KB to under 6 KB
+            let (before, after) = (plain.len(), event.payload.len());
+            assert!(after * 4 < before, "{:?}: {} -> {} bytes", encoding, before, after);
+        }
+    }
+
+    #[test]
+    fn small_frames_are_left_alone() {
+        let snapshot = peer_list(3);
+        let size = snapshot.encode_to_vec().len();
+        assert!(compress(&snapshot, Encoding::Protobuf, size + 1).is_none());
+        assert!(compress(&peer_list(1000), Encoding::Protobuf, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn only_peers_with_the_capability_get_compressed_snapshots() {
+        let server = TestServer::start(ServerConfig { snapshot_compression_min_bytes: 1, ..Default::default() }).await;
+        let _bob = server.join("bob", "red").await;
+
+        let mut ann = server.connect("peerId=ann&room=red&caps=compression").await;
+        let chunk = ann.expect("peer_list_chunk").await;
+        assert_eq!(chunk.data["compressed"], "deflate");
+        assert!(chunk.items.is_empty());
+        let inner = Envelope::decode(inflate(&chunk.payload).as_slice()).expect("an Envelope");
+        let roster = inner.event_data.expect("event data");
+        assert_eq!(roster.method, "peer_list_chunk");
+        assert_eq!(roster.items[0].data["peerId"], "bob");
+
+        let mut cat = server.connect("peerId=cat&room=red").await;
+        let chunk = cat.expect("peer_list_chunk").await;
+        assert!(!chunk.data.contains_key("compressed"));
+        assert_eq!(chunk.items.len(), 2);
+    }
+}
@@ -0,0 +1,1096 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use semver::Version;
+use tracing::warn;
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::peer_id::{DuplicatePeerIdPolicy, PeerIdStrategy};
+use crate::validation::ValidationMode;
+
+// Runtime configuration for the server.
+// Sources, lowest to highest priority:
+//   1. built-in defaults
+//   2. optional TOML file (`--config path.toml` or CONFIG_FILE env)
+//   3. environment variables
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)] // missing keys use defaults, typos are errors
+pub struct ServerConfig {
+    // Hard cap on how long one WebSocket connection may stay open.
+    // 0 = disabled (connections may live forever).
+    // Env: MAX_CONNECTION_LIFETIME_SECS
+    pub max_connection_lifetime_secs: u64,
+
+    // Max number of peer ids one client may subscribe to for presence.
+    // Env: MAX_PRESENCE_SUBSCRIPTIONS
+    pub max_presence_subscriptions: usize,
+
+    // Let presence subscriptions follow peers in other rooms (buddy lists).
+    // Off by default: rooms are isolated and a subscription only reports
+    // peers in the subscriber's own room.
+    // Env: CROSS_ROOM_PRESENCE_SUBSCRIPTIONS
+    pub cross_room_presence_subscriptions: bool,
+
+    // Disconnect a peer whose outbound queue stays deeper than this...
+    // 0 = disabled.
+    // Env: SLOW_CLIENT_MAX_QUEUE_DEPTH
+    pub slow_client_max_queue_depth: usize,
+
+    // ...for at least this many seconds.
+    // Env: SLOW_CLIENT_GRACE_SECS
+    pub slow_client_grace_secs: u64,
+
+    // Frames each of a peer's three outbound priority lanes may hold (see
+    // priority.rs). Broadcasts to a peer whose lane is full are dropped for
+    // that peer instead of waiting for it. Applies to new connections.
+    // Env: OUTBOUND_LANE_CAPACITY
+    pub outbound_lane_capacity: usize,
+
+    // Largest inbound text (JSON) frame accepted, in bytes.
+    // Env: MAX_TEXT_FRAME_BYTES
+    pub max_text_frame_bytes: usize,
+
+    // Largest inbound binary (protobuf) frame accepted, in bytes.
+    // Env: MAX_BINARY_FRAME_BYTES
+    pub max_binary_frame_bytes: usize,
+    // Disconnect a peer on its Nth frame over those limits (each one is
+    // rejected with a message_too_large error first). 0 = never disconnect.
+    // Env: MAX_OVERSIZED_FRAMES
+    pub max_oversized_frames: u32,
+
+    // Range of X-Protocol-Version header values accepted on upgrade.
+    // Clients that don't send the header are always accepted. min must not
+    // be above max.
+    // Env: MIN_PROTOCOL_VERSION / MAX_PROTOCOL_VERSION
+    pub min_protocol_version: u32,
+    pub max_protocol_version: u32,
+
+    // Bearer token for the /api admin endpoints. Unset = admin API disabled.
+    // Env: ADMIN_TOKEN
+    pub admin_token: Option<Secret>,
+
+    // Separate bearer token for /api/announce, which reaches every peer across
+    // all scopes. Unset = global announcements disabled.
+    // Env: ANNOUNCE_TOKEN
+    pub announce_token: Option<Secret>,
+
+    // Key for signed client tokens (see auth.rs). When set, every WebSocket
+    // upgrade needs a valid token, and the token decides the peerId.
+    // Unset = anyone may connect as any peerId.
+    // Env: CLIENT_TOKEN_SECRET
+    pub client_token_secret: Option<Secret>,
+
+    // Rules for client-supplied peerId values (generated ids always pass).
+    // Letters and digits are always allowed, plus the extra chars.
+    // Env: PEER_ID_MIN_LEN / PEER_ID_MAX_LEN / PEER_ID_EXTRA_CHARS / PEER_ID_PATTERN
+    pub peer_id_min_len: usize,
+    pub peer_id_max_len: usize,
+    pub peer_id_extra_chars: String,
+    // Optional regex the whole id must also match
+    pub peer_id_pattern: Option<String>,
+
+    // How ids are made for clients that don't send ?peerId (see peer_id.rs):
+    // short_uuid (default), uuid, sequential, random_words or
+    // client_provided_only (reject the upgrade instead)
+    // Env: PEER_ID_STRATEGY, PEER_ID_SEQUENTIAL_PREFIX
+    pub peer_id_strategy: PeerIdStrategy,
+    // Prefix for sequential ids ("peer_" gives peer_1, peer_2, ...)
+    pub peer_id_sequential_prefix: String,
+    // A second connection with a connected peer's peerId: reject_new
+    // (default) or replace_old (see peer_id.rs)
+    // Env: DUPLICATE_PEER_ID_POLICY
+    pub duplicate_peer_id_policy: DuplicatePeerIdPolicy,
+
+    // Join/leave notifications carry expiresAt = now + TTL (ms since epoch)
+    // so client UIs can auto-dismiss them. 0 = no expiry.
+    // Env: PRESENCE_NOTIFICATION_TTL_SECS
+    pub presence_notification_ttl_secs: u64,
+
+    // Ping each new connection and only register it once it answers with a pong.
+    // Bots that connect just to hold a socket often never do.
+    // Env: REQUIRE_INITIAL_PONG (true/false), INITIAL_PONG_TIMEOUT_SECS
+    pub require_initial_pong: bool,
+    pub initial_pong_timeout_secs: u64,
+
+    // Stamped on every notification so multi-instance deployments can tell
+    // which server handled it. instance_id defaults to a random id per start.
+    // Env: SERVER_NAME / INSTANCE_ID
+    pub server_name: String,
+    pub instance_id: Option<String>,
+
+    // On server-initiated disconnects, wait up to this long for the peer's
+    // pending outbound messages to be written before sending Close. 0 = don't wait.
+    // Env: DRAIN_TIMEOUT_MS
+    pub drain_timeout_ms: u64,
+
+    // Max peers per peer_list_chunk message (and rooms per room_list_chunk),
+    // so a huge peer list never becomes one giant frame.
+    // Env: PEER_LIST_CHUNK_SIZE
+    pub peer_list_chunk_size: usize,
+
+    // Snapshot frames (peer/room list chunks, the chat replay) at least this
+    // big are sent deflated to peers with ?caps=compression (see
+    // compression.rs); smaller ones aren't worth the CPU. 0 = never compress.
+    // Env: SNAPSHOT_COMPRESSION_MIN_BYTES
+    pub snapshot_compression_min_bytes: usize,
+
+    // How many recent chat messages to keep in memory for export
+    // (GET /api/history/export). 0 = keep none.
+    // Env: CHAT_HISTORY_SIZE
+    pub chat_history_size: usize,
+
+    // Unsolicited pong frames a client may send per minute before it is
+    // disconnected as a pong flood. 0 = no limit.
+    // Env: MAX_UNSOLICITED_PONGS_PER_MIN
+    pub max_unsolicited_pongs_per_min: u64,
+
+    // Disable Nagle's algorithm on accepted connections. With it on, small
+    // frames (a chat message, a pong) can sit in the kernel for up to ~40ms
+    // waiting to be coalesced with the next write. Keep on for realtime traffic.
+    // Env: TCP_NODELAY
+    pub tcp_nodelay: bool,
+
+    // Idle seconds before the OS starts TCP keepalive probes, so dead peers
+    // (pulled cable, sleeping laptop) are eventually noticed. 0 = OS default (off).
+    // Set on the listening socket and inherited by accepted connections on
+    // Linux and Windows; on macOS/BSD only SO_KEEPALIVE itself is inherited
+    // and the idle time stays at the system default (usually 2 hours).
+    // Env: TCP_KEEPALIVE_SECS
+    pub tcp_keepalive_secs: u64,
+
+    // How many recent messages remember their sender for read receipts...
+    // Env: READ_RECEIPT_MAX_TRACKED (0 = read receipts off)
+    pub read_receipt_max_tracked: usize,
+
+    // ...and for how long
+    // Env: READ_RECEIPT_TTL_SECS
+    pub read_receipt_ttl_secs: u64,
+
+    // Upgrade requests with more query parameters than this, or a longer
+    // query string (bytes), get 400. 0 = no limit.
+    // Env: MAX_QUERY_PARAMS, MAX_QUERY_BYTES
+    pub max_query_params: usize,
+    pub max_query_bytes: usize,
+
+    // Tokio runtime. multi_thread (default) spreads connections over worker
+    // threads; current_thread runs everything on the main thread, which is
+    // cheaper on a single-core container but means one busy connection delays
+    // all the others. worker_threads only applies to multi_thread:
+    // 0 = one per CPU core (or TOKIO_WORKER_THREADS, if set). In containers
+    // with a CPU quota the core count can be far above the quota, so set it.
+    // Env: RUNTIME_FLAVOR, WORKER_THREADS
+    pub runtime_flavor: RuntimeFlavor,
+    pub worker_threads: usize,
+
+    // A peer that drops and reconnects with the same peerId within this many
+    // seconds keeps its place: others see no peer_left/peer_joined pair.
+    // 0 = peer_left is sent as soon as the socket closes.
+    // Env: RECONNECT_GRACE_SECS
+    pub reconnect_grace_secs: u64,
+
+    // Frames kept for a peer within its reconnect grace period and sent when
+    // it comes back (see offline.rs). Past this, newer frames are dropped.
+    // 0 = nothing is kept. No effect without reconnect_grace_secs.
+    // Env: OFFLINE_QUEUE_MAX_MESSAGES
+    pub offline_queue_max_messages: usize,
+
+    // Per-method payload caps (bytes of data keys + values, items included),
+    // checked after decoding, on top of the frame-size limits. Methods not
+    // listed are only bound by max_binary_frame_bytes.
+    // TOML: [method_size_limits] chat_message = 4096
+    // Env: METHOD_SIZE_LIMITS="chat_message=4096,encrypted_message=32768"
+    //      (replaces the whole table)
+    pub method_size_limits: HashMap<String, usize>,
+
+    // Features (request methods, or raw_relay) only some peers may use, with
+    // the peer ids allowed each ("prefix*" matches by prefix). Features not
+    // listed are open to everyone (see features.rs).
+    // TOML: [feature_rules] raw_relay = ["alice", "premium_*"]
+    // Env: FEATURE_RULES="raw_relay=alice|premium_*,group_message=bob"
+    //      (replaces the whole table)
+    pub feature_rules: HashMap<String, Vec<String>>,
+
+    // How often peers with the `quality` capability get a connection_quality
+    // report (and a ping to measure RTT). Values below
+    // MIN_CONNECTION_QUALITY_INTERVAL are raised to it. 0 = no reports.
+    // Env: CONNECTION_QUALITY_INTERVAL_SECS
+    pub connection_quality_interval_secs: u64,
+
+    // What to do with frames that decode but look wrong (see validation.rs):
+    // lenient (default), reject_unknown or strict
+    // Env: VALIDATION_MODE
+    pub validation_mode: ValidationMode,
+
+    // Checksum for kind 2 raw_relay frames from `checksum` peers
+    // (see checksum.rs): crc32 (default) or sha256
+    // Env: RAW_RELAY_CHECKSUM
+    pub raw_relay_checksum: ChecksumAlgorithm,
+
+    // Most group labels one peer may carry (see groups.rs)
+    // Env: MAX_GROUPS_PER_PEER
+    pub max_groups_per_peer: usize,
+    // Whether a group_message sender that is in the group gets a copy too
+    // Env: GROUP_MESSAGE_ECHO_TO_SENDER
+    pub group_message_echo_to_sender: bool,
+    // Whether the sender of a direct chat_message (data.toPeerId) gets a copy too
+    // Env: DIRECT_MESSAGE_ECHO_TO_SENDER
+    pub direct_message_echo_to_sender: bool,
+
+    // Batch joins/leaves over this many ms into one presence_update per peer
+    // (see presence.rs), to cut traffic during mass reconnects.
+    // 0 = send peer_joined/peer_left immediately, one per change.
+    // Env: PRESENCE_COALESCE_WINDOW_MS
+    pub presence_coalesce_window_ms: u64,
+    // Like presence_coalesce_window_ms but for leaves only, used while that
+    // one is 0: joins still go out at once, leaves are batched. Cuts the
+    // storm when many peers drop together (e.g. a network partition).
+    // Env: LEAVE_COALESCE_WINDOW_MS
+    pub leave_coalesce_window_ms: u64,
+    // At most this many leaves per presence_update; the rest wait for the
+    // next window, in order, none dropped. 0 = no cap.
+    // Env: MAX_LEAVES_PER_PRESENCE_UPDATE
+    pub max_leaves_per_presence_update: usize,
+
+    // Stamp a chat_message with groupedWithPrevious=true when the previous
+    // chat message (by messageId) came from the same sender at most this
+    // many ms earlier, so clients can render runs as one visual group.
+    // 0 = off.
+    // Env: MESSAGE_GROUPING_WINDOW_MS
+    pub message_grouping_window_ms: u64,
+
+    // Bridge to another server (see bridge.rs): connect to this ws:// URL as
+    // a client, query string included (e.g. ?peerId=bridge-eu). None = no bridge.
+    // Env: UPSTREAM_URL
+    pub upstream_url: Option<String>,
+    // Methods relayed over the bridge, in both directions
+    // Env: UPSTREAM_BRIDGE_METHODS="chat_message,group_message"
+    pub upstream_bridge_methods: Vec<String>,
+
+    // Log routine lines for 1 in N connections (see logging.rs).
+    // 1 = every connection, 0 = only errors and warnings.
+    // Env: LOG_SAMPLE_EVERY
+    pub log_sample_every: u64,
+
+    // Disconnect peers that send no binary/text frame for this many seconds.
+    // 0 = never. Pings and pongs don't count: client libraries send those on
+    // their own, so they say nothing about the user being there.
+    // Env: IDLE_TIMEOUT_SECS
+    pub idle_timeout_secs: u64,
+    // Lead time for the "you'll be disconnected" system warning before an
+    // idle disconnect. 0 (or >= the timeout) = no warning.
+    // Env: IDLE_WARNING_SECS
+    pub idle_warning_secs: u64,
+
+    // Ping every connection this often and drop it when the pong hasn't come
+    // back within the timeout (see heartbeat.rs). Evicts dead TCP connections
+    // the idle timeout wouldn't, since that one ignores pings/pongs.
+    // 0 = no heartbeat. The timeout must be at least 1 while it is on.
+    // Env: HEARTBEAT_INTERVAL_SECS, HEARTBEAT_TIMEOUT_SECS
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_timeout_secs: u64,
+
+    // Acknowledged delivery (see reliable.rs): how long to wait for a
+    // "delivery_ack" before sending the message again, and how many times.
+    // Env: ACK_TIMEOUT_MS, ACK_MAX_RETRIES
+    pub ack_timeout_ms: u64,
+    pub ack_max_retries: u32,
+
+    // Client app version floors, semver (see client_version.rs): below the
+    // minimum clients are disconnected, below the recommended one they are
+    // warned and flagged. The upgrade URL goes into both messages.
+    // Env: MIN_CLIENT_VERSION, RECOMMENDED_CLIENT_VERSION, CLIENT_UPGRADE_URL
+    pub min_client_version: Option<Version>,
+    pub recommended_client_version: Option<Version>,
+    pub client_upgrade_url: Option<String>,
+
+    // TLS (see tls.rs): PEM certificate chain and private key. Both or
+    // neither; None = plain ws://.
+    // Env: TLS_CERT_PATH, TLS_KEY_PATH
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // PEM CA bundle for mutual TLS: clients must present a certificate it
+    // signed, and the certificate's CN/SAN becomes their peerId
+    // Env: TLS_CLIENT_CA_PATH
+    pub tls_client_ca_path: Option<String>,
+
+    // Empty rooms lose their state (password, ...; see rooms.rs) once nobody
+    // has been in them for the grace period, checked every sweep interval.
+    // Rooms set up through PUT /api/rooms/{room} are kept.
+    // Sweep interval 0 = keep rooms forever; only read at startup.
+    // Env: EMPTY_ROOM_SWEEP_INTERVAL_SECS, EMPTY_ROOM_GRACE_SECS
+    pub empty_room_sweep_interval_secs: u64,
+    pub empty_room_grace_secs: u64,
+
+    // Leave password-protected rooms' topics out of list_rooms replies; their
+    // name and peer count are still listed.
+    // Env: HIDE_PROTECTED_ROOM_TOPICS
+    pub hide_protected_room_topics: bool,
+
+    // Pinned messages per room (see pins.rs). 0 = pinning is off. Pins are
+    // taken from the chat history, so chat_history_size 0 also rules it out.
+    // Env: MAX_PINNED_MESSAGES
+    pub max_pinned_messages: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    MultiThread,
+    CurrentThread,
+}
+
+// A config string that must never show up in logs (the config is printed at startup)
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    #[cfg(test)]
+    pub fn new(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+// Default for outbound_lane_capacity; also used by tunnel connections
+pub const DEFAULT_OUTBOUND_LANE_CAPACITY: usize = 1024;
+
+// Default for both frame-size limits
+const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+
+// Frames up to this many times the configured limit still get a clear
+// rejection instead of a dropped connection
+const OVERSIZED_FRAME_HEADROOM: usize = 4;
+
+// Floor for connection_quality_interval_secs, so reports stay a trickle
+const MIN_CONNECTION_QUALITY_INTERVAL: Duration = Duration::from_secs(5);
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_connection_lifetime_secs: 0,
+            max_presence_subscriptions: 100,
+            cross_room_presence_subscriptions: false,
+            slow_client_max_queue_depth: 0,
+            slow_client_grace_secs: 10,
+            outbound_lane_capacity: DEFAULT_OUTBOUND_LANE_CAPACITY,
+            max_text_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_binary_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_oversized_frames: 0,
+            min_protocol_version: 1,
+            max_protocol_version: 1,
+            admin_token: None,
+            announce_token: None,
+            client_token_secret: None,
+            peer_id_min_len: 1,
+            peer_id_max_len: 64,
+            peer_id_extra_chars: "-_".to_string(),
+            peer_id_pattern: None,
+            peer_id_strategy: PeerIdStrategy::ShortUuid,
+            peer_id_sequential_prefix: "peer_".to_string(),
+            duplicate_peer_id_policy: DuplicatePeerIdPolicy::RejectNew,
+            presence_notification_ttl_secs: 0,
+            require_initial_pong: false,
+            initial_pong_timeout_secs: 5,
+            server_name: "rust_socket".to_string(),
+            instance_id: None,
+            drain_timeout_ms: 2000,
+            peer_list_chunk_size: 100,
+            snapshot_compression_min_bytes: 1024,
+            chat_history_size: 1000,
+            max_unsolicited_pongs_per_min: 60,
+            tcp_nodelay: true,
+            tcp_keepalive_secs: 60,
+            read_receipt_max_tracked: 10_000,
+            read_receipt_ttl_secs: 3600,
+            max_query_params: 32,
+            max_query_bytes: 4096,
+            runtime_flavor: RuntimeFlavor::MultiThread,
+            worker_threads: 0,
+            reconnect_grace_secs: 0,
+            offline_queue_max_messages: 0,
+            method_size_limits: HashMap::from([
+                ("chat_message".to_string(), 4096),
+                ("mark_read".to_string(), 256),
+                ("list_peers".to_string(), 256),
+                ("list_rooms".to_string(), 256),
+                ("pin_message".to_string(), 256),
+                ("unpin_message".to_string(), 256),
+            ]),
+            feature_rules: HashMap::new(),
+            connection_quality_interval_secs: 10,
+            validation_mode: ValidationMode::Lenient,
+            raw_relay_checksum: ChecksumAlgorithm::Crc32,
+            max_groups_per_peer: 16,
+            group_message_echo_to_sender: false,
+            direct_message_echo_to_sender: false,
+            presence_coalesce_window_ms: 0,
+            leave_coalesce_window_ms: 0,
+            max_leaves_per_presence_update: 0,
+            message_grouping_window_ms: 0,
+            upstream_url: None,
+            upstream_bridge_methods: vec!["chat_message".to_string()],
+            log_sample_every: 1,
+            idle_timeout_secs: 0,
+            idle_warning_secs: 30,
+            heartbeat_interval_secs: 30,
+            heartbeat_timeout_secs: 10,
+            ack_timeout_ms: 5000,
+            ack_max_retries: 3,
+            min_client_version: None,
+            recommended_client_version: None,
+            client_upgrade_url: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            empty_room_sweep_interval_secs: 60,
+            empty_room_grace_secs: 300,
+            hide_protected_room_topics: false,
+            max_pinned_messages: 10,
+        }
+    }
+}
+
+// Why loading the config failed. Shown to the operator at startup.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: String, source: std::io::Error },
+    Parse { path: String, source: toml::de::Error },
+    // Parsed fine but a value is unusable
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read config file '{}': {}", path, source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file '{}': {}", path, source)
+            }
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// The config as the running server sees it. Reloads (SIGHUP or
+// POST /api/config/reload) swap in a whole new snapshot; readers take the
+// current one with `current()` each time, so a change applies to the next
+// operation that reads it and never half-way through one.
+pub struct LiveConfig {
+    // What the server started with, for restart_required_changes
+    startup: Arc<ServerConfig>,
+    // std RwLock: only held to clone or replace the Arc
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        let config = Arc::new(config);
+        Self {
+            startup: config.clone(),
+            current: RwLock::new(config),
+        }
+    }
+
+    pub fn startup(&self) -> &ServerConfig {
+        &self.startup
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, config: ServerConfig) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+}
+
+impl ServerConfig {
+    // Build the config from defaults, the optional TOML file, then env overrides.
+    // Called in main and again on every reload.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match config_file_path() {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env();
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Settings that parse fine but can't work together. Checked by load, so
+    // a bad value stops startup and a bad reload keeps the old config.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.min_protocol_version > self.max_protocol_version {
+            // An empty range would turn away every client that sends the header
+            return Err(ConfigError::Invalid(format!(
+                "min_protocol_version ({}) is above max_protocol_version ({})",
+                self.min_protocol_version, self.max_protocol_version
+            )));
+        }
+        if self.heartbeat_interval_secs > 0 && self.heartbeat_timeout_secs == 0 {
+            // Nobody can answer a ping in no time: every peer would be evicted
+            return Err(ConfigError::Invalid(
+                "heartbeat_timeout_secs must be at least 1 (set heartbeat_interval_secs = 0 to disable the heartbeat)"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Parse a TOML file into a config. Keys not present keep their defaults.
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let raw = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+        toml::from_str(&raw).map_err(|source| ConfigError::Parse {
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    // Environment variables win over file values
+    fn apply_env(&mut self) {
+        if let Some(secs) = env_u64("MAX_CONNECTION_LIFETIME_SECS") {
+            self.max_connection_lifetime_secs = secs;
+        }
+        if let Some(max) = env_u64("MAX_PRESENCE_SUBSCRIPTIONS") {
+            self.max_presence_subscriptions = max as usize;
+        }
+        if let Some(cross_room) = env_bool("CROSS_ROOM_PRESENCE_SUBSCRIPTIONS") {
+            self.cross_room_presence_subscriptions = cross_room;
+        }
+        if let Some(depth) = env_u64("SLOW_CLIENT_MAX_QUEUE_DEPTH") {
+            self.slow_client_max_queue_depth = depth as usize;
+        }
+        if let Some(secs) = env_u64("SLOW_CLIENT_GRACE_SECS") {
+            self.slow_client_grace_secs = secs;
+        }
+        if let Some(capacity) = env_u64("OUTBOUND_LANE_CAPACITY") {
+            self.outbound_lane_capacity = capacity as usize;
+        }
+        if let Some(bytes) = env_u64("MAX_TEXT_FRAME_BYTES") {
+            self.max_text_frame_bytes = bytes as usize;
+        }
+        if let Some(bytes) = env_u64("MAX_BINARY_FRAME_BYTES") {
+            self.max_binary_frame_bytes = bytes as usize;
+        }
+        if let Some(count) = env_u64("MAX_OVERSIZED_FRAMES") {
+            self.max_oversized_frames = count as u32;
+        }
+        if let Some(version) = env_u64("MIN_PROTOCOL_VERSION") {
+            self.min_protocol_version = version as u32;
+        }
+        if let Some(version) = env_u64("MAX_PROTOCOL_VERSION") {
+            self.max_protocol_version = version as u32;
+        }
+        if let Ok(token) = std::env::var("ADMIN_TOKEN") {
+            self.admin_token = Some(Secret(token)).filter(|token| !token.0.is_empty());
+        }
+        if let Ok(token) = std::env::var("ANNOUNCE_TOKEN") {
+            self.announce_token = Some(Secret(token)).filter(|token| !token.0.is_empty());
+        }
+        if let Ok(secret) = std::env::var("CLIENT_TOKEN_SECRET") {
+            self.client_token_secret = Some(Secret(secret)).filter(|secret| !secret.0.is_empty());
+        }
+        if let Some(len) = env_u64("PEER_ID_MIN_LEN") {
+            self.peer_id_min_len = len as usize;
+        }
+        if let Some(len) = env_u64("PEER_ID_MAX_LEN") {
+            self.peer_id_max_len = len as usize;
+        }
+        if let Ok(chars) = std::env::var("PEER_ID_EXTRA_CHARS") {
+            self.peer_id_extra_chars = chars;
+        }
+        if let Ok(pattern) = std::env::var("PEER_ID_PATTERN") {
+            self.peer_id_pattern = Some(pattern).filter(|pattern| !pattern.is_empty());
+        }
+        if let Ok(raw) = std::env::var("PEER_ID_STRATEGY") {
+            match raw.trim() {
+                "short_uuid" => self.peer_id_strategy = PeerIdStrategy::ShortUuid,
+                "uuid" => self.peer_id_strategy = PeerIdStrategy::Uuid,
+                "sequential" => self.peer_id_strategy = PeerIdStrategy::Sequential,
+                "random_words" => self.peer_id_strategy = PeerIdStrategy::RandomWords,
+                "client_provided_only" => self.peer_id_strategy = PeerIdStrategy::ClientProvidedOnly,
+                _ => warn!(
+                    "Ignoring PEER_ID_STRATEGY='{}': expected short_uuid, uuid, sequential, random_words or client_provided_only",
+                    raw
+                ),
+            }
+        }
+        if let Ok(prefix) = std::env::var("PEER_ID_SEQUENTIAL_PREFIX") {
+            self.peer_id_sequential_prefix = prefix;
+        }
+        if let Ok(raw) = std::env::var("DUPLICATE_PEER_ID_POLICY") {
+            match raw.trim() {
+                "reject_new" => self.duplicate_peer_id_policy = DuplicatePeerIdPolicy::RejectNew,
+                "replace_old" => self.duplicate_peer_id_policy = DuplicatePeerIdPolicy::ReplaceOld,
+                _ => warn!(
+                    "Ignoring DUPLICATE_PEER_ID_POLICY='{}': expected reject_new or replace_old",
+                    raw
+                ),
+            }
+        }
+        if let Some(secs) = env_u64("PRESENCE_NOTIFICATION_TTL_SECS") {
+            self.presence_notification_ttl_secs = secs;
+        }
+        if let Some(required) = env_bool("REQUIRE_INITIAL_PONG") {
+            self.require_initial_pong = required;
+        }
+        if let Some(secs) = env_u64("INITIAL_PONG_TIMEOUT_SECS") {
+            self.initial_pong_timeout_secs = secs;
+        }
+        if let Ok(name) = std::env::var("SERVER_NAME") {
+            self.server_name = name;
+        }
+        if let Ok(id) = std::env::var("INSTANCE_ID") {
+            self.instance_id = Some(id).filter(|id| !id.is_empty());
+        }
+        if let Some(ms) = env_u64("DRAIN_TIMEOUT_MS") {
+            self.drain_timeout_ms = ms;
+        }
+        if let Some(size) = env_u64("PEER_LIST_CHUNK_SIZE") {
+            self.peer_list_chunk_size = size as usize;
+        }
+        if let Some(bytes) = env_u64("SNAPSHOT_COMPRESSION_MIN_BYTES") {
+            self.snapshot_compression_min_bytes = bytes as usize;
+        }
+        if let Some(size) = env_u64("CHAT_HISTORY_SIZE") {
+            self.chat_history_size = size as usize;
+        }
+        if let Some(max) = env_u64("MAX_UNSOLICITED_PONGS_PER_MIN") {
+            self.max_unsolicited_pongs_per_min = max;
+        }
+        if let Some(nodelay) = env_bool("TCP_NODELAY") {
+            self.tcp_nodelay = nodelay;
+        }
+        if let Some(secs) = env_u64("TCP_KEEPALIVE_SECS") {
+            self.tcp_keepalive_secs = secs;
+        }
+        if let Some(max) = env_u64("READ_RECEIPT_MAX_TRACKED") {
+            self.read_receipt_max_tracked = max as usize;
+        }
+        if let Some(secs) = env_u64("READ_RECEIPT_TTL_SECS") {
+            self.read_receipt_ttl_secs = secs;
+        }
+        if let Some(max) = env_u64("MAX_QUERY_PARAMS") {
+            self.max_query_params = max as usize;
+        }
+        if let Some(max) = env_u64("MAX_QUERY_BYTES") {
+            self.max_query_bytes = max as usize;
+        }
+        if let Ok(raw) = std::env::var("RUNTIME_FLAVOR") {
+            match raw.trim() {
+                "multi_thread" => self.runtime_flavor = RuntimeFlavor::MultiThread,
+                "current_thread" => self.runtime_flavor = RuntimeFlavor::CurrentThread,
+                _ => warn!(
+                    "Ignoring RUNTIME_FLAVOR='{}': expected multi_thread or current_thread",
+                    raw
+                ),
+            }
+        }
+        if let Some(threads) = env_u64("WORKER_THREADS") {
+            self.worker_threads = threads as usize;
+        }
+        if let Some(secs) = env_u64("RECONNECT_GRACE_SECS") {
+            self.reconnect_grace_secs = secs;
+        }
+        if let Some(max) = env_u64("OFFLINE_QUEUE_MAX_MESSAGES") {
+            self.offline_queue_max_messages = max as usize;
+        }
+        if let Ok(raw) = std::env::var("METHOD_SIZE_LIMITS") {
+            match parse_method_size_limits(&raw) {
+                Some(limits) => self.method_size_limits = limits,
+                None => warn!(
+                    "Ignoring METHOD_SIZE_LIMITS='{}': expected method=bytes,method=bytes",
+                    raw
+                ),
+            }
+        }
+        if let Ok(raw) = std::env::var("FEATURE_RULES") {
+            match parse_feature_rules(&raw) {
+                Some(rules) => self.feature_rules = rules,
+                None => warn!(
+                    "Ignoring FEATURE_RULES='{}': expected feature=peer|peer,feature=peer",
+                    raw
+                ),
+            }
+        }
+        if let Some(secs) = env_u64("CONNECTION_QUALITY_INTERVAL_SECS") {
+            self.connection_quality_interval_secs = secs;
+        }
+        if let Ok(raw) = std::env::var("VALIDATION_MODE") {
+            match raw.trim() {
+                "lenient" => self.validation_mode = ValidationMode::Lenient,
+                "reject_unknown" => self.validation_mode = ValidationMode::RejectUnknown,
+                "strict" => self.validation_mode = ValidationMode::Strict,
+                _ => warn!(
+                    "Ignoring VALIDATION_MODE='{}': expected lenient, reject_unknown or strict",
+                    raw
+                ),
+            }
+        }
+        if let Ok(raw) = std::env::var("RAW_RELAY_CHECKSUM") {
+            match raw.trim() {
+                "crc32" => self.raw_relay_checksum = ChecksumAlgorithm::Crc32,
+                "sha256" => self.raw_relay_checksum = ChecksumAlgorithm::Sha256,
+                _ => warn!("Ignoring RAW_RELAY_CHECKSUM='{}': expected crc32 or sha256", raw),
+            }
+        }
+        if let Some(max) = env_u64("MAX_GROUPS_PER_PEER") {
+            self.max_groups_per_peer = max as usize;
+        }
+        if let Some(echo) = env_bool("GROUP_MESSAGE_ECHO_TO_SENDER") {
+            self.group_message_echo_to_sender = echo;
+        }
+        if let Some(echo) = env_bool("DIRECT_MESSAGE_ECHO_TO_SENDER") {
+            self.direct_message_echo_to_sender = echo;
+        }
+        if let Some(ms) = env_u64("PRESENCE_COALESCE_WINDOW_MS") {
+            self.presence_coalesce_window_ms = ms;
+        }
+        if let Some(ms) = env_u64("LEAVE_COALESCE_WINDOW_MS") {
+            self.leave_coalesce_window_ms = ms;
+        }
+        if let Some(max) = env_u64("MAX_LEAVES_PER_PRESENCE_UPDATE") {
+            self.max_leaves_per_presence_update = max as usize;
+        }
+        if let Some(ms) = env_u64("MESSAGE_GROUPING_WINDOW_MS") {
+            self.message_grouping_window_ms = ms;
+        }
+        if let Ok(url) = std::env::var("UPSTREAM_URL") {
+            self.upstream_url = Some(url).filter(|url| !url.is_empty());
+        }
+        if let Some(every) = env_u64("LOG_SAMPLE_EVERY") {
+            self.log_sample_every = every;
+        }
+        if let Some(secs) = env_u64("IDLE_TIMEOUT_SECS") {
+            self.idle_timeout_secs = secs;
+        }
+        if let Some(secs) = env_u64("IDLE_WARNING_SECS") {
+            self.idle_warning_secs = secs;
+        }
+        if let Some(secs) = env_u64("HEARTBEAT_INTERVAL_SECS") {
+            self.heartbeat_interval_secs = secs;
+        }
+        if let Some(secs) = env_u64("HEARTBEAT_TIMEOUT_SECS") {
+            self.heartbeat_timeout_secs = secs;
+        }
+        if let Some(ms) = env_u64("ACK_TIMEOUT_MS") {
+            self.ack_timeout_ms = ms;
+        }
+        if let Some(retries) = env_u64("ACK_MAX_RETRIES") {
+            self.ack_max_retries = retries as u32;
+        }
+        if let Some(version) = env_version("MIN_CLIENT_VERSION") {
+            self.min_client_version = version;
+        }
+        if let Some(version) = env_version("RECOMMENDED_CLIENT_VERSION") {
+            self.recommended_client_version = version;
+        }
+        if let Ok(url) = std::env::var("CLIENT_UPGRADE_URL") {
+            self.client_upgrade_url = Some(url).filter(|url| !url.is_empty());
+        }
+        if let Ok(path) = std::env::var("TLS_CERT_PATH") {
+            self.tls_cert_path = Some(path).filter(|path| !path.is_empty());
+        }
+        if let Ok(path) = std::env::var("TLS_KEY_PATH") {
+            self.tls_key_path = Some(path).filter(|path| !path.is_empty());
+        }
+        if let Ok(path) = std::env::var("TLS_CLIENT_CA_PATH") {
+            self.tls_client_ca_path = Some(path).filter(|path| !path.is_empty());
+        }
+        if let Some(secs) = env_u64("EMPTY_ROOM_SWEEP_INTERVAL_SECS") {
+            self.empty_room_sweep_interval_secs = secs;
+        }
+        if let Some(secs) = env_u64("EMPTY_ROOM_GRACE_SECS") {
+            self.empty_room_grace_secs = secs;
+        }
+        if let Some(hide) = env_bool("HIDE_PROTECTED_ROOM_TOPICS") {
+            self.hide_protected_room_topics = hide;
+        }
+        if let Some(max) = env_u64("MAX_PINNED_MESSAGES") {
+            self.max_pinned_messages = max as usize;
+        }
+        if let Ok(methods) = std::env::var("UPSTREAM_BRIDGE_METHODS") {
+            self.upstream_bridge_methods = methods
+                .split(',')
+                .map(str::trim)
+                .filter(|method| !method.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+    }
+
+    // None when the lifetime cap is disabled
+    pub fn max_connection_lifetime(&self) -> Option<Duration> {
+        (self.max_connection_lifetime_secs > 0)
+            .then(|| Duration::from_secs(self.max_connection_lifetime_secs))
+    }
+
+    // How long to wait for the initial pong, or None when it isn't required
+    pub fn initial_pong_timeout(&self) -> Option<Duration> {
+        self.require_initial_pong
+            .then(|| Duration::from_secs(self.initial_pong_timeout_secs))
+    }
+
+    // (ping interval, pong timeout), or None when there is no heartbeat
+    pub fn heartbeat_policy(&self) -> Option<(Duration, Duration)> {
+        (self.heartbeat_interval_secs > 0).then(|| {
+            (
+                Duration::from_secs(self.heartbeat_interval_secs),
+                Duration::from_secs(self.heartbeat_timeout_secs),
+            )
+        })
+    }
+
+    // (timeout, warning lead time if any), or None when idle peers stay
+    pub fn idle_policy(&self) -> Option<(Duration, Option<Duration>)> {
+        (self.idle_timeout_secs > 0).then(|| {
+            let warning = (self.idle_warning_secs > 0 && self.idle_warning_secs < self.idle_timeout_secs)
+                .then(|| Duration::from_secs(self.idle_warning_secs));
+            (Duration::from_secs(self.idle_timeout_secs), warning)
+        })
+    }
+
+    // None when peer_left should go out immediately
+    pub fn reconnect_grace(&self) -> Option<Duration> {
+        (self.reconnect_grace_secs > 0).then(|| Duration::from_secs(self.reconnect_grace_secs))
+    }
+
+    // None when presence changes go out immediately
+    pub fn presence_coalesce_window(&self) -> Option<Duration> {
+        (self.presence_coalesce_window_ms > 0).then(|| Duration::from_millis(self.presence_coalesce_window_ms))
+    }
+
+    // What the WebSocket layer itself accepts: frames up to this size reach
+    // handle_socket and get a message_too_large error; larger ones end the
+    // connection before they are buffered in full
+    pub fn transport_frame_limit(&self) -> usize {
+        self.max_text_frame_bytes.max(self.max_binary_frame_bytes) * OVERSIZED_FRAME_HEADROOM
+    }
+
+    // None when leaves go out immediately
+    pub fn leave_coalesce_window(&self) -> Option<Duration> {
+        self.presence_coalesce_window()
+            .or_else(|| (self.leave_coalesce_window_ms > 0).then(|| Duration::from_millis(self.leave_coalesce_window_ms)))
+    }
+
+    // None when chat messages are never marked groupedWithPrevious
+    pub fn message_grouping_window(&self) -> Option<Duration> {
+        (self.message_grouping_window_ms > 0).then(|| Duration::from_millis(self.message_grouping_window_ms))
+    }
+
+    // None when disconnects shouldn't wait for the outbound queue
+    pub fn drain_timeout(&self) -> Option<Duration> {
+        (self.drain_timeout_ms > 0).then(|| Duration::from_millis(self.drain_timeout_ms))
+    }
+
+    // expiresAt for a join/leave notification created now, or None without a TTL
+    pub fn presence_expires_at(&self) -> Option<u64> {
+        (self.presence_notification_ttl_secs > 0)
+            .then(|| crate::now_ms() + self.presence_notification_ttl_secs * 1000)
+    }
+
+    // Idle time before keepalive probes, or None to leave keepalive off
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        (self.tcp_keepalive_secs > 0).then(|| Duration::from_secs(self.tcp_keepalive_secs))
+    }
+
+    // Settings that are only read at startup, so a reload that changes them
+    // has no effect until the server restarts. Everything else (limits,
+    // timeouts, tokens, ...) applies live.
+    pub fn restart_required_changes(&self, new: &ServerConfig) -> Vec<&'static str> {
+        let changed = [
+            ("runtime_flavor", self.runtime_flavor != new.runtime_flavor),
+            ("worker_threads", self.worker_threads != new.worker_threads),
+            ("tcp_nodelay", self.tcp_nodelay != new.tcp_nodelay),
+            ("tcp_keepalive_secs", self.tcp_keepalive_secs != new.tcp_keepalive_secs),
+            ("server_name", self.server_name != new.server_name),
+            ("instance_id", self.instance_id != new.instance_id),
+            ("chat_history_size", self.chat_history_size != new.chat_history_size),
+            ("read_receipt_max_tracked", self.read_receipt_max_tracked != new.read_receipt_max_tracked),
+            ("read_receipt_ttl_secs", self.read_receipt_ttl_secs != new.read_receipt_ttl_secs),
+            ("peer_id_min_len", self.peer_id_min_len != new.peer_id_min_len),
+            ("peer_id_max_len", self.peer_id_max_len != new.peer_id_max_len),
+            ("peer_id_extra_chars", self.peer_id_extra_chars != new.peer_id_extra_chars),
+            ("peer_id_pattern", self.peer_id_pattern != new.peer_id_pattern),
+            ("upstream_url", self.upstream_url != new.upstream_url),
+            ("upstream_bridge_methods", self.upstream_bridge_methods != new.upstream_bridge_methods),
+            ("tls_cert_path", self.tls_cert_path != new.tls_cert_path),
+            ("tls_key_path", self.tls_key_path != new.tls_key_path),
+            ("tls_client_ca_path", self.tls_client_ca_path != new.tls_client_ca_path),
+            (
+                "empty_room_sweep_interval_secs",
+                self.empty_room_sweep_interval_secs != new.empty_room_sweep_interval_secs,
+            ),
+        ];
+        changed
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // None when empty rooms are never swept
+    pub fn empty_room_sweep_interval(&self) -> Option<Duration> {
+        (self.empty_room_sweep_interval_secs > 0).then(|| Duration::from_secs(self.empty_room_sweep_interval_secs))
+    }
+
+    // Report interval (never below the floor), or None when reports are off
+    pub fn connection_quality_interval(&self) -> Option<Duration> {
+        (self.connection_quality_interval_secs > 0).then(|| {
+            Duration::from_secs(self.connection_quality_interval_secs).max(MIN_CONNECTION_QUALITY_INTERVAL)
+        })
+    }
+
+    // (max queue depth, grace period), or None when the policy is disabled
+    pub fn slow_client_policy(&self) -> Option<(usize, Duration)> {
+        (self.slow_client_max_queue_depth > 0).then(|| {
+            (
+                self.slow_client_max_queue_depth,
+                Duration::from_secs(self.slow_client_grace_secs),
+            )
+        })
+    }
+}
+
+// `--config path.toml` / `--config=path.toml` on the command line, else CONFIG_FILE
+fn config_file_path() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    std::env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty())
+}
+
+// Reads an env var as u64, ignoring it (with a warning) if it doesn't parse
+fn env_u64(name: &str) -> Option<u64> {
+    let raw = std::env::var(name).ok()?;
+    match raw.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring {}='{}': expected a whole number", name, raw);
+            None
+        }
+    }
+}
+
+// "a=1,b=2" -> {a: 1, b: 2}; None if any entry is malformed
+fn parse_method_size_limits(raw: &str) -> Option<HashMap<String, usize>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (method, bytes) = entry.split_once('=')?;
+            Some((method.trim().to_string(), bytes.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+// "a=x|y*,b=z" -> {a: [x, y*], b: [z]}; None if any entry is malformed
+fn parse_feature_rules(raw: &str) -> Option<HashMap<String, Vec<String>>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (feature, peers) = entry.split_once('=')?;
+            let peers = peers.split('|').map(str::trim).filter(|peer| !peer.is_empty());
+            Some((feature.trim().to_string(), peers.map(str::to_string).collect()))
+        })
+        .collect()
+}
+
+// Reads an env var as a semver version; Some(None) when set but empty (= no floor)
+fn env_version(name: &str) -> Option<Option<Version>> {
+    let raw = std::env::var(name).ok()?;
+    if raw.trim().is_empty() {
+        return Some(None);
+    }
+    match Version::parse(raw.trim()) {
+        Ok(version) => Some(Some(version)),
+        Err(_) => {
+            warn!("Ignoring {}='{}': expected a semver version like 1.4.0", name, raw);
+            None
+        }
+    }
+}
+
+// Reads an env var as a bool ("true"/"false"/"1"/"0"), ignoring anything else
+fn env_bool(name: &str) -> Option<bool> {
+    let raw = std::env::var(name).ok()?;
+    match raw.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => {
+            warn!("Ignoring {}='{}': expected true or false", name, raw);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_is_off_with_a_zero_interval() {
+        let config = ServerConfig { heartbeat_interval_secs: 0, heartbeat_timeout_secs: 0, ..Default::default() };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.heartbeat_policy(), None);
+    }
+
+    #[test]
+    fn rejects_a_zero_heartbeat_timeout() {
+        let config = ServerConfig { heartbeat_timeout_secs: 0, ..Default::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = ServerConfig { heartbeat_interval_secs: 30, heartbeat_timeout_secs: 1, ..Default::default() };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.heartbeat_policy(), Some((Duration::from_secs(30), Duration::from_secs(1))));
+    }
+
+    #[test]
+    fn rejects_an_empty_protocol_version_range() {
+        let config = ServerConfig { min_protocol_version: 3, max_protocol_version: 2, ..Default::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = ServerConfig { min_protocol_version: 2, max_protocol_version: 2, ..Default::default() };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn idle_warnings_need_room_before_the_timeout() {
+        let idle = |timeout, warning| {
+            ServerConfig { idle_timeout_secs: timeout, idle_warning_secs: warning, ..Default::default() }.idle_policy()
+        };
+        assert_eq!(idle(0, 30), None);
+        assert_eq!(idle(60, 30), Some((Duration::from_secs(60), Some(Duration::from_secs(30)))));
+        // A lead time as long as the timeout would warn right at connect
+        assert_eq!(idle(30, 30), Some((Duration::from_secs(30), None)));
+        assert_eq!(idle(60, 0), Some((Duration::from_secs(60), None)));
+    }
+}
+
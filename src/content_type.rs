@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use crate::{error_notification, notification, send_server_message, AppState, Client};
+
+// What a chat_message without a contentType counts as when filtering
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+// Longest accepted content type or filter pattern, in bytes
+const MAX_CONTENT_TYPE_BYTES: usize = 128;
+// Most patterns one peer may filter on
+const MAX_FILTER_PATTERNS: usize = 32;
+
+// MIME-style content types on chat messages. A sender may tag a chat_message
+// with data.contentType (e.g. "text/markdown", "application/json; v=2",
+// "image/png"), which is relayed as-is so recipients can render it. Peers
+// may narrow what they receive with a "set_content_filter" request:
+// data.contentTypes is a comma-separated list of types, "type/*" or "*/*";
+// empty means everything (the default). Untagged messages are text/plain,
+// and parameters (after ';') are ignored when matching.
+
+// Checks `raw` looks like "type/subtype[; params]" and returns it trimmed
+pub fn parse(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    if raw.len() > MAX_CONTENT_TYPE_BYTES {
+        return Err(format!("content type is longer than {} bytes", MAX_CONTENT_TYPE_BYTES));
+    }
+    let valid = essence(raw)
+        .split_once('/')
+        .is_some_and(|(kind, subtype)| is_token(kind) && is_token(subtype));
+    if !valid {
+        return Err(format!("'{}' is not a content type like text/markdown", raw));
+    }
+    Ok(raw.to_string())
+}
+
+// Does a peer with these filter patterns want a message of `content_type`?
+pub fn accepts(patterns: &[String], content_type: &str) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    let essence = essence(content_type).to_ascii_lowercase();
+    let kind = essence.split('/').next().unwrap_or_default();
+    patterns.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(pattern_kind) => pattern_kind == kind,
+        None => *pattern == essence,
+    })
+}
+
+// Handles "set_content_filter": data.contentTypes replaces the peer's filter.
+// Replies with "content_filter" listing the patterns now in effect.
+pub async fn handle_set_filter(state: &AppState, peer_id: &str, client: &Client, data: &HashMap<String, String>) {
+    let patterns = match parse_filter(data.get("contentTypes").map(String::as_str).unwrap_or_default()) {
+        Ok(patterns) => patterns,
+        Err(reason) => {
+            let reply = error_notification("invalid_content_type", &reason);
+            send_server_message(client, &reply, "set_content_filter").await;
+            return;
+        }
+    };
+
+    let mut reply_data = HashMap::new();
+    reply_data.insert("contentTypes".to_string(), patterns.join(","));
+    {
+        let mut peers_guard = state.peers.lock().await;
+        let Some(me) = peers_guard.get_mut(peer_id) else {
+            return;
+        };
+        me.accepted_content_types = patterns;
+    }
+    send_server_message(client, &notification("content_filter", reply_data), "set_content_filter").await;
+}
+
+// Lowercased patterns, parameters dropped; empty input = no filter
+fn parse_filter(raw: &str) -> Result<Vec<String>, String> {
+    let mut patterns = Vec::new();
+    for pattern in raw.split(',').map(str::trim).filter(|pattern| !pattern.is_empty()) {
+        let pattern = match pattern.strip_suffix("/*") {
+            Some(kind) if kind == "*" || is_token(kind) => pattern.to_ascii_lowercase(),
+            _ => essence(&parse(pattern)?).to_ascii_lowercase(),
+        };
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+    }
+    if patterns.len() > MAX_FILTER_PATTERNS {
+        return Err(format!("at most {} content types can be filtered on", MAX_FILTER_PATTERNS));
+    }
+    Ok(patterns)
+}
+
+fn essence(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+// RFC 6838 restricted-name characters
+fn is_token(part: &str) -> bool {
+    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+// The frame-type split on /ws:
+// - binary frames carry data: protobuf Envelopes (chat, presence, ...)
+// - text frames carry out-of-band control commands as small JSON objects,
+//   e.g. {"cmd":"ping","id":"42"}, answered with a JSON text frame
+// Both arrive on the same socket and are handled in order by the one
+// receive loop, so a client can freely interleave them.
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum ControlCommand {
+    // Application-level liveness check (browsers can't send WebSocket pings)
+    Ping { id: Option<String> },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum ControlReply {
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    Error { code: &'static str, message: String },
+}
+
+// Runs one text-frame command and returns the JSON reply to send back
+pub fn handle(text: &str) -> String {
+    let reply = match serde_json::from_str::<ControlCommand>(text) {
+        Ok(ControlCommand::Ping { id }) => ControlReply::Pong { id },
+        Err(e) => {
+            debug!("Invalid control command {:?}: {}", text, e);
+            ControlReply::Error {
+                code: "invalid_control",
+                message: e.to_string(),
+            }
+        }
+    };
+    serde_json::to_string(&reply).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testing::TestServer;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn reply(text: &str) -> Value {
+        serde_json::from_str(&handle(text)).expect("JSON reply")
+    }
+
+    #[test]
+    fn ping_is_answered_with_its_id() {
+        assert_eq!(reply(r#"{"cmd":"ping","id":"42"}"#), json!({"cmd": "pong", "id": "42"}));
+        assert_eq!(reply(r#"{"cmd":"ping"}"#), json!({"cmd": "pong"}));
+    }
+
+    #[test]
+    fn anything_else_is_an_invalid_control_error() {
+        for text in [r#"{"cmd":"reboot"}"#, r#"{"id":"1"}"#, "ping", ""] {
+            let reply = reply(text);
+            assert_eq!(reply["cmd"], "error", "{:?}", text);
+            assert_eq!(reply["code"], "invalid_control", "{:?}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn control_and_data_frames_interleave() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        ann.expect("peer_joined").await;
+
+        ann.send(Message::Text(r#"{"cmd":"ping","id":"1"}"#.to_string())).await;
+        ann.request("chat_message", &[("text", "between pings")]).await;
+        ann.send(Message::Text(r#"{"cmd":"ping","id":"2"}"#.to_string())).await;
+        ann.request("list_peers", &[]).await;
+        ann.send(Message::Text("not a command".to_string())).await;
+
+        // Answered in the order sent, each in its own frame type
+        let mut replies = Vec::new();
+        while replies.len() < 4 {
+            match ann.next_frame(Duration::from_secs(5)).await.expect("a reply") {
+                Message::Text(text) => {
+                    let reply: Value = serde_json::from_str(&text).expect("JSON reply");
+                    replies.push(format!("{} {}", reply["cmd"], reply["id"]));
+                }
+                Message::Binary(_) => replies.push("binary".to_string()),
+                _ => {}
+            }
+        }
+        assert_eq!(replies, [r#""pong" "1""#, r#""pong" "2""#, "binary", r#""error" null"#]);
+        assert_eq!(bob.expect("chat_message").await.data["text"], "between pings");
+    }
+}
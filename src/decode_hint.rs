@@ -0,0 +1,33 @@
+use prost::Message;
+
+use crate::generated::{Envelope, EventData};
+
+// Debugging aid for the common wire-format mistakes.
+// When a binary frame isn't a usable "request" Envelope, try the other shapes
+// a client might have sent by mistake and describe the match for the log.
+// Nothing here is ever acted upon: only the real Envelope path is processed.
+//
+// `decoded` is the Envelope if the bytes did decode as one (protobuf is lenient,
+// so a bare EventData often "decodes" as an Envelope with garbage fields).
+pub fn wrong_type_hint(bytes: &[u8], decoded: Option<&Envelope>) -> Option<&'static str> {
+    if let Some(envelope) = decoded {
+        if envelope.event == "notification" {
+            return Some("a server notification Envelope was sent back to the server; clients must send event=\"request\"");
+        }
+    }
+
+    if let Ok(event_data) = EventData::decode(bytes) {
+        let looks_like_event_data = !event_data.method.is_empty()
+            && decoded.is_none_or(|envelope| envelope.event == event_data.method);
+        if looks_like_event_data {
+            return Some("a bare EventData was sent; wrap it in an Envelope with event=\"request\"");
+        }
+    }
+
+    match std::str::from_utf8(bytes).map(str::trim_start) {
+        Ok(text) if text.starts_with('{') || text.starts_with('[') => {
+            Some("JSON text was sent in a binary frame; encode an Envelope protobuf instead")
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use crate::logging::sampled;
+use crate::{error_notification, notification, queue_server_message, send_server_message, AppState, Client};
+
+// End-to-end encrypted messages ("encrypted_message" requests).
+//
+// In this mode the server is a blind relay: clients agree on keys themselves
+// (out of band, or by sending each other public keys as encrypted_message
+// payloads) and encrypt before sending. The server only reads the cleartext
+// routing metadata (toPeerId) and forwards `payload` byte-for-byte. It can't
+// decrypt it, and doesn't transform it or keep it in chat history.
+//
+// Request data:  {payload, toPeerId?}  (no toPeerId = every e2e-capable peer)
+// Notification:  {fromPeerId, payload, toPeerId?}
+// Only peers in the sender's room that connected with ?caps=e2e receive these.
+pub async fn relay_encrypted(
+    state: &AppState,
+    peer_id: &str,
+    room: &str,
+    client: &Client,
+    data: &HashMap<String, String>,
+) {
+    let Some(payload) = data.get("payload").filter(|payload| !payload.is_empty()) else {
+        let reply = error_notification("missing_payload", "encrypted_message requires a payload");
+        send_server_message(client, &reply, "encrypted_message_error").await;
+        return;
+    };
+    let to_peer_id = data.get("toPeerId").filter(|id| !id.is_empty());
+
+    let mut out_data = HashMap::new();
+    out_data.insert("fromPeerId".to_string(), peer_id.to_string());
+    out_data.insert("payload".to_string(), payload.clone());
+    if let Some(to) = to_peer_id {
+        out_data.insert("toPeerId".to_string(), to.clone());
+    }
+    let message = notification("encrypted_message", out_data);
+
+    let peers_guard = state.peers.lock().await;
+    match to_peer_id {
+        Some(to) => {
+            let Some(recipient) = peers_guard.get(to).filter(|peer| peer.room == room && peer.capabilities.e2e) else {
+                drop(peers_guard);
+                let reply = error_notification(
+                    "e2e_recipient_unavailable",
+                    &format!("Peer '{}' is not connected with the e2e capability", to),
+                );
+                send_server_message(client, &reply, "encrypted_message_error").await;
+                return;
+            };
+            queue_server_message(&recipient.sender, &message, "encrypted_message");
+        }
+        None => {
+            for (id, peer) in peers_guard.iter() {
+                if id != peer_id && peer.room == room && peer.capabilities.e2e {
+                    queue_server_message(&peer.sender, &message, "encrypted_message");
+                }
+            }
+        }
+    }
+    sampled!(
+        info,
+        "Relayed encrypted_message from {} to {} ({} payload bytes)",
+        peer_id,
+        to_peer_id.map(String::as_str).unwrap_or("all e2e peers"),
+        payload.len()
+    );
+}
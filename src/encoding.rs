@@ -0,0 +1,113 @@
+use axum::extract::ws::Message as WsMessage;
+use base64::Engine;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::generated::{DataItem, Envelope, EventData};
+
+// Wire format of the frames a peer receives. Protobuf (binary frames) is the
+// baseline; peers that connect with ?caps=json get every notification as a
+// JSON text frame instead, and may send requests as JSON text frames too:
+//   {"event":"request","eventData":{"method":"chat_message","data":{"text":"hi"}}}
+// Envelopes are the canonical form in between, so protobuf and JSON peers
+// talk to each other transparently. JSON peers tell notifications
+// ("event" key) from control replies ("cmd" key, see control.rs) by shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+// JSON view of an Envelope: camelCase keys, items as plain objects, payload
+// as base64. Empty items/payload are left out.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonEnvelope {
+    event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event_data: Option<JsonEventData>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    server_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    instance_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonEventData {
+    #[serde(default)]
+    method: String,
+    #[serde(default)]
+    data: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    items: Vec<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    payload: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    checksum: String,
+}
+
+pub fn to_json(envelope: &Envelope) -> String {
+    let json = JsonEnvelope {
+        event: envelope.event.clone(),
+        event_data: envelope.event_data.as_ref().map(|event_data| JsonEventData {
+            method: event_data.method.clone(),
+            data: event_data.data.clone(),
+            items: event_data.items.iter().map(|item| item.data.clone()).collect(),
+            payload: base64::engine::general_purpose::STANDARD.encode(&event_data.payload),
+            checksum: base64::engine::general_purpose::STANDARD.encode(&event_data.checksum),
+        }),
+        server_name: envelope.server_name.clone(),
+        instance_id: envelope.instance_id.clone(),
+    };
+    serde_json::to_string(&json).unwrap_or_default()
+}
+
+// None if `text` isn't a JSON Envelope (e.g. it's a control command)
+pub fn from_json(text: &str) -> Option<Envelope> {
+    let json: JsonEnvelope = serde_json::from_str(text).ok()?;
+    let event_data = match json.event_data {
+        None => None,
+        Some(event_data) => Some(EventData {
+            method: event_data.method,
+            data: event_data.data,
+            items: event_data.items.into_iter().map(|data| DataItem { data }).collect(),
+            payload: base64::engine::general_purpose::STANDARD.decode(event_data.payload).ok()?,
+            checksum: base64::engine::general_purpose::STANDARD.decode(event_data.checksum).ok()?,
+        }),
+    };
+    Some(Envelope {
+        event: json.event,
+        event_data,
+        server_name: json.server_name,
+        instance_id: json.instance_id,
+    })
+}
+
+pub fn encode(envelope: &Envelope, encoding: Encoding) -> WsMessage {
+    match encoding {
+        Encoding::Protobuf => WsMessage::Binary(envelope.encode_to_vec()),
+        Encoding::Json => WsMessage::Text(to_json(envelope)),
+    }
+}
+
+// One envelope on its way to many peers: encoded at most once per format
+pub struct EncodedOnce<'a> {
+    envelope: &'a Envelope,
+    protobuf: Option<WsMessage>,
+    json: Option<WsMessage>,
+}
+
+impl<'a> EncodedOnce<'a> {
+    pub fn new(envelope: &'a Envelope) -> Self {
+        Self { envelope, protobuf: None, json: None }
+    }
+
+    pub fn get(&mut self, encoding: Encoding) -> WsMessage {
+        let cached = match encoding {
+            Encoding::Protobuf => &mut self.protobuf,
+            Encoding::Json => &mut self.json,
+        };
+        cached.get_or_insert_with(|| encode(self.envelope, encoding)).clone()
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+
+// Per-connection feature flags, for tiered service on one server (e.g. raw
+// relaying for premium peers only). Unlike capabilities, which a client
+// declares, these are the server's decision.
+//
+// A feature is a request method (e.g. "group_message") or "raw_relay" for raw
+// binary frames. Features with no rule in feature_rules are open to everyone.
+// A feature with a rule is only enabled for the peer ids it lists ("premium_*"
+// matches by prefix). With mutual TLS the certificate identity is the peer id.
+// Resolved once at connect time: a config reload applies to new connections.
+// Using a disabled feature gets a "feature_disabled" error; the peer is told
+// up front in a "features" notification {disabled: "a,b"}.
+pub const RAW_RELAY: &str = "raw_relay";
+
+#[derive(Debug, Clone, Default)]
+pub struct FeatureSet {
+    disabled: HashSet<String>,
+}
+
+impl FeatureSet {
+    pub fn resolve(rules: &HashMap<String, Vec<String>>, peer_id: &str) -> Self {
+        let disabled = rules
+            .iter()
+            .filter(|(_, allowed)| !allowed.iter().any(|pattern| matches(pattern, peer_id)))
+            .map(|(feature, _)| feature.clone())
+            .collect();
+        Self { disabled }
+    }
+
+    pub fn allows(&self, feature: &str) -> bool {
+        !self.disabled.contains(feature)
+    }
+
+    // Sorted, for the "features" notification and logs
+    pub fn disabled(&self) -> Vec<&str> {
+        let mut disabled: Vec<&str> = self.disabled.iter().map(String::as_str).collect();
+        disabled.sort_unstable();
+        disabled
+    }
+}
+
+// "premium_*" matches by prefix, anything else exactly
+pub fn matches(pattern: &str, peer_id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => peer_id.starts_with(prefix),
+        None => pattern == peer_id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::raw_relay::KIND_RAW;
+    use crate::testing::TestServer;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn rules() -> HashMap<String, Vec<String>> {
+        HashMap::from([
+            (RAW_RELAY.to_string(), vec!["alice".to_string(), "premium_*".to_string()]),
+            ("group_message".to_string(), vec!["premium_*".to_string()]),
+        ])
+    }
+
+    #[test]
+    fn features_with_a_rule_are_only_for_the_listed_peers() {
+        let basic = FeatureSet::resolve(&rules(), "bob");
+        assert_eq!(basic.disabled(), ["group_message", RAW_RELAY]);
+        assert!(basic.allows("chat_message"));
+
+        let alice = FeatureSet::resolve(&rules(), "alice");
+        assert!(alice.allows(RAW_RELAY));
+        assert!(!alice.allows("group_message"));
+        // Exact names don't match by prefix
+        assert!(!FeatureSet::resolve(&rules(), "alice2").allows(RAW_RELAY));
+
+        let premium = FeatureSet::resolve(&rules(), "premium_carol");
+        assert!(premium.disabled().is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_features_are_rejected() {
+        let server = TestServer::start(ServerConfig { feature_rules: rules(), ..Default::default() }).await;
+        let mut bob = server.connect("peerId=bob&caps=framed").await;
+        bob.expect("peer_list_chunk").await;
+        assert_eq!(bob.expect("features").await.data["disabled"], "group_message,raw_relay");
+        let mut premium = server.connect("peerId=premium_carol&caps=framed").await;
+        premium.expect("peer_list_chunk").await;
+        premium.expect_no("features", Duration::from_millis(300)).await;
+
+        bob.send(Message::Binary(vec![KIND_RAW, 1, 2, 3])).await;
+        let error = bob.expect("error").await;
+        assert_eq!(error.data["code"], "feature_disabled");
+        assert_eq!(error.data["message"], "'raw_relay' is not enabled for this connection");
+        premium.expect_no("raw_relay", Duration::from_millis(300)).await;
+
+        // Request methods too (from an unframed peer, whose frames need no kind byte)
+        let mut dan = server.join("dan", "lobby").await;
+        dan.request("group_message", &[("group", "team"), ("text", "hi")]).await;
+        assert_eq!(dan.expect("error").await.data["code"], "feature_disabled");
+
+        premium.send(Message::Binary(vec![KIND_RAW, 4, 5, 6])).await;
+        assert_eq!(bob.expect("raw_relay").await.payload, [4, 5, 6]);
+    }
+}
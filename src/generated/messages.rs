@@ -13,6 +13,24 @@ pub struct EventData {
         ::prost::alloc::string::String,
         ::prost::alloc::string::String,
     >,
+    /// List-shaped payloads (e.g. peer lists), one key/value record per item
+    #[prost(message, repeated, tag = "3")]
+    pub items: ::prost::alloc::vec::Vec<DataItem>,
+    /// Opaque application bytes (e.g. raw_relay), never interpreted by the server
+    #[prost(bytes = "vec", tag = "4")]
+    pub payload: ::prost::alloc::vec::Vec<u8>,
+    /// Checksum of payload (see checksum.rs), empty when the sender didn't send one
+    #[prost(bytes = "vec", tag = "5")]
+    pub checksum: ::prost::alloc::vec::Vec<u8>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataItem {
+    #[prost(map = "string, string", tag = "1")]
+    pub data: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -22,4 +40,9 @@ pub struct Envelope {
     pub event: ::prost::alloc::string::String,
     #[prost(message, optional, tag = "2")]
     pub event_data: ::core::option::Option<EventData>,
+    /// Which server sent it (set on notifications, ignored on requests)
+    #[prost(string, tag = "3")]
+    pub server_name: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub instance_id: ::prost::alloc::string::String,
 }
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::generated::EventData;
+use crate::logging::sampled;
+use crate::{
+    error_notification, notification, notification_envelope, queue_server_message, send_server_message, AppState, Client,
+};
+
+// Longest accepted group label, in bytes
+const MAX_GROUP_NAME_BYTES: usize = 64;
+
+// Group labels a peer is tagged with, e.g. "moderators" or "team-blue".
+// A peer can be in several groups and groups overlap freely. Set at connect
+// time with ?groups=a,b or later with a "set_groups" request; "group_message"
+// relays to everyone in one group.
+//
+// group -> peer ids, so a group send doesn't scan every peer. Peer::groups is
+// the per-peer side; both are only changed while holding the peers lock, so
+// they always agree.
+#[derive(Default)]
+pub struct GroupIndex {
+    // std Mutex: only held to update/copy, never across .await
+    members: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl GroupIndex {
+    // Moves `peer_id` from the `old` groups to the `new` ones
+    pub fn replace(&self, peer_id: &str, old: &HashSet<String>, new: &HashSet<String>) {
+        let mut members = self.lock();
+        for group in old.difference(new) {
+            if let Some(ids) = members.get_mut(group) {
+                ids.remove(peer_id);
+                if ids.is_empty() {
+                    members.remove(group);
+                }
+            }
+        }
+        for group in new.difference(old) {
+            members.entry(group.clone()).or_default().insert(peer_id.to_string());
+        }
+    }
+
+    // On disconnect
+    pub fn remove_peer(&self, peer_id: &str, groups: &HashSet<String>) {
+        self.replace(peer_id, groups, &HashSet::new());
+    }
+
+    pub fn members(&self, group: &str) -> Vec<String> {
+        self.lock().get(group).map(|ids| ids.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, HashSet<String>>> {
+        self.members.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Parses a comma-separated group list (?groups= or set_groups data.groups).
+// Blank entries are skipped; an empty list is valid and means "no groups".
+pub fn parse_groups(raw: &str, max_groups: usize) -> Result<HashSet<String>, String> {
+    let groups: HashSet<String> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(long) = groups.iter().find(|group| group.len() > MAX_GROUP_NAME_BYTES) {
+        return Err(format!("group '{}' is longer than {} bytes", long, MAX_GROUP_NAME_BYTES));
+    }
+    if groups.len() > max_groups {
+        return Err(format!("at most {} groups per peer are allowed", max_groups));
+    }
+    Ok(groups)
+}
+
+// Handles "set_groups": data.groups replaces the peer's whole group set.
+// Replies with "groups" listing the groups now in effect.
+pub async fn handle_set_groups(state: &AppState, peer_id: &str, client: &Client, data: &HashMap<String, String>) {
+    let max = state.config.current().max_groups_per_peer;
+    let groups = match parse_groups(data.get("groups").map(String::as_str).unwrap_or_default(), max) {
+        Ok(groups) => groups,
+        Err(reason) => {
+            send_server_message(client, &error_notification("invalid_groups", &reason), "invalid_groups").await;
+            return;
+        }
+    };
+
+    let mut names: Vec<String> = groups.iter().cloned().collect();
+    names.sort_unstable();
+    {
+        let mut peers_guard = state.peers.lock().await;
+        let Some(me) = peers_guard.get_mut(peer_id) else {
+            return;
+        };
+        state.groups.replace(peer_id, &me.groups, &groups);
+        me.groups = groups;
+    }
+
+    // Sent after the peers lock is released: a client that doesn't read
+    // can't hold it
+    let mut reply_data = HashMap::new();
+    reply_data.insert("groups".to_string(), names.join(","));
+    send_server_message(client, &notification("groups", reply_data), "set_groups").await;
+}
+
+// Handles "group_message" {group, text}: relayed as "group_message" to the
+// group's members in the sender's room. The sender doesn't need to be in the
+// group. It gets its own copy only if it is a member and
+// group_message_echo_to_sender is on.
+pub async fn send_group_message(
+    state: &AppState,
+    peer_id: &str,
+    display_name: &str,
+    room: &str,
+    client: &Client,
+    data: &HashMap<String, String>,
+) {
+    let Some(group) = data.get("group").filter(|group| !group.is_empty()) else {
+        let reply = error_notification("invalid_groups", "group_message requires a group");
+        send_server_message(client, &reply, "group_message").await;
+        return;
+    };
+
+    let mut out_data = HashMap::new();
+    out_data.insert("group".to_string(), group.clone());
+    out_data.insert("fromPeerId".to_string(), peer_id.to_string());
+    out_data.insert("fromDisplayName".to_string(), display_name.to_string());
+    out_data.insert("text".to_string(), data.get("text").cloned().unwrap_or_default());
+    let message = notification_envelope(EventData {
+        method: "group_message".to_string(),
+        data: out_data,
+        items: Vec::new(),
+        payload: Vec::new(),
+        checksum: Vec::new(),
+    });
+    state.shadow.observe(&message);
+
+    let echo_to_sender = state.config.current().group_message_echo_to_sender;
+    let peers_guard = state.peers.lock().await;
+    let mut delivered = 0;
+    for id in state.groups.members(group) {
+        if id == peer_id && !echo_to_sender {
+            continue;
+        }
+        if let Some(peer) = peers_guard.get(&id).filter(|peer| peer.room == room) {
+            if queue_server_message(&peer.sender, &message, "group_message") {
+                delivered += 1;
+            }
+        }
+    }
+    sampled!(info, "group_message from {} to group '{}': {} peers", peer_id, group, delivered);
+}
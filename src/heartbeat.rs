@@ -0,0 +1,107 @@
+use tokio::time::{Duration, Instant};
+
+// Liveness check for one connection. A TCP connection that silently died
+// (pulled cable, NAT dropped the mapping) never reports an error, so without
+// this its Peer would stay in the map forever.
+//
+// Every heartbeat_interval_secs the server sends a ping with a fresh nonce.
+// If the matching pong hasn't arrived heartbeat_timeout_secs later, the
+// connection is treated as dead: closed, removed, and announced as left
+// (after the reconnect grace period, if one is configured).
+// A new ping is only sent once the previous one was answered.
+pub struct Heartbeat {
+    timeout: Duration,
+    next_nonce: u64,
+    // Ping we are waiting on: (nonce, when it was sent)
+    outstanding: Option<(Vec<u8>, Instant)>,
+}
+
+impl Heartbeat {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, next_nonce: 0, outstanding: None }
+    }
+
+    // Payload for the next ping, or None while the last one is unanswered
+    pub fn start_ping(&mut self) -> Option<Vec<u8>> {
+        if self.outstanding.is_some() {
+            return None;
+        }
+        self.next_nonce += 1;
+        let nonce = format!("heartbeat-{}", self.next_nonce).into_bytes();
+        self.outstanding = Some((nonce.clone(), Instant::now()));
+        Some(nonce)
+    }
+
+    // True if `payload` answers our ping
+    pub fn on_pong(&mut self, payload: &[u8]) -> bool {
+        match &self.outstanding {
+            Some((nonce, _)) if nonce.as_slice() == payload => {
+                self.outstanding = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // When the outstanding ping counts as unanswered, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.outstanding.as_ref().map(|(_, sent_at)| *sent_at + self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testing::TestServer;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn fast_heartbeat() -> ServerConfig {
+        ServerConfig { heartbeat_interval_secs: 1, heartbeat_timeout_secs: 1, ..Default::default() }
+    }
+
+    #[test]
+    fn one_ping_at_a_time_until_answered() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(5));
+        assert!(heartbeat.deadline().is_none());
+        let nonce = heartbeat.start_ping().expect("first ping");
+        assert!(heartbeat.start_ping().is_none());
+        assert!(heartbeat.deadline().is_some());
+
+        assert!(!heartbeat.on_pong(b"something else"));
+        assert!(heartbeat.on_pong(&nonce));
+        assert!(heartbeat.deadline().is_none());
+        assert_ne!(heartbeat.start_ping(), Some(nonce));
+    }
+
+    #[tokio::test]
+    async fn a_ponging_client_stays_connected() {
+        let server = TestServer::start(fast_heartbeat()).await;
+        let mut ann = server.join("ann", "red").await;
+
+        // Reading is what answers: tungstenite pongs every ping it reads
+        let mut pings = 0;
+        let until = Instant::now() + Duration::from_millis(3500);
+        while let Some(frame) = ann.next_frame(until.saturating_duration_since(Instant::now())).await {
+            assert!(!matches!(frame, Message::Close(_)), "closed despite answering pings");
+            if matches!(frame, Message::Ping(_)) {
+                pings += 1;
+            }
+        }
+        assert!(pings >= 2, "expected repeated pings, got {}", pings);
+        assert!(server.state.peers.lock().await.contains_key("ann"));
+    }
+
+    #[tokio::test]
+    async fn a_silent_client_is_evicted() {
+        let server = TestServer::start(fast_heartbeat()).await;
+        let ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+
+        // ann reads nothing, so never answers; bob does and sees her go
+        bob.expect("peer_left").await;
+        assert!(!server.state.peers.lock().await.contains_key("ann"));
+        assert!(server.state.peers.lock().await.contains_key("bob"));
+        drop(ann);
+    }
+}
@@ -0,0 +1,331 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::compression;
+use crate::generated::{DataItem, EventData};
+use crate::logging::sampled;
+use crate::{notification, notification_envelope, send_server_message, Client};
+
+// One relayed chat message, as it was delivered to the other peers
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub message_id: u64,
+    pub message_uuid: String,
+    pub sent_at_ms: u64,
+    pub from_peer_id: String,
+    pub from_display_name: String,
+    pub text: String,
+    // The message's structured content, if it had any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to_message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    // Set for direct messages, which are only replayed to their recipient
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_peer_id: Option<String>,
+    // Only replayed to peers in the same room
+    pub room: String,
+}
+
+// The last `capacity` chat messages, oldest first. In memory only, so it's
+// empty after a restart. Capacity 0 keeps nothing but still hands out ids.
+pub struct ChatHistory {
+    capacity: usize,
+    next_id: AtomicU64,
+    // std Mutex: only held to push/clone, never across .await
+    entries: Mutex<VecDeque<HistoryEntry>>,
+}
+
+impl ChatHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            next_id: AtomicU64::new(1),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    // Server-assigned id for the next chat message (sent as messageId)
+    pub fn next_message_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // The newest messageId handed out so far (0 = none yet)
+    pub fn last_issued_id(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed) - 1
+    }
+
+    pub fn record(&self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // Copy of the buffer, so callers can serialize without holding the lock
+    pub fn snapshot(&self) -> Vec<HistoryEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.iter().cloned().collect()
+    }
+
+    // The buffered message with this id, if it is still there
+    pub fn get(&self, message_id: u64) -> Option<HistoryEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let index = entries.binary_search_by_key(&message_id, |entry| entry.message_id).ok()?;
+        Some(entries[index].clone())
+    }
+
+    // Entries with messageId > `seq`, plus whether some of the messages after
+    // `seq` are no longer in the buffer (the caller missed more than we can replay).
+    // Ids are handed out in order, so the buffer is sorted by messageId.
+    pub fn since(&self, seq: u64) -> (Vec<HistoryEntry>, bool) {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let last_issued = self.last_issued_id();
+        let gap = match entries.front() {
+            Some(oldest) => oldest.message_id > seq.saturating_add(1),
+            None => last_issued > seq,
+        };
+        let missed = entries.iter().filter(|entry| entry.message_id > seq).cloned().collect();
+        (missed, gap)
+    }
+}
+
+// What a reconnecting peer missed (messageId > resume_from_seq): copied
+// under the peers lock (see handle_socket) and sent once it is released, so
+// a peer that doesn't read can't hold the lock.
+pub struct Replay {
+    resume_from_seq: u64,
+    missed: Vec<HistoryEntry>,
+    // Some of the messages after resume_from_seq are no longer in the buffer
+    gap: bool,
+    // The oldest message still buffered, whether or not it is for this peer
+    oldest_available: Option<u64>,
+    last_seq: u64,
+}
+
+impl ChatHistory {
+    // Skips the peer's own messages and other rooms', like the live broadcast
+    pub fn replay_for(&self, peer_id: &str, room: &str, resume_from_seq: u64) -> Replay {
+        let (missed, gap) = self.since(resume_from_seq);
+        let for_this_peer = |entry: &HistoryEntry| {
+            entry.room == room
+                && entry.from_peer_id != peer_id
+                && entry.to_peer_id.as_deref().is_none_or(|to| to == peer_id)
+        };
+        Replay {
+            resume_from_seq,
+            oldest_available: missed.first().map(|entry| entry.message_id),
+            missed: missed.into_iter().filter(for_this_peer).collect(),
+            gap,
+            last_seq: self.last_issued_id(),
+        }
+    }
+}
+
+impl Replay {
+    // Sends the missed messages in order, as regular chat_message
+    // notifications marked replayed=true, then "replay_complete" {lastSeq}.
+    // Live chat messages are not held back meanwhile and may arrive in
+    // between (they all have messageId > lastSeq, and may also overtake the
+    // replay on a higher priority lane), so clients should order by
+    // messageId; ids are server-wide, so a room's ids can skip numbers.
+    // If the buffer no longer reaches back far enough, a "replay_gap"
+    // notification with resync=true comes first: the replay is incomplete,
+    // and the client should drop its local copy and resync fully (e.g.
+    // /api/history/export). Replayed messages may go out deflated, see
+    // compression.rs.
+    pub async fn send(self, client: &Client, peer_id: &str, compression_min_bytes: usize) {
+        if self.gap {
+            let mut gap_data = HashMap::new();
+            gap_data.insert("resumeFromSeq".to_string(), self.resume_from_seq.to_string());
+            gap_data.insert("resync".to_string(), "true".to_string());
+            if let Some(oldest) = self.oldest_available {
+                gap_data.insert("oldestAvailableSeq".to_string(), oldest.to_string());
+            }
+            send_server_message(client, &notification("replay_gap", gap_data), "replay_gap").await;
+        }
+
+        let mut replayed = 0;
+        for entry in self.missed {
+            let mut event = chat_event(entry);
+            event.data.insert("replayed".to_string(), "true".to_string());
+            let message = notification_envelope(event);
+            if !compression::send_snapshot(client, &message, "replay", compression_min_bytes).await {
+                return;
+            }
+            replayed += 1;
+        }
+        let mut complete_data = HashMap::new();
+        complete_data.insert("lastSeq".to_string(), self.last_seq.to_string());
+        send_server_message(client, &notification("replay_complete", complete_data), "replay_complete").await;
+        sampled!(
+            info,
+            "Replayed {} missed messages to {} (from seq {}{})",
+            replayed,
+            peer_id,
+            self.resume_from_seq,
+            if self.gap { ", with gap" } else { "" }
+        );
+    }
+}
+
+// The chat_message notification `entry` was delivered as (also the base of
+// message_pinned, see pins.rs)
+pub fn chat_event(entry: HistoryEntry) -> EventData {
+    let mut data = HashMap::new();
+    data.insert("messageId".to_string(), entry.message_id.to_string());
+    data.insert("messageUuid".to_string(), entry.message_uuid);
+    data.insert("sentAtMs".to_string(), entry.sent_at_ms.to_string());
+    data.insert("fromPeerId".to_string(), entry.from_peer_id);
+    data.insert("fromDisplayName".to_string(), entry.from_display_name);
+    data.insert("text".to_string(), entry.text);
+    if let Some(reply_to) = entry.reply_to_message_id {
+        data.insert("replyToMessageId".to_string(), reply_to);
+    }
+    if let Some(content_type) = entry.content_type {
+        data.insert("contentType".to_string(), content_type);
+    }
+    if let Some(to) = entry.to_peer_id {
+        data.insert("toPeerId".to_string(), to);
+    }
+    EventData {
+        method: "chat_message".to_string(),
+        data,
+        items: entry.items.into_iter().map(|data| DataItem { data }).collect(),
+        payload: Vec::new(),
+        checksum: Vec::new(),
+    }
+}
+
+// RFC 4180 style: header row, fields quoted only when they need it
+pub fn to_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv = String::from(
+        "messageId,sentAtMs,room,fromPeerId,fromDisplayName,toPeerId,text,contentType,items,replyToMessageId,messageUuid\r\n",
+    );
+    for entry in entries {
+        // Structured content goes in one column, as the JSON array of its items
+        let items = if entry.items.is_empty() {
+            String::new()
+        } else {
+            serde_json::to_string(&entry.items).unwrap_or_default()
+        };
+        let fields = [
+            entry.message_id.to_string(),
+            entry.sent_at_ms.to_string(),
+            csv_field(&entry.room),
+            csv_field(&entry.from_peer_id),
+            csv_field(&entry.from_display_name),
+            csv_field(entry.to_peer_id.as_deref().unwrap_or("")),
+            csv_field(&entry.text),
+            csv_field(entry.content_type.as_deref().unwrap_or("")),
+            csv_field(&items),
+            csv_field(entry.reply_to_message_id.as_deref().unwrap_or("")),
+            entry.message_uuid.clone(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(history: &ChatHistory, from: &str, room: &str, to: Option<&str>) -> HistoryEntry {
+        HistoryEntry {
+            message_id: history.next_message_id(),
+            message_uuid: String::new(),
+            sent_at_ms: 0,
+            from_peer_id: from.to_string(),
+            from_display_name: from.to_string(),
+            text: "hi".to_string(),
+            items: Vec::new(),
+            reply_to_message_id: None,
+            content_type: None,
+            to_peer_id: to.map(str::to_string),
+            room: room.to_string(),
+        }
+    }
+
+    fn ids(replay: &Replay) -> Vec<u64> {
+        replay.missed.iter().map(|entry| entry.message_id).collect()
+    }
+
+    #[test]
+    fn replays_only_what_the_peer_would_have_received() {
+        let history = ChatHistory::new(10);
+        // 1, 2 own, 3 other room, 4 someone else's DM, 5 DM to bob
+        for (from, room, to) in [
+            ("ann", "lobby", None),
+            ("bob", "lobby", None),
+            ("ann", "other", None),
+            ("ann", "lobby", Some("cat")),
+            ("ann", "lobby", Some("bob")),
+        ] {
+            history.record(entry(&history, from, room, to));
+        }
+
+        let replay = history.replay_for("bob", "lobby", 0);
+        assert_eq!(ids(&replay), vec![1, 5]);
+        assert!(!replay.gap);
+        assert_eq!(replay.last_seq, 5);
+
+        let replay = history.replay_for("bob", "lobby", 1);
+        assert_eq!(ids(&replay), vec![5]);
+    }
+
+    #[test]
+    fn reports_a_gap_when_the_buffer_no_longer_reaches_back() {
+        let history = ChatHistory::new(2);
+        for _ in 0..4 {
+            history.record(entry(&history, "ann", "lobby", None));
+        }
+        let replay = history.replay_for("bob", "lobby", 1);
+        assert!(replay.gap);
+        assert_eq!(replay.oldest_available, Some(3));
+        assert_eq!(ids(&replay), vec![3, 4]);
+
+        // Nothing missed since the newest message
+        let replay = history.replay_for("bob", "lobby", 4);
+        assert!(!replay.gap);
+        assert!(replay.missed.is_empty());
+    }
+
+    #[test]
+    fn csv_export_carries_room_recipient_and_content() {
+        let history = ChatHistory::new(10);
+        let mut dm = entry(&history, "ann", "team, east", Some("bob"));
+        dm.text = "say \"hi\"".to_string();
+        dm.content_type = Some("text/markdown".to_string());
+        dm.items = vec![HashMap::from([("kind".to_string(), "link".to_string())])];
+
+        let csv = to_csv(&[dm]);
+        let mut lines = csv.split("\r\n");
+        assert_eq!(
+            lines.next(),
+            Some("messageId,sentAtMs,room,fromPeerId,fromDisplayName,toPeerId,text,contentType,items,replyToMessageId,messageUuid")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(r#"1,0,"team, east",ann,ann,bob,"say ""hi""",text/markdown,"[{""kind"":""link""}]",,"#)
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing_subscriber::EnvFilter;
+
+// Log output goes through `tracing`. Verbosity comes from RUST_LOG, e.g.
+// RUST_LOG=rust_socket=debug for the per-frame detail, or
+// RUST_LOG=warn for problems only; unset means info. Each connection's
+// lines carry a `connection` span with its peer_id and display_name.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+// Connection log sampling, for deployments where logging every connect,
+// disconnect and message is too much.
+//
+// Each new connection is either logged fully or not at all, decided once at
+// upgrade time from log_sample_every (1 = every connection, N = 1 in N,
+// 0 = none). Only routine lines go through `sampled!`: warnings and errors,
+// policy actions and server-wide events are always logged (subject to
+// RUST_LOG).
+//
+// The decision lives in a task-local for the connection's task, so it also
+// covers the helpers it calls (e.g. send_server_message). Work outside a
+// connection task (timers, admin API, ...) is always logged.
+tokio::task_local! {
+    static CONNECTION_LOGGED: bool;
+}
+
+// Connections seen so far, for the 1-in-N choice
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+pub fn sample_connection(sample_every: u64) -> bool {
+    let n = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    sample_every > 0 && n.is_multiple_of(sample_every)
+}
+
+// Runs one connection's handler with its sampling decision
+pub async fn scoped<F: Future>(logged: bool, connection: F) -> F::Output {
+    CONNECTION_LOGGED.scope(logged, connection).await
+}
+
+pub fn enabled() -> bool {
+    CONNECTION_LOGGED.try_with(|logged| *logged).unwrap_or(true)
+}
+
+// A tracing event for routine per-connection lines, skipped for unsampled
+// connections: sampled!(info, "...", args) or sampled!(debug, ...)
+macro_rules! sampled {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::logging::enabled() {
+            tracing::$level!($($arg)*);
+        }
+    };
+}
+pub(crate) use sampled;
@@ -1,24 +1,27 @@
 use axum::{
     extract::{
         ws::{
+            close_code, CloseFrame, //Close frame sent when the server ends a connection itself.
+            rejection::WebSocketUpgradeRejection, //Why a request to /ws isn't a valid WebSocket handshake.
             Message as WsMessage, //Represents a WebSocket frame. supports text, binary, ping, pong, close.
             WebSocket, //The actual full-duplex socket. After upgrade, this is what you use. supports send, receive ,split.
             WebSocketUpgrade, //without this, cannot perform WebSocket handshake. 
             //Represents an incoming HTTP request that wants to upgrade to WebSocket.
             //Converts HTTP → WebSocket protocol.
         },
+        ConnectInfo, //Remote address of the connection; needs the ...with_connect_info service in run().
+        Extension,
         Query,
+        RawQuery,
         State,
     },
-    response::IntoResponse,//trait| Anything that implements IntoResponse can be returned from an Axum handler.
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},//trait| Anything that implements IntoResponse can be returned from an Axum handler.
     // ws.on_upgrade(...) returns a type that implements IntoResponse.
     routing::get,//Registers HTTP GET route. WebSocket handshake always starts as HTTP GET request.
     Router,//A router is a collection of routes.Without Router: 👉 No route definitions.
 };
 use futures_util::{
-    SinkExt,//SplitSink implements Sink, but .send() is provided by SinkExt.
-    // Without it:
-    // ❌ .send() method doesn't exist.
      StreamExt// WebSocket implements Stream, but .next() is provided by StreamExt.
      // Without StreamExt:
      // ❌ .next() will not compile.
@@ -27,8 +30,12 @@ use futures_util::{
 use std::net::SocketAddr;//SocketAddr is a tuple of (ip_address, port).
 use std::sync::Arc;//Atomic Reference Counted pointer. Without Arc:
 // ❌ Cannot move sender into multiple async contexts.
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
 use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn, Instrument};
 // IMPORTANT:
 // This is async mutex, not std::sync::Mutex.
 // Why? Because:
@@ -36,6 +43,74 @@ use tokio::sync::Mutex;
 // std::Mutex blocks thread.
 // tokio::Mutex yields control when waiting.
 
+mod api;
+mod api_error;
+mod auth;
+mod away;
+mod bridge;
+mod capabilities;
+mod checksum;
+mod client_version;
+mod compression;
+mod config;
+mod content_type;
+mod control;
+mod decode_hint;
+mod e2e;
+mod encoding;
+mod features;
+mod groups;
+mod heartbeat;
+mod history;
+mod logging;
+mod metrics;
+mod offline;
+mod peer_id;
+mod peer_list;
+mod pins;
+mod presence;
+mod priority;
+mod quality;
+mod raw_relay;
+mod receipts;
+mod reliable;
+mod room_rates;
+mod rooms;
+mod runtime_metrics;
+mod scheduler;
+mod selftest;
+mod session;
+mod shadow;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod transform;
+mod tunnel;
+mod validation;
+use bridge::UpstreamBridge;
+use capabilities::Capabilities;
+use client_version::VersionStatus;
+use config::{ConfigError, LiveConfig, RuntimeFlavor, ServerConfig};
+use encoding::{EncodedOnce, Encoding};
+use features::FeatureSet;
+use groups::GroupIndex;
+use history::{ChatHistory, HistoryEntry};
+use logging::sampled;
+use metrics::Metrics;
+use offline::OfflineQueue;
+use peer_id::{DuplicatePeerIdPolicy, PeerIdGenerator, PeerIdRules};
+use presence::PresenceBatch;
+use priority::{Lanes, Priority, QueueStats};
+use receipts::ReadReceipts;
+use reliable::PendingAcks;
+use room_rates::RoomRates;
+use rooms::{Admission, RoomRegistry};
+use scheduler::Scheduler;
+use session::SessionSummary;
+use shadow::ShadowPeer;
+use transform::TransformPipeline;
+use tunnel::Tunnels;
+
 // Include generated protobuf code
 pub mod generated {
     include!("generated/messages.rs");
@@ -43,8 +118,122 @@ pub mod generated {
 use generated::*;
 use prost::Message; // Trait for encode/decode methods
 
+// How often each connection checks its own outbound queue depth
+const SLOW_CLIENT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Window for max_unsolicited_pongs_per_min
+const PONG_FLOOD_WINDOW: Duration = Duration::from_secs(60);
+
+// Upper bound on closing every peer at shutdown, and how often to check
+// whether they are gone
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// The sending half of one client's socket.
+// Frames go onto a bounded priority lane for the socket's writer task (see
+// priority.rs), two ways:
+// - `send`/`send_with_priority` wait for room in the lane and then until the
+//   frame has been written. For a connection's own traffic (replies, pings,
+//   closes), where waiting on that one socket is fine.
+// - `try_send_with_priority` only queues and returns at once. For fan-outs,
+//   which run under the peers lock and must not wait on any one slow socket.
+//   When the lane is full the frame is dropped for this peer and counted in
+//   `dropped` (reported in connection_quality); the connection stays up
+//   unless the slow-client policy (slow_client_max_queue_depth) removes it.
+// Frames queued or being written are counted: that count is the peer's
+// outbound queue depth.
+struct ClientSender {
+    lanes: Lanes,
+    // Format notifications are encoded in for this client
+    encoding: Encoding,
+    // Client inflates deflated snapshot frames (see compression.rs)
+    compression: bool,
+    stats: Arc<QueueStats>,
+    // Acked deliveries still waiting on a "delivery_ack" (see reliable.rs)
+    pending_acks: PendingAcks,
+    // Fan-out frames kept while the peer is away (see offline.rs)
+    offline: OfflineQueue,
+}
+
+impl ClientSender {
+    fn new(
+        sink: futures_util::stream::SplitSink<WebSocket, WsMessage>,
+        encoding: Encoding,
+        compression: bool,
+        lane_capacity: usize,
+    ) -> Self {
+        let stats = Arc::new(QueueStats::default());
+        Self {
+            lanes: priority::spawn_writer(sink, lane_capacity, stats.clone()),
+            encoding,
+            compression,
+            stats,
+            pending_acks: PendingAcks::default(),
+            offline: OfflineQueue::default(),
+        }
+    }
+
+    async fn send(&self, msg: WsMessage) -> Result<(), axum::Error> {
+        self.send_with_priority(msg, Priority::Normal).await
+    }
+
+    async fn send_with_priority(&self, msg: WsMessage, priority: Priority) -> Result<(), axum::Error> {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let (done, written) = tokio::sync::oneshot::channel();
+        if self.lanes.get(priority).send((msg, Some(done))).await.is_err() {
+            // The writer only stops once the lanes are dropped
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            self.stats.finished();
+            return Err(axum::Error::new("outbound writer stopped"));
+        }
+        written
+            .await
+            .unwrap_or_else(|_| Err(axum::Error::new("outbound writer stopped")))
+    }
+
+    // False when the frame was dropped because the lane is full
+    fn try_send_with_priority(&self, msg: WsMessage, priority: Priority) -> bool {
+        if let Some(kept) = self.offline.push(&msg) {
+            return kept;
+        }
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        if self.lanes.get(priority).try_send((msg, None)).is_ok() {
+            return true;
+        }
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        self.stats.finished();
+        false
+    }
+
+    // Frames currently waiting to be written to this client
+    fn queue_depth(&self) -> usize {
+        self.stats.queued.load(Ordering::Relaxed)
+    }
+
+    fn dropped_count(&self) -> u64 {
+        self.stats.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Waits (up to `timeout`) until everything already queued for this client has
+// been written, so a server-initiated close doesn't cut off in-flight messages
+// such as the "you're being disconnected" notice. Returns false on timeout.
+async fn drain_outbound(client: &ClientSender, timeout: Duration) -> bool {
+    let wait = async {
+        loop {
+            // Register before checking so a wakeup in between isn't missed
+            let drained = client.stats.drained.notified();
+            if client.queue_depth() == 0 {
+                return;
+            }
+            drained.await;
+        }
+    };
+    tokio::time::timeout(timeout, wait).await.is_ok()
+}
+
 // Type alias for client sender| A sender is a half of a split WebSocket.
-type Client = Arc<Mutex<futures_util::stream::SplitSink<WebSocket, WsMessage>>>;
+type Client = Arc<ClientSender>;
 
 // Peer information structure
 #[allow(dead_code)]
@@ -52,55 +241,724 @@ struct Peer {
     sender: Client,
     display_name: String,
     peer_id: String, // Kept for future use (e.g., peer lookup, admin features)
+    // Peer ids this peer wants presence updates for ("buddy list"),
+    // delivered regardless of who else would normally be told
+    presence_subscriptions: HashSet<String>,
+    // Group labels (see groups.rs), mirrored in AppState::groups
+    groups: HashSet<String>,
+    // Reported app version, and whether it's below the recommended one
+    // (see client_version.rs)
+    app_version: Option<semver::Version>,
+    outdated_client: bool,
+    // Content types this peer wants chat messages in; empty = all
+    // (see content_type.rs)
+    accepted_content_types: Vec<String>,
+    // Identity from the verified client certificate, with mutual TLS
+    // (see tls.rs); when set it is also the peer_id
+    client_cert: Option<String>,
+    // Optional features this peer declared via ?caps=
+    capabilities: Capabilities,
+    // Registration order: higher = joined later (see laterJoinersOnly).
+    // Unique per connection, so it also tells whether an entry is still ours.
+    join_seq: u64,
+    connection_state: ConnectionState,
+    // Set by ?room= at connect time. Chat, presence and the other fan-outs
+    // only reach peers in the same room.
+    room: String,
+    // What the server lets this connection use (see features.rs)
+    features: FeatureSet,
+    // When the peer connected (ms since epoch); kept across a resume within
+    // the reconnect grace period
+    connected_at_ms: u64,
+}
+
+// Room for clients that connect without ?room=
+const DEFAULT_ROOM: &str = "lobby";
+
+// Disconnected peers are simply not in the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    Connected,
+    // Socket closed, still shown as present until reconnect_grace_secs runs out
+    Reconnecting,
 }
 
 // Global state to store all connected peers
 // Key: peer_id, Value: Peer struct
 type Peers = Arc<Mutex<HashMap<String, Peer>>>;
 
-// Helper to send any Envelope with consistent logging
-async fn send_server_message(client: &Client, msg: &Envelope, context: &str) {
-    println!(
-        "[SERVER DEBUG] [{}] Preparing to send Envelope: {:?}",
+// Everything handlers need, shared through axum's State extractor
+#[derive(Clone)]
+struct AppState {
+    peers: Peers,
+    // Swapped as a whole on reload, read with state.config.current()
+    config: Arc<LiveConfig>,
+    metrics: Arc<Metrics>,
+    // Rewrites applied to chat messages before broadcast, in order
+    transforms: Arc<TransformPipeline>,
+    // Where this server listens (used by the self-test to connect to itself)
+    listen_addr: SocketAddr,
+    // Validation for client-supplied peer ids
+    peer_id_rules: Arc<PeerIdRules>,
+    // Ids for clients that connect without ?peerId (see peer_id.rs)
+    peer_ids: Arc<PeerIdGenerator>,
+    // Raw frame tunnels between paired connections (see tunnel.rs)
+    tunnels: Tunnels,
+    // Recent chat messages, for export
+    history: Arc<ChatHistory>,
+    // System broadcasts queued for later (see /api/schedule)
+    scheduler: Arc<Scheduler>,
+    // While set, new upgrades get 503; existing connections are left alone
+    maintenance: Arc<AtomicBool>,
+    // Next Peer::join_seq, only taken while holding the peers lock
+    next_join_seq: Arc<AtomicU64>,
+    // messageId -> sender, for routing read receipts
+    receipts: Arc<ReadReceipts>,
+    // In-process copy of every broadcast, for diagnostics (see shadow.rs)
+    shadow: Arc<ShadowPeer>,
+    // group -> member peer ids (see groups.rs)
+    groups: Arc<GroupIndex>,
+    // Joins/leaves waiting to go out as one presence_update
+    presence_batch: Arc<PresenceBatch>,
+    // Optional link to an upstream server (see bridge.rs)
+    upstream: Arc<UpstreamBridge>,
+    // Presence log and departures, for "while you were away" summaries
+    away: Arc<away::AwayTracker>,
+    // Chat messages per room over sliding windows (see room_rates.rs)
+    room_rates: Arc<RoomRates>,
+    // Per-room state beyond who is in it, e.g. passwords (see rooms.rs)
+    rooms: Arc<RoomRegistry>,
+}
+
+// (server_name, instance_id) stamped on every notification.
+// Set once in main; lets clients and logs tell load-balanced instances apart.
+static SERVER_IDENTITY: OnceLock<(String, String)> = OnceLock::new();
+
+// Wraps EventData in a server -> client notification Envelope
+fn notification_envelope(event_data: EventData) -> Envelope {
+    let (server_name, instance_id) = SERVER_IDENTITY.get().cloned().unwrap_or_default();
+    Envelope {
+        event: "notification".to_string(),
+        event_data: Some(event_data),
+        server_name,
+        instance_id,
+    }
+}
+
+// Builds a notification for `method` with the given key/value data
+fn notification(method: &str, data: HashMap<String, String>) -> Envelope {
+    notification_envelope(EventData {
+        method: method.to_string(),
+        data,
+        items: Vec::new(),
+        payload: Vec::new(),
+        checksum: Vec::new(),
+    })
+}
+
+// Builds a server "system" notification carrying a human-readable message
+fn system_notification(message: &str) -> Envelope {
+    let mut data = HashMap::new();
+    data.insert("message".to_string(), message.to_string());
+    notification("system", data)
+}
+
+// Builds an "error" notification sent back to the client that caused it
+fn error_notification(code: &str, message: &str) -> Envelope {
+    let mut data = HashMap::new();
+    data.insert("code".to_string(), code.to_string());
+    data.insert("message".to_string(), message.to_string());
+    notification("error", data)
+}
+
+// Tells the client it used a feature this connection doesn't have (see features.rs)
+async fn reject_disabled_feature(client: &Client, peer_id: &str, feature: &str) {
+    sampled!(debug, "Rejected {} from {}: feature disabled", feature, peer_id);
+    let reply = error_notification(
+        "feature_disabled",
+        &format!("'{}' is not enabled for this connection", feature),
+    );
+    send_server_message(client, &reply, "feature_disabled").await;
+}
+
+// Tells the client its frame was dropped for exceeding the size limit
+async fn reject_oversized_frame(client: &Client, kind: &str, len: usize, limit: usize) {
+    warn!(
+        "Rejected {} frame of {} bytes (limit {} bytes)",
+        kind, len, limit
+    );
+    let reply = error_notification(
+        "message_too_large",
+        &format!("{} frame of {} bytes exceeds the {} byte limit", kind, len, limit),
+    );
+    send_server_message(client, &reply, "message_too_large").await;
+}
+
+// A text frame whose payload isn't UTF-8. RFC 6455 wants the connection
+// closed with 1007 (invalid payload data) rather than just dropped.
+fn is_invalid_utf8(error: axum::Error) -> bool {
+    use tokio_tungstenite::tungstenite::Error as WsError;
+    error.into_inner().downcast_ref::<WsError>().is_some_and(|error| matches!(error, WsError::Utf8))
+}
+
+// Closes the connection once it has sent max_oversized_frames oversized
+// frames. True when it was closed.
+async fn too_many_oversized_frames(state: &AppState, client: &Client, peer_id: &str, count: u32) -> bool {
+    let max = state.config.current().max_oversized_frames;
+    if max == 0 || count < max {
+        return false;
+    }
+    let disconnects = Metrics::incr(&state.metrics.oversized_frame_disconnects);
+    warn!(
+        "Disconnecting {}: {} oversized frames (oversized_frame_disconnects={})",
+        peer_id, count, disconnects
+    );
+    let _ = client
+        .send(WsMessage::Close(Some(CloseFrame {
+            code: close_code::SIZE,
+            reason: "too many oversized frames".into(),
+        })))
+        .await;
+    true
+}
+
+// Size of a request's payload for method_size_limits: every data key and
+// value, including those in list items
+fn payload_size(event_data: &EventData) -> usize {
+    let map_size = |map: &HashMap<String, String>| map.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+    map_size(&event_data.data) + event_data.items.iter().map(|item| map_size(&item.data)).sum::<usize>()
+}
+
+// Wall-clock time in milliseconds since the Unix epoch
+fn now_ms() -> u64 {
+    now_us() / 1000
+}
+
+// Wall-clock time in microseconds since the Unix epoch
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// Resolves at the deadline, or never when there is no deadline
+async fn sleep_until_deadline(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Helper to send any Envelope with consistent logging.
+// Returns whether the frame was written.
+async fn send_server_message(client: &Client, msg: &Envelope, context: &str) -> bool {
+    send_server_message_with_priority(client, msg, context, Priority::Normal).await
+}
+
+async fn send_server_message_with_priority(client: &Client, msg: &Envelope, context: &str, priority: Priority) -> bool {
+    sampled!(
+        debug,
+        "[{}] Preparing to send Envelope: {:?}",
         context, msg
     );
-    let bytes = msg.encode_to_vec();
-    println!(
-        "[SERVER DEBUG] [{}] Encoded Envelope ({} bytes)",
+    let frame = encoding::encode(msg, client.encoding);
+    send_encoded(client, frame, context, priority).await
+}
+
+// Like send_server_message, but only queues the frame (see
+// ClientSender::try_send_with_priority). For messages to other peers, sent
+// while holding the peers lock. Returns whether the frame was queued.
+fn queue_server_message(client: &Client, msg: &Envelope, context: &str) -> bool {
+    queue_encoded(client, encoding::encode(msg, client.encoding), context, Priority::Normal)
+}
+
+// Queues an Envelope already encoded for this client (see EncodedOnce)
+fn queue_encoded(client: &Client, frame: WsMessage, context: &str, priority: Priority) -> bool {
+    let len = frame_len(&frame);
+    if client.try_send_with_priority(frame, priority) {
+        sampled!(debug, "[{}] Queued Envelope ({} bytes, {:?})", context, len, client.encoding);
+        true
+    } else {
+        sampled!(debug, "[{}] Outbound lane full, dropped {} bytes", context, len);
+        false
+    }
+}
+
+// Sends an Envelope already encoded for this client (see EncodedOnce)
+async fn send_encoded(client: &Client, frame: WsMessage, context: &str, priority: Priority) -> bool {
+    sampled!(
+        debug,
+        "[{}] Encoded Envelope ({} bytes, {:?})",
         context,
-        bytes.len()
+        frame_len(&frame),
+        client.encoding
     );
-    let mut sender_lock = client.lock().await;
-    match sender_lock.send(WsMessage::Binary(bytes.into())).await {
-        Ok(_) => println!("[SERVER DEBUG] [{}] ✅ Send OK", context),
-        Err(e) => println!("[SERVER DEBUG] [{}] ❌ Send failed: {}", context, e),
+    match client.send_with_priority(frame, priority).await {
+        Ok(_) => {
+            sampled!(debug, "[{}] Send OK", context);
+            true
+        }
+        Err(e) => {
+            debug!("[{}] Send failed: {}", context, e);
+            false
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    // Create shared state for all peers
-    let peers: Peers = Arc::new(Mutex::new(HashMap::new()));
+fn frame_len(frame: &WsMessage) -> usize {
+    match frame {
+        WsMessage::Binary(bytes) => bytes.len(),
+        WsMessage::Text(text) => text.len(),
+        _ => 0,
+    }
+}
 
-    let app = Router::new()
-        .route("/ws", get(ws_handler))
-        .with_state(peers);
+// Sends one system message to every connected peer, encoding it only once
+// per format. With `require_ack`, peers with the reliable capability get a
+// copy that is retransmitted until acknowledged (see reliable.rs).
+// Returns how many peers it was written to.
+async fn broadcast_system(state: &AppState, message: &str, require_ack: bool) -> usize {
+    let notice = system_notification(message);
+    state.shadow.observe(&notice);
+    let mut encoded = EncodedOnce::new(&notice);
+
+    // One ackId for the whole broadcast: pending acks are tracked per connection
+    let ack_id = reliable::next_ack_id();
+    let mut reliable_notice = notice.clone();
+    if let Some(event_data) = reliable_notice.event_data.as_mut() {
+        event_data.data.insert("requiresAck".to_string(), "true".to_string());
+        event_data.data.insert("ackId".to_string(), ack_id.to_string());
+    }
+    let mut reliable_encoded = EncodedOnce::new(&reliable_notice);
+    let config = state.config.current();
+    let ack_timeout = Duration::from_millis(config.ack_timeout_ms);
+
+    let peers_guard = state.peers.lock().await;
+    let mut delivered = 0;
+    for peer in peers_guard.values() {
+        let queued = if require_ack && peer.capabilities.reliable {
+            let frame = reliable_encoded.get(peer.sender.encoding);
+            reliable::deliver(&peer.sender, frame, ack_id, ack_timeout, config.ack_max_retries)
+        } else {
+            peer.sender.try_send_with_priority(encoded.get(peer.sender.encoding), Priority::Normal)
+        };
+        if queued {
+            delivered += 1;
+        }
+    }
+    info!(
+        "System message delivered to {}/{} peers: {}",
+        delivered,
+        peers_guard.len(),
+        message
+    );
+    delivered
+}
+
+// mark_read {messageId}: tells the message's sender that `reader_peer_id` read it
+// ("read_receipt" {messageId, readerPeerId}). Dropped silently when the id is
+// unknown or expired, the reader is the sender, or the sender has disconnected
+// or is in another room.
+async fn send_read_receipt(state: &AppState, reader_peer_id: &str, reader_room: &str, data: &HashMap<String, String>) {
+    let Some(message_id) = data.get("messageId").and_then(|id| id.parse::<u64>().ok()) else {
+        sampled!(debug, "mark_read without a valid messageId from {}", reader_peer_id);
+        return;
+    };
+    let Some(sender_peer_id) = state.receipts.sender_of(message_id) else {
+        return;
+    };
+    if sender_peer_id == reader_peer_id {
+        return;
+    }
+
+    let mut receipt_data = HashMap::new();
+    receipt_data.insert("messageId".to_string(), message_id.to_string());
+    receipt_data.insert("readerPeerId".to_string(), reader_peer_id.to_string());
+    let receipt = notification("read_receipt", receipt_data);
+
+    let peers_guard = state.peers.lock().await;
+    if let Some(sender) = peers_guard.get(&sender_peer_id).filter(|sender| sender.room == reader_room) {
+        queue_server_message(&sender.sender, &receipt, "read_receipt");
+    }
+}
+
+// typing: tells the other peers in the room that this one is typing
+// ("typing" {peerId, displayName}). Sent on every keystroke, so it is
+// encoded once, never stored or echoed, and dropped for peers whose
+// outbound lane is full.
+async fn broadcast_typing(state: &AppState, peer_id: &str, display_name: &str, room: &str) {
+    let mut typing_data = HashMap::new();
+    typing_data.insert("peerId".to_string(), peer_id.to_string());
+    typing_data.insert("displayName".to_string(), display_name.to_string());
+    let typing = notification("typing", typing_data);
+    let mut encoded = EncodedOnce::new(&typing);
+
+    let peers_guard = state.peers.lock().await;
+    for (id, peer) in peers_guard.iter() {
+        if id != peer_id && peer.room == room && peer.connection_state == ConnectionState::Connected {
+            peer.sender.try_send_with_priority(encoded.get(peer.sender.encoding), Priority::Normal);
+        }
+    }
+}
+
+// Config is loaded before the async runtime exists, because it decides
+// which runtime to build
+fn main() {
+    logging::init();
+    let loaded = ServerConfig::load().and_then(|config| {
+        let peer_id_rules = PeerIdRules::from_config(&config)?;
+        Ok((config, peer_id_rules))
+    });
+    let (config, peer_id_rules) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Failed to load config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("Config: {:?}", config);
+
+    let runtime = match build_runtime(&config) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(run(config, peer_id_rules));
+}
+
+fn build_runtime(config: &ServerConfig) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = match config.runtime_flavor {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if config.worker_threads > 0 {
+                builder.worker_threads(config.worker_threads);
+            }
+            builder
+        }
+    };
+    builder.enable_all().build()
+}
+
+async fn run(config: ServerConfig, peer_id_rules: PeerIdRules) {
+    let instance_id = config.instance_id.clone().unwrap_or_else(|| {
+        format!("inst_{}", &uuid::Uuid::new_v4().simple().to_string()[..8])
+    });
+    info!("Server '{}', instance '{}'", config.server_name, instance_id);
+    let _ = SERVER_IDENTITY.set((config.server_name.clone(), instance_id));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 7878));
-    println!("WebSocket server running on ws://{addr}/ws");
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let tls_acceptor = match tls::load_acceptor(&config) {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            error!("Failed to set up TLS: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let tcp_nodelay = config.tcp_nodelay;
+    let tcp_keepalive = config.tcp_keepalive();
+    let upstream_url = config.upstream_url.clone();
+    let (upstream, upstream_outbound) = UpstreamBridge::new(&config);
+    let state = AppState::new(config, peer_id_rules, addr, upstream);
+
+    if let (Some(url), Some(outbound)) = (upstream_url, upstream_outbound) {
+        tokio::spawn(bridge::run(state.clone(), url, outbound));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone()));
+
+    if let Some(interval) = state.config.current().empty_room_sweep_interval() {
+        tokio::spawn(rooms::sweep_empty_rooms(state.clone(), interval));
+    }
+
+    let shutdown = {
+        let state = state.clone();
+        async move {
+            shutdown_signal().await;
+            shut_down(&state).await;
+        }
+    };
+    let app = app(state);
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            let hint = match e.kind() {
+                std::io::ErrorKind::AddrInUse => " — is another instance running?",
+                std::io::ErrorKind::PermissionDenied => " — is the port privileged?",
+                _ => "",
+            };
+            error!("Failed to bind {}: {}{}", addr, e, hint);
+            std::process::exit(1);
+        }
+    };
+    let scheme = if tls_acceptor.is_some() { "wss" } else { "ws" };
+    info!("WebSocket server running on {scheme}://{addr}/ws");
+    // Accepted sockets inherit keepalive from the listener (see config.rs for
+    // platform differences); nodelay is set by axum on each accepted socket
+    if let Some(idle) = tcp_keepalive {
+        let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+        if let Err(e) = socket2::SockRef::from(&listener).set_tcp_keepalive(&keepalive) {
+            warn!("Could not enable TCP keepalive: {}", e);
+        }
+    }
+    if let Some(acceptor) = tls_acceptor {
+        tls::serve(listener, app, acceptor, tcp_nodelay, shutdown).await;
+    } else if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .tcp_nodelay(tcp_nodelay)
+        .with_graceful_shutdown(shutdown)
+        .await
+    {
+        error!("Server stopped with an error: {}", e);
+        std::process::exit(1);
+    }
+    info!("Shut down");
+}
+
+impl AppState {
+    // Fresh state for a server listening on `listen_addr`. Background tasks
+    // (bridge, SIGHUP reload) are started by run.
+    fn new(config: ServerConfig, peer_id_rules: PeerIdRules, listen_addr: SocketAddr, upstream: UpstreamBridge) -> Self {
+        let history = Arc::new(ChatHistory::new(config.chat_history_size));
+        let receipts = Arc::new(ReadReceipts::new(
+            config.read_receipt_max_tracked,
+            Duration::from_secs(config.read_receipt_ttl_secs),
+        ));
+        Self {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(LiveConfig::new(config)),
+            metrics: Arc::new(Metrics::default()),
+            transforms: Arc::new(TransformPipeline::default()),
+            listen_addr,
+            peer_id_rules: Arc::new(peer_id_rules),
+            peer_ids: Arc::new(PeerIdGenerator::default()),
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            history,
+            scheduler: Arc::new(Scheduler::default()),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            next_join_seq: Arc::new(AtomicU64::new(1)),
+            receipts,
+            shadow: Arc::new(ShadowPeer::default()),
+            groups: Arc::new(GroupIndex::default()),
+            presence_batch: Arc::new(PresenceBatch::default()),
+            upstream: Arc::new(upstream),
+            away: Arc::new(away::AwayTracker::default()),
+            room_rates: Arc::new(RoomRates::default()),
+            rooms: Arc::new(RoomRegistry::default()),
+        }
+    }
+}
+
+// /ws plus the HTTP API
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/ws", get(ws_handler))
+        .merge(api::routes())
+        .with_state(state)
+}
+
+// Resolves on Ctrl-C / SIGINT (or SIGTERM on unix)
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Cannot listen for Ctrl-C, no graceful shutdown on SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminations) => {
+                terminations.recv().await;
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM, no graceful shutdown on it: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+// Run once the server should stop: new upgrades are refused and every peer
+// is closed. WebSocket connections outlive their HTTP request, so the
+// graceful shutdown in axum::serve doesn't wait for them; this does.
+async fn shut_down(state: &AppState) {
+    info!("Shutting down: refusing new connections, closing peers");
+    state.maintenance.store(true, Ordering::Relaxed);
+    close_all_peers(state).await;
+}
+
+// Tells every connected peer the server is going away. Whatever was already
+// queued for it (e.g. an in-flight broadcast) is written first, within the
+// drain timeout, then the notice and a Close. Waits for the sockets to finish
+// closing, so none is left half-open; SHUTDOWN_TIMEOUT bounds the whole thing.
+async fn close_all_peers(state: &AppState) {
+    let clients: Vec<(String, Client)> = state
+        .peers
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, peer)| peer.connection_state == ConnectionState::Connected)
+        .map(|(id, peer)| (id.clone(), peer.sender.clone()))
+        .collect();
+    info!("Closing {} peers", clients.len());
+    let drain_timeout = state.config.current().drain_timeout();
+    let notice = system_notification("Server is shutting down, please reconnect later");
+
+    let close_all = async {
+        let closes = clients
+            .iter()
+            .map(|(peer_id, client)| close_for_shutdown(peer_id, client, &notice, drain_timeout));
+        futures_util::future::join_all(closes).await;
+        // Each handle_socket removes its peer once the close handshake is done
+        loop {
+            let still_open = state
+                .peers
+                .lock()
+                .await
+                .values()
+                .filter(|peer| peer.connection_state == ConnectionState::Connected)
+                .count();
+            if still_open == 0 {
+                break;
+            }
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, close_all).await.is_err() {
+        warn!("Not every peer closed within {:?}, exiting anyway", SHUTDOWN_TIMEOUT);
+    }
+}
+
+async fn close_for_shutdown(peer_id: &str, client: &Client, notice: &Envelope, drain_timeout: Option<Duration>) {
+    if let Some(timeout) = drain_timeout {
+        if !drain_outbound(client, timeout).await {
+            warn!("Outbound queue for {} not drained within {:?}", peer_id, timeout);
+        }
+    }
+    send_server_message(client, notice, "shutdown").await;
+    let _ = client
+        .send(WsMessage::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "server shutting down".into(),
+        })))
+        .await;
+}
+
+// Re-reads the config (file + env, like at startup) and swaps it in if it is
+// valid; an invalid config is rejected and the old one stays. Returns the
+// changed settings that only apply after a restart.
+// Env vars can't change under a running process, so in practice this picks
+// up edits to the config file.
+fn reload_config(state: &AppState) -> Result<Vec<&'static str>, ConfigError> {
+    let new = ServerConfig::load()?;
+    // Same validation as startup
+    PeerIdRules::from_config(&new)?;
+    let restart_required = state.config.startup().restart_required_changes(&new);
+    state.config.replace(new);
+    info!("Config reloaded: {:?}", state.config.current());
+    if !restart_required.is_empty() {
+        warn!("These changes need a restart to apply: {:?}", restart_required);
+    }
+    Ok(restart_required)
+}
+
+// `kill -HUP <pid>` reloads the config
+#[cfg(unix)]
+async fn reload_on_sighup(state: AppState) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, config reload only via the API: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+        if let Err(e) = reload_config(&state) {
+            warn!("Config reload rejected, keeping the old config: {}", e);
+        }
+    }
 }
 
 // WebSocket route handler
-// Extracts query params and shared state, then upgrades to WebSocket
+// Extracts query params and shared state, then upgrades to WebSocket.
+//
+// Extractor order: axum runs them left to right, and only the last one may
+// consume the request body. Everything here only reads request parts, so the
+// order is free, but keep this layout when adding more:
+//   1. State and ConnectInfo (can't fail, given the service setup in run())
+//   2. the request-derived ones (query, headers, extensions)
+//   3. WebSocketUpgrade last, like a body extractor would be. Taken as a
+//      Result so a plain HTTP request gets upgrade_required's explanation.
+// Don't add a body extractor (String, Json, ...): it would have to come last,
+// and a handshake has no body anyway. ConnectInfo only exists when the app is
+// served with into_make_service_with_connect_info (plain) or tls::serve (TLS);
+// without it every upgrade fails with a 500.
 async fn ws_handler(
+    State(state): State<AppState>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    RawQuery(raw_query): RawQuery,
     Query(params): Query<HashMap<String, String>>,
-    ws: WebSocketUpgrade,
-    State(peers): State<Peers>,
-) -> impl IntoResponse {
-    println!("WebSocket upgrade requested");
+    headers: HeaderMap,
+    client_cert: Option<Extension<tls::ClientCertIdentity>>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(rejection) => return upgrade_required(&rejection, &headers, &state.config.current()),
+    };
+    info!("WebSocket upgrade requested from {}", remote_addr);
+
+    // Maintenance mode: stop intake so the server drains before a deploy
+    if state.maintenance.load(Ordering::Relaxed) {
+        info!("Rejected upgrade: maintenance mode");
+        return (StatusCode::SERVICE_UNAVAILABLE, "server is in maintenance mode, try again later").into_response();
+    }
+
+    // Sanity-check the raw query string before looking at any parameter in it
+    if let Some(rejection) = check_query_limits(raw_query.as_deref(), &state.config.current()) {
+        return rejection;
+    }
+
+    // Reject clients speaking a protocol version we don't support (before upgrading)
+    if let Some(rejection) = check_protocol_version(&headers, &state.config.current()) {
+        return rejection;
+    }
+
+    // With client_token_secret set, no token no connection, tunnels included
+    let token_identity = match check_client_token(&params, &headers, &state.config.current()) {
+        Ok(identity) => identity,
+        Err(message) => return (StatusCode::UNAUTHORIZED, message).into_response(),
+    };
+
+    // ?tunnel_id=... switches the connection to raw tunnel mode
+    if let Some(tunnel_id) = params.get("tunnel_id").cloned() {
+        if let Err(reason) = state.peer_id_rules.validate(&tunnel_id) {
+            warn!("Rejected upgrade: invalid tunnel_id {:?}: {}", tunnel_id, reason);
+            return (StatusCode::BAD_REQUEST, reason.replace("peerId", "tunnel_id")).into_response();
+        }
+        if tunnel::is_full(&state.tunnels, &tunnel_id).await {
+            warn!("Rejected upgrade: tunnel '{}' is full", tunnel_id);
+            return (StatusCode::CONFLICT, "tunnel already has two peers").into_response();
+        }
+        let tunnels = state.tunnels.clone();
+        return ws
+            .on_upgrade(move |socket| tunnel::handle_tunnel(socket, tunnels, tunnel_id))
+            .into_response();
+    }
 
     // Read displayName and peerId from query parameters
     let display_name = params
@@ -108,195 +966,1640 @@ async fn ws_handler(
         .cloned()
         .unwrap_or_else(|| "Anonymous".to_string());
 
-    let peer_id = params
-        .get("peerId")
-        .cloned()
-        .unwrap_or_else(|| {
-            format!(
-                "peer_{}",
-                uuid::Uuid::new_v4().to_string().split('-').next().unwrap_or("unknown")
-            )
-        });
-
-    println!(
-        "[SERVER] Using client-provided identity: display_name='{}', peer_id='{}'",
-        display_name, peer_id
+    // With mutual TLS the certificate decides who this is (see tls.rs)
+    let client_cert = client_cert.map(|Extension(identity)| identity.0);
+    if let Some(identity) = &client_cert {
+        if let Err(reason) = state.peer_id_rules.validate(identity) {
+            warn!("Rejected upgrade: certificate identity {:?} isn't a valid peerId: {}", identity, reason);
+            return (StatusCode::FORBIDDEN, "client certificate identity is not a valid peerId").into_response();
+        }
+        if params.get("peerId").is_some_and(|requested| requested != identity) {
+            info!("Ignoring ?peerId={:?}: client certificate says {:?}", params["peerId"], identity);
+        }
+    }
+
+    // A signed token decides too (see auth.rs); it has to agree with a certificate
+    if let Some(identity) = &token_identity {
+        if client_cert.as_ref().is_some_and(|cert| cert != identity) {
+            warn!("Rejected upgrade: token is for {:?}, client certificate says {:?}", identity, client_cert);
+            return (StatusCode::UNAUTHORIZED, "token does not match the client certificate").into_response();
+        }
+        if let Err(reason) = state.peer_id_rules.validate(identity) {
+            warn!("Rejected upgrade: token identity {:?} isn't a valid peerId: {}", identity, reason);
+            return (StatusCode::FORBIDDEN, "token identity is not a valid peerId").into_response();
+        }
+        if params.get("peerId").is_some_and(|requested| requested != identity) {
+            info!("Ignoring ?peerId={:?}: token says {:?}", params["peerId"], identity);
+        }
+    }
+    let verified_identity = client_cert.clone().or(token_identity);
+
+    // Client-supplied ids must pass the configured rules (generated ids always do)
+    if let Some(requested) = params.get("peerId").filter(|_| verified_identity.is_none()) {
+        if let Err(reason) = state.peer_id_rules.validate(requested) {
+            warn!("Rejected upgrade: invalid peerId {:?}: {}", requested, reason);
+            return (StatusCode::BAD_REQUEST, reason).into_response();
+        }
+    }
+
+    let provided = verified_identity.or_else(|| params.get("peerId").cloned());
+    let peer_id = match provided {
+        Some(peer_id) => peer_id,
+        None => {
+            // Checked against the peers map so a generated id never takes over
+            // a connected peer (e.g. one that picked "peer_3" itself)
+            let peers = state.peers.lock().await;
+            let generated = state.peer_ids.generate(&state.config.current(), |id| peers.contains_key(id));
+            drop(peers);
+            match generated {
+                Some(peer_id) => peer_id,
+                None => {
+                    warn!("Rejected upgrade: no peerId and peer_id_strategy is client_provided_only");
+                    return (StatusCode::BAD_REQUEST, "peerId is required").into_response();
+                }
+            }
+        }
+    };
+
+    // ?room=... picks the isolated room this peer joins; same rules as peer ids
+    let room = params.get("room").cloned().unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    if let Err(reason) = state.peer_id_rules.validate(&room) {
+        warn!("Rejected upgrade: invalid room {:?}: {}", room, reason);
+        return (StatusCode::BAD_REQUEST, reason.replace("peerId", "room")).into_response();
+    }
+
+    // ?room_password=... for protected rooms; confirmed again on registration
+    let occupied = state.peers.lock().await.values().any(|peer| peer.room == room && peer.peer_id != peer_id);
+    let admission = match state.rooms.check(&room, params.get("room_password").map(String::as_str), occupied).await {
+        Ok(admission) => admission,
+        Err(message) => {
+            warn!("Rejected upgrade to room {:?}: {}", room, message);
+            return (StatusCode::FORBIDDEN, message).into_response();
+        }
+    };
+
+    let capabilities = Capabilities::parse(params.get("caps").map(String::as_str));
+
+    // ?groups=a,b tags the peer with group labels (None = not given)
+    let groups = match params.get("groups") {
+        None => None,
+        Some(raw) => match groups::parse_groups(raw, state.config.current().max_groups_per_peer) {
+            Ok(groups) => Some(groups),
+            Err(reason) => {
+                warn!("Rejected upgrade: invalid groups: {}", reason);
+                return (StatusCode::BAD_REQUEST, reason).into_response();
+            }
+        },
+    };
+
+    // ?resume_from_seq=N: replay chat messages after messageId N on connect
+    let resume_from_seq = match params.get("resume_from_seq").map(|seq| seq.parse::<u64>()) {
+        None => None,
+        Some(Ok(seq)) => Some(seq),
+        Some(Err(_)) => {
+            warn!("Rejected upgrade: invalid resume_from_seq");
+            return (StatusCode::BAD_REQUEST, "resume_from_seq must be a whole number").into_response();
+        }
+    };
+
+    // ?appVersion=1.4.2, checked against the configured version floors
+    let app_version = match params.get("appVersion").map(|raw| client_version::parse(raw)) {
+        None => None,
+        Some(Ok(version)) => Some(version),
+        Some(Err(reason)) => {
+            warn!("Rejected upgrade: {}", reason);
+            return (StatusCode::BAD_REQUEST, reason).into_response();
+        }
+    };
+    let config = state.config.current();
+    let version_status = client_version::classify(app_version.as_ref(), &config);
+    if version_status == VersionStatus::Unsupported {
+        warn!("Rejected upgrade: appVersion {:?} is below the minimum", app_version);
+        let encoding = if capabilities.json { Encoding::Json } else { Encoding::Protobuf };
+        let message = client_version::upgrade_message(version_status, &config).unwrap_or_default();
+        let notice = encoding::encode(&system_notification(&message), encoding);
+        return ws.on_upgrade(move |socket| client_version::reject(socket, notice)).into_response();
+    }
+
+    info!(
+        "Using client-provided identity: display_name='{}', peer_id='{}', room='{}', caps={:?}, app_version={:?}, client_cert={:?}",
+        display_name, peer_id, room, capabilities, app_version, client_cert
+    );
+
+    let logged = logging::sample_connection(state.config.current().log_sample_every);
+    // Backstop for frames far over the limits; handle_socket rejects the rest
+    let frame_limit = state.config.current().transport_frame_limit();
+    let ws = ws.max_message_size(frame_limit).max_frame_size(frame_limit);
+    ws.on_upgrade(move |socket| {
+        logging::scoped(
+            logged,
+            handle_socket(
+                socket,
+                state,
+                display_name,
+                peer_id,
+                room,
+                admission,
+                capabilities,
+                groups,
+                app_version,
+                client_cert,
+                resume_from_seq,
+            ),
+        )
+    })
+    .into_response()
+}
+
+// Compile-time check that ws_handler is still a valid handler: an extractor
+// change that breaks it fails here, next to the handler, instead of as a long
+// trait error at the route
+const _: fn() = || {
+    fn is_handler<T, H: axum::handler::Handler<T, AppState>>(_: H) {}
+    is_handler(ws_handler);
+};
+
+// Caps the number of query parameters and the query string length, so a
+// client can't make every upgrade parse a huge pile of irrelevant params.
+// Counted on the raw string: repeated keys count each time.
+fn check_query_limits(raw_query: Option<&str>, config: &ServerConfig) -> Option<Response> {
+    let raw = raw_query.unwrap_or_default();
+    let count = raw.split('&').filter(|param| !param.is_empty()).count();
+
+    let problem = if config.max_query_bytes > 0 && raw.len() > config.max_query_bytes {
+        format!("query string is {} bytes, limit is {}", raw.len(), config.max_query_bytes)
+    } else if config.max_query_params > 0 && count > config.max_query_params {
+        format!("{} query parameters, limit is {}", count, config.max_query_params)
+    } else {
+        return None;
+    };
+    debug!("Rejected upgrade: {}", problem);
+    Some((StatusCode::BAD_REQUEST, problem).into_response())
+}
+
+// A request to /ws that isn't a WebSocket handshake, typically someone
+// curling the endpoint: 426 Upgrade Required (with the Upgrade header, as
+// RFC 9110 asks) and a JSON body saying how to connect instead of axum's
+// one-line rejection text.
+fn upgrade_required(rejection: &WebSocketUpgradeRejection, headers: &HeaderMap, config: &ServerConfig) -> Response {
+    warn!("Rejected /ws request without a WebSocket handshake: {}", rejection.body_text());
+    let scheme = if config.tls_cert_path.is_some() { "wss" } else { "ws" };
+    let host = headers
+        .get(axum::http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    let body = serde_json::json!({
+        "error": {
+            "code": "upgrade_required",
+            "message": "/ws is a WebSocket endpoint; connect with a WebSocket client instead of a plain HTTP request",
+            "detail": rejection.body_text(),
+        },
+        "example": format!("websocat '{}://{}/ws?displayName=Alice&room=lobby'", scheme, host),
+    });
+    (
+        StatusCode::UPGRADE_REQUIRED,
+        [
+            (axum::http::header::UPGRADE, "websocket"),
+            (axum::http::header::CONNECTION, "Upgrade"),
+        ],
+        axum::Json(body),
+    )
+        .into_response()
+}
+
+// The client token (see auth.rs): ?token=, or an Authorization: Bearer header.
+// Ok(None) when client tokens are off; Ok(Some(peer_id)) for a valid token;
+// otherwise the message for the 401. Why a token failed is only logged.
+fn check_client_token(
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    config: &ServerConfig,
+) -> Result<Option<String>, &'static str> {
+    let Some(secret) = &config.client_token_secret else {
+        return Ok(None);
+    };
+    let bearer = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = params.get("token").map(String::as_str).or(bearer) else {
+        warn!("Rejected upgrade: no client token");
+        return Err("a token is required");
+    };
+    match auth::verify(secret, token, now_ms() / 1000) {
+        Ok(claims) => Ok(Some(claims.peer_id)),
+        Err(reason) => {
+            warn!("Rejected upgrade: invalid client token ({})", reason);
+            Err("invalid or expired token")
+        }
+    }
+}
+
+// Optional X-Protocol-Version header on the upgrade request.
+// Absent = permissive (accepted). Present = must be a number within the
+// configured range, otherwise 426 Upgrade Required with the supported version.
+// Returns the rejection response, if any.
+fn check_protocol_version(headers: &HeaderMap, config: &ServerConfig) -> Option<Response> {
+    let raw = headers.get("x-protocol-version")?;
+    let supported = config.min_protocol_version..=config.max_protocol_version;
+    let version = raw.to_str().ok().and_then(|v| v.trim().parse::<u32>().ok());
+
+    let Some(version) = version else {
+        warn!("Rejected upgrade: malformed X-Protocol-Version {:?}", raw);
+        return Some((StatusCode::BAD_REQUEST, "X-Protocol-Version must be a whole number").into_response());
+    };
+    if supported.contains(&version) {
+        return None;
+    }
+
+    warn!(
+        "Rejected upgrade: protocol version {} not in {}..={}",
+        version, config.min_protocol_version, config.max_protocol_version
+    );
+    let message = format!(
+        "Protocol version {} is not supported; this server supports versions {} to {}",
+        version, config.min_protocol_version, config.max_protocol_version
     );
+    Some(
+        (
+            StatusCode::UPGRADE_REQUIRED,
+            [("x-protocol-version", config.max_protocol_version.to_string())],
+            message,
+        )
+            .into_response(),
+    )
+}
+
+// Sends a ping with a random payload and waits for the matching pong.
+// Frames other than ping/pong/close that arrive first are dropped: the peer
+// isn't registered yet, so there is nobody to deliver them to.
+async fn await_initial_pong(
+    client: &Client,
+    receiver: &mut futures_util::stream::SplitStream<WebSocket>,
+    timeout: Duration,
+) -> bool {
+    let nonce = uuid::Uuid::new_v4().as_bytes().to_vec();
+    if client.send(WsMessage::Ping(nonce.clone())).await.is_err() {
+        return false;
+    }
 
-    ws.on_upgrade(move |socket| handle_socket(socket, peers, display_name, peer_id))
+    let wait = async {
+        while let Some(Ok(msg)) = receiver.next().await {
+            match msg {
+                WsMessage::Pong(payload) if payload == nonce => return true,
+                WsMessage::Ping(payload) => {
+                    let _ = client.send(WsMessage::Pong(payload)).await;
+                }
+                WsMessage::Close(_) => return false,
+                _ => sampled!(debug, "Dropping frame received before initial pong"),
+            }
+        }
+        false
+    };
+    tokio::time::timeout(timeout, wait).await.unwrap_or(false)
 }
 
-// Actual WebSocket logic
-async fn handle_socket(socket: WebSocket, peers: Peers, display_name: String, peer_id: String) {
-    println!("[SERVER] WebSocket upgrade completed - client connected");
+// Actual WebSocket logic. Everything logged for this connection, including
+// by the helpers it calls, carries the connection span.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "connection", skip_all, fields(peer_id = %peer_id, display_name = %display_name))]
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    display_name: String,
+    peer_id: String,
+    room: String,
+    admission: Admission,
+    capabilities: Capabilities,
+    groups: Option<HashSet<String>>,
+    app_version: Option<semver::Version>,
+    client_cert: Option<String>,
+    resume_from_seq: Option<u64>,
+) {
+    sampled!(info, "WebSocket upgrade completed - client connected");
+    // Unsupported versions were turned away in ws_handler; this is Some for
+    // clients below the recommended version (see client_version.rs)
+    let upgrade_message = {
+        let config = state.config.current();
+        client_version::upgrade_message(client_version::classify(app_version.as_ref(), &config), &config)
+    };
+    let peers = state.peers.clone();
+
+    // Resolved once: a config reload only affects new connections
+    let features = FeatureSet::resolve(&state.config.current().feature_rules, &peer_id);
+
+    // Optional hard cap on connection lifetime (None = live forever)
+    let lifetime_deadline = state
+        .config
+        .current()
+        .max_connection_lifetime()
+        .map(|lifetime| tokio::time::Instant::now() + lifetime);
 
     let (sender, mut receiver) = socket.split();
-    let client: Client = Arc::new(Mutex::new(sender));
+    let encoding = if capabilities.json { Encoding::Json } else { Encoding::Protobuf };
+    let lane_capacity = state.config.current().outbound_lane_capacity;
+    let client: Client = Arc::new(ClientSender::new(sender, encoding, capabilities.compression, lane_capacity));
+
+    // Anti-abuse: optionally make the client prove it is a real bidirectional
+    // peer by answering a ping before it is registered
+    if let Some(timeout) = state.config.current().initial_pong_timeout() {
+        if !await_initial_pong(&client, &mut receiver, timeout).await {
+            warn!(
+                "{} ({}) did not answer the initial ping within {:?}, disconnecting",
+                display_name, peer_id, timeout
+            );
+            let _ = client
+                .send(WsMessage::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "initial pong timeout".into(),
+                })))
+                .await;
+            return;
+        }
+    }
 
     // Add peer to the shared state
     let peer_count_after_join: usize;
+    let join_seq: u64;
+    let resumed: bool;
+    let replay: Option<history::Replay>;
     {
         let mut peers_guard = peers.lock().await;
-        peers_guard.insert(
+        // Someone connected is already using this peerId. Checked under the
+        // lock so two connections racing for one id can't both get in.
+        if let Some(existing) = peers_guard
+            .get(&peer_id)
+            .filter(|existing| existing.connection_state == ConnectionState::Connected)
+        {
+            match state.config.current().duplicate_peer_id_policy {
+                DuplicatePeerIdPolicy::RejectNew => {
+                    drop(peers_guard);
+                    warn!("Rejected {} ({}): peer_id already in use", display_name, peer_id);
+                    let reply = error_notification("peer_id_in_use", &format!("Peer id '{}' is already connected", peer_id));
+                    send_server_message(&client, &reply, "peer_id_in_use").await;
+                    let _ = client
+                        .send(WsMessage::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "peer_id in use".into(),
+                        })))
+                        .await;
+                    return;
+                }
+                DuplicatePeerIdPolicy::ReplaceOld => {
+                    // Queued, not awaited: the old socket may be the dead one.
+                    // Its own cleanup sees a newer join_seq and leaves ours alone.
+                    info!("{} connected again from a new socket, closing the old one", peer_id);
+                    let notice = system_notification("You were disconnected: this peer id connected from somewhere else");
+                    let frame = encoding::encode(&notice, existing.sender.encoding);
+                    queue_encoded(&existing.sender, frame, "peer_id_replaced", Priority::Control);
+                    existing.sender.try_send_with_priority(
+                        WsMessage::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "peer_id connected elsewhere".into(),
+                        })),
+                        Priority::Control,
+                    );
+                }
+            }
+        }
+        // Under the lock, so "first in the room" is still true when we get in
+        let first_in_room = !peers_guard.values().any(|peer| peer.room == room && peer.peer_id != peer_id);
+        if !state.rooms.admit(&room, admission, first_in_room) {
+            drop(peers_guard);
+            warn!("Rejected {} ({}): the password of room {:?} changed", display_name, peer_id, room);
+            let reply = error_notification("room_password_invalid", "The room password changed, reconnect with the new one");
+            send_server_message(&client, &reply, "room_password_invalid").await;
+            let _ = client
+                .send(WsMessage::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "room password changed".into(),
+                })))
+                .await;
+            return;
+        }
+        // Taken under the lock so join order and join_seq order always agree
+        join_seq = state.next_join_seq.fetch_add(1, Ordering::Relaxed);
+        // Back within the reconnect grace period: take over the old entry
+        // (its presence subscriptions, and its groups unless ?groups= was
+        // given) without announcing a new join. Coming back into another
+        // room is a leave from the old one and a join to the new one.
+        let resumed_from = peers_guard
+            .get(&peer_id)
+            .filter(|previous| previous.connection_state == ConnectionState::Reconnecting && previous.room == room)
+            .map(|previous| {
+                (
+                    previous.presence_subscriptions.clone(),
+                    previous.groups.clone(),
+                    previous.accepted_content_types.clone(),
+                    previous.connected_at_ms,
+                    previous.sender.offline.take().map(|buffered| (buffered, previous.sender.encoding)),
+                )
+            });
+        resumed = resumed_from.is_some();
+        let (resumed_subscriptions, resumed_groups, resumed_content_types, resumed_connected_at_ms, offline_frames) =
+            resumed_from.unwrap_or_default();
+        let groups = groups.unwrap_or(resumed_groups);
+        let previous = peers_guard.insert(
             peer_id.clone(),
             Peer {
                 sender: client.clone(),
                 display_name: display_name.clone(),
                 peer_id: peer_id.clone(),
+                presence_subscriptions: resumed_subscriptions,
+                groups: groups.clone(),
+                app_version,
+                outdated_client: upgrade_message.is_some(),
+                accepted_content_types: resumed_content_types,
+                client_cert,
+                capabilities,
+                join_seq,
+                connection_state: ConnectionState::Connected,
+                room: room.clone(),
+                features: features.clone(),
+                connected_at_ms: if resumed { resumed_connected_at_ms } else { now_ms() },
             },
         );
+        // The old room would otherwise keep showing this peer forever
+        if let Some(previous) = previous.as_ref().filter(|previous| previous.room != room) {
+            broadcast_peer_left(&state, &peers_guard, &peer_id, &previous.display_name, &previous.room).await;
+        }
+        // Also covers a replaced entry for the same peerId
+        let previous_groups = previous.map(|previous| previous.groups).unwrap_or_default();
+        state.groups.replace(&peer_id, &previous_groups, &groups);
         peer_count_after_join = peers_guard.len();
-        println!("[SERVER] ✅ Peer registered: {} ({})", display_name, peer_id);
-        println!("[SERVER] Total connected peers: {}", peer_count_after_join);
+        if resumed {
+            sampled!(info, "Peer reconnected within grace period: {} ({})", display_name, peer_id);
+        } else {
+            sampled!(info, "Peer registered: {} ({})", display_name, peer_id);
+        }
+        sampled!(info, "Total connected peers: {}", peer_count_after_join);
+
+        // Queued (not sent) before the peers lock is released, so nothing
+        // live gets ahead of what the peer missed while away
+        if let Some((buffered, encoding)) = offline_frames {
+            offline::flush(&client, buffered, encoding == client.encoding);
+        }
+
+        // Taken under the peers lock: chat messages get their messageId and
+        // are fanned out under the same lock, so everything after lastSeq
+        // reaches this peer live and nothing falls in between
+        replay = resume_from_seq.map(|seq| state.history.replay_for(&peer_id, &room, seq));
+    }
+
+    // Sent without the lock; live messages may interleave (see history.rs)
+    if let Some(replay) = replay {
+        replay.send(&client, &peer_id, state.config.current().snapshot_compression_min_bytes).await;
     }
 
-    // Broadcast \"peer_joined\" notification to all OTHER peers (not the new peer)
+    // Who is already here, before anyone is told about us. A peer joining in
+    // between may show up both in the roster and as peer_joined.
+    peer_list::send_peer_list(&state, &client, &room, Some(&peer_id)).await;
+    pins::send_pins(&state, &client, &room).await;
+
+    // So the UI can hide what this connection isn't allowed to use
+    let disabled_features = features.disabled();
+    if !disabled_features.is_empty() {
+        let mut features_data = HashMap::new();
+        features_data.insert("disabled".to_string(), disabled_features.join(","));
+        send_server_message(&client, &notification("features", features_data), "features").await;
+    }
+
+    // Broadcast \"peer_joined\" notification to all OTHER peers in the room (not the new peer)
     let mut join_data = std::collections::HashMap::new();
     join_data.insert("peerId".to_string(), peer_id.clone());
     join_data.insert("displayName".to_string(), display_name.clone());
     join_data.insert("message".to_string(), format!("{} joined", display_name));
+    if let Some(expires_at) = state.config.current().presence_expires_at() {
+        join_data.insert("expiresAt".to_string(), expires_at.to_string());
+    }
 
-    let join_notification = Envelope {
-        event: "notification".to_string(),
-        event_data: Some(EventData {
-            method: "peer_joined".to_string(),
-            data: join_data,
-        }),
-    };
+    let join_notification = notification("peer_joined", join_data);
 
-    {
+    // A peer that was gone for good (not just within the grace period) may
+    // get a summary of what it missed; taken either way so it can't go stale
+    let away_summary = (!resumed)
+        .then(|| state.away.take_summary(&peer_id, &room, &state.history))
+        .flatten()
+        .filter(|_| capabilities.away_summary);
+    if !resumed {
+        state.away.record_joined(&peer_id, &display_name, &room);
+    }
+    if let Some(summary) = away_summary {
+        send_server_message(&client, &notification_envelope(summary), "away_summary").await;
+    }
+
+    if let Some(window) = state.config.current().presence_coalesce_window().filter(|_| !resumed) {
+        presence::queue_change(&state, "joined", &peer_id, &display_name, &room, window);
+    } else if !resumed {
+        state.shadow.observe(&join_notification);
+        let cross_room = state.config.current().cross_room_presence_subscriptions;
         let peers_guard = peers.lock().await;
         for (id, peer) in peers_guard.iter() {
             // Skip the newly joined peer - only notify others
-            if *id != peer_id {
+            if presence::receives_presence_of(peer, &peer_id, &room, cross_room) {
                 let ctx = format!("join_notification → {}", id);
-                send_server_message(&peer.sender, &join_notification, &ctx).await;
+                queue_server_message(&peer.sender, &join_notification, &ctx);
             }
         }
     }
 
+    // Outdated clients may stay, but are told to upgrade
+    if let Some(message) = &upgrade_message {
+        send_server_message(&client, &system_notification(message), "client_version").await;
+    }
+
+    // Slow-client policy: (max queue depth, how long it may stay above it)
+    let slow_client_policy = state.config.current().slow_client_policy();
+    let mut queue_check = tokio::time::interval(SLOW_CLIENT_CHECK_INTERVAL);
+    let mut slow_since: Option<Instant> = None;
+
+    // Connection-quality reports, only for peers that asked for them
+    let quality_interval = state
+        .config
+        .current()
+        .connection_quality_interval()
+        .filter(|_| capabilities.quality);
+    // First report one interval in (the period is unused when reports are off)
+    let quality_period = quality_interval.unwrap_or(SLOW_CLIENT_CHECK_INTERVAL);
+    let mut quality_tick = tokio::time::interval_at(tokio::time::Instant::now() + quality_period, quality_period);
+    let mut quality_probe = quality::QualityProbe::default();
+
+    // Heartbeat: periodic pings, and a deadline for the pong (see heartbeat.rs)
+    let heartbeat_policy = state.config.current().heartbeat_policy();
+    let heartbeat_period = heartbeat_policy.map_or(SLOW_CLIENT_CHECK_INTERVAL, |(interval, _)| interval);
+    let mut heartbeat_tick =
+        tokio::time::interval_at(tokio::time::Instant::now() + heartbeat_period, heartbeat_period);
+    let mut heartbeat = heartbeat::Heartbeat::new(heartbeat_policy.map(|(_, timeout)| timeout).unwrap_or_default());
+
+    // Pong flood guard: unsolicited pongs seen in the current window
+    let mut pong_window_start = Instant::now();
+    let mut pongs_in_window: u64 = 0;
+
+    // Frames rejected for exceeding the size limits (see max_oversized_frames)
+    let mut oversized_frames: u32 = 0;
+
+    // Session stats, logged as one line only if the connection ends in an error
+    let mut summary = SessionSummary::new();
+    let mut session_error: Option<String> = None;
+
+    // Idle disconnect: a warning `lead` before the timeout, then the close.
+    // Both count from the last binary/text frame.
+    let idle_policy = state.config.current().idle_policy();
+    let mut last_activity = tokio::time::Instant::now();
+    let mut idle_warned = false;
+
+    // (messageId, when) of this peer's last chat message, for groupedWithPrevious
+    let mut last_chat: Option<(u64, Instant)> = None;
+
     // Receive loop
-    while let Some(msg_result) = receiver.next().await {
+    loop {
+        let idle_deadline = idle_policy.map(|(timeout, _)| last_activity + timeout);
+        let idle_warning_at = idle_policy
+            .and_then(|(timeout, lead)| Some(last_activity + timeout - lead?))
+            .filter(|_| !idle_warned);
+        let msg_result = tokio::select! {
+            next = receiver.next() => match next {
+                Some(msg_result) => msg_result,
+                None => break,
+            },
+            _ = sleep_until_deadline(lifetime_deadline) => {
+                sampled!(
+                    info,
+                    "Max connection lifetime reached for {} ({}), closing",
+                    display_name, peer_id
+                );
+                let notice = system_notification("Maximum connection lifetime reached, please reconnect");
+                if send_server_message(&client, &notice, "lifetime_exceeded").await {
+                    summary.record_out();
+                }
+                if let Some(timeout) = state.config.current().drain_timeout() {
+                    if !drain_outbound(&client, timeout).await {
+                        warn!("Outbound queue for {} not drained within {:?}", peer_id, timeout);
+                    }
+                }
+                let _ = client
+                    .send(WsMessage::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "max connection lifetime reached".into(),
+                    })))
+                    .await;
+                break;
+            }
+            _ = queue_check.tick(), if slow_client_policy.is_some() => {
+                let Some((max_depth, grace)) = slow_client_policy else { continue };
+                let depth = client.queue_depth();
+                if depth <= max_depth {
+                    slow_since = None;
+                    continue;
+                }
+                let since = *slow_since.get_or_insert_with(Instant::now);
+                if since.elapsed() < grace {
+                    continue;
+                }
+                let evictions = Metrics::incr(&state.metrics.slow_client_evictions);
+                warn!(
+                    "Evicting slow client {} ({}): queue depth {} > {} for {:?} (slow_client_evictions={})",
+                    display_name, peer_id, depth, max_depth, since.elapsed(), evictions
+                );
+                // No drain_outbound here: the queue being stuck is the problem.
+                // The sink may be stuck too, so don't wait on it forever
+                let close = client.send(WsMessage::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "slow consumer".into(),
+                })));
+                let _ = tokio::time::timeout(SLOW_CLIENT_CHECK_INTERVAL, close).await;
+                break;
+            }
+            _ = sleep_until_deadline(idle_warning_at) => {
+                idle_warned = true;
+                let lead = idle_policy.and_then(|(_, lead)| lead).unwrap_or_default();
+                let warning = system_notification(&format!(
+                    "You'll be disconnected in {}s for inactivity",
+                    lead.as_secs()
+                ));
+                if send_server_message(&client, &warning, "idle_warning").await {
+                    summary.record_out();
+                }
+                continue;
+            }
+            _ = sleep_until_deadline(idle_deadline) => {
+                sampled!(info, "Disconnecting idle peer {} ({})", display_name, peer_id);
+                let notice = system_notification("Disconnected for inactivity");
+                if send_server_message(&client, &notice, "idle_timeout").await {
+                    summary.record_out();
+                }
+                if let Some(timeout) = state.config.current().drain_timeout() {
+                    drain_outbound(&client, timeout).await;
+                }
+                let _ = client
+                    .send(WsMessage::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "idle timeout".into(),
+                    })))
+                    .await;
+                break;
+            }
+            _ = heartbeat_tick.tick(), if heartbeat_policy.is_some() => {
+                // Only queued: on a dead connection the write may never finish,
+                // and waiting for it would keep the deadline below from firing
+                if let Some(nonce) = heartbeat.start_ping() {
+                    if client.try_send_with_priority(WsMessage::Ping(nonce), Priority::Control) {
+                        summary.record_out();
+                    }
+                }
+                continue;
+            }
+            _ = sleep_until_deadline(heartbeat.deadline()) => {
+                let timeouts = Metrics::incr(&state.metrics.heartbeat_timeouts);
+                warn!(
+                    "{} ({}) did not answer a heartbeat ping within {:?}, disconnecting (heartbeat_timeouts={})",
+                    display_name,
+                    peer_id,
+                    heartbeat_policy.map(|(_, timeout)| timeout).unwrap_or_default(),
+                    timeouts
+                );
+                // The peer is most likely gone, so don't wait on the close for long
+                let close = client.send(WsMessage::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "heartbeat timeout".into(),
+                })));
+                let _ = tokio::time::timeout(SLOW_CLIENT_CHECK_INTERVAL, close).await;
+                session_error = Some("heartbeat timeout".to_string());
+                break;
+            }
+            _ = quality_tick.tick(), if quality_interval.is_some() => {
+                let report = quality_probe.report(&client);
+                if send_server_message(&client, &report, "connection_quality").await {
+                    summary.record_out();
+                }
+                if client.send(WsMessage::Ping(quality_probe.start_ping())).await.is_ok() {
+                    summary.record_out();
+                }
+                continue;
+            }
+        };
         let msg = match msg_result {
             Ok(msg) => msg,
-            Err(_) => break,
+            Err(e) => {
+                session_error = Some(e.to_string());
+                if is_invalid_utf8(e) {
+                    warn!("{} sent a text frame that is not valid UTF-8, disconnecting", peer_id);
+                    let _ = client
+                        .send(WsMessage::Close(Some(CloseFrame {
+                            code: close_code::INVALID,
+                            reason: "text frame is not valid UTF-8".into(),
+                        })))
+                        .await;
+                }
+                break;
+            }
+        };
+
+        if matches!(msg, WsMessage::Binary(_) | WsMessage::Text(_)) {
+            last_activity = tokio::time::Instant::now();
+            idle_warned = false;
+        }
+
+        match &msg {
+            WsMessage::Binary(data) => summary.record_in("binary", data.len()),
+            WsMessage::Text(text) => summary.record_in("text", text.len()),
+            WsMessage::Ping(payload) => summary.record_in("ping", payload.len()),
+            WsMessage::Pong(payload) => summary.record_in("pong", payload.len()),
+            WsMessage::Close(_) => summary.record_in("close", 0),
+        }
+
+        // JSON peers send requests as JSON text frames; from here on those are
+        // handled exactly like binary Envelopes. Anything else in a text frame
+        // (control commands, oversized frames) takes the usual text path.
+        let (msg, from_json) = match msg {
+            WsMessage::Text(text) if capabilities.json && text.len() <= state.config.current().max_text_frame_bytes => {
+                match encoding::from_json(&text) {
+                    Some(envelope) => (WsMessage::Binary(envelope.encode_to_vec()), true),
+                    None => (WsMessage::Text(text), false),
+                }
+            }
+            msg => (msg, false),
         };
 
         match msg {
             WsMessage::Binary(data) => {
-                println!(
-                    "[SERVER DEBUG] 📥 Raw binary frame from client ({} bytes)",
+                // Start of the fan-out latency measurement, plus the wall clock
+                // echoed to server_timestamp-capable senders
+                let received_at = Instant::now();
+                let received_at_us = now_us();
+                sampled!(
+                    debug,
+                    "Raw binary frame from client ({} bytes)",
                     data.len()
                 );
+                let limit = state.config.current().max_binary_frame_bytes;
+                if data.len() > limit {
+                    reject_oversized_frame(&client, "binary", data.len(), limit).await;
+                    oversized_frames += 1;
+                    if too_many_oversized_frames(&state, &client, &peer_id, oversized_frames).await {
+                        break;
+                    }
+                    continue;
+                }
+                // Framed clients say per frame whether it's an Envelope or raw bytes
+                let data = if capabilities.framed && !from_json {
+                    match raw_relay::unframe(&client, data, &capabilities, state.config.current().raw_relay_checksum).await {
+                        Some(raw_relay::Frame::Envelope(envelope_bytes)) => envelope_bytes,
+                        Some(raw_relay::Frame::Raw { bytes, checksum }) => {
+                            if features.allows(features::RAW_RELAY) {
+                                raw_relay::relay(&state, &peer_id, &room, bytes, checksum).await;
+                            } else {
+                                reject_disabled_feature(&client, &peer_id, features::RAW_RELAY).await;
+                            }
+                            continue;
+                        }
+                        None => continue,
+                    }
+                } else {
+                    data
+                };
                 // Parse protobuf envelope from client
                 match Envelope::decode(data.as_ref()) {
                     Ok(envelope) => {
-                        println!("[SERVER DEBUG] Decoded client Envelope: {:?}", envelope);
+                        sampled!(debug, "Decoded client Envelope: {:?}", envelope);
+
+                        if let Err(problem) = validation::check(state.config.current().validation_mode, &envelope) {
+                            warn!("Rejected message from {}: {}", peer_id, problem);
+                            let reply = error_notification("invalid_message", &problem);
+                            send_server_message(&client, &reply, "invalid_message").await;
+                            continue;
+                        }
 
                         // We only expect \"request\" from client
                         if envelope.event != "request" {
-                            println!("[SERVER DEBUG] Unexpected event from client: {}", envelope.event);
+                            sampled!(debug, "Unexpected event from client: {}", envelope.event);
+                            if let Some(hint) = decode_hint::wrong_type_hint(&data, Some(&envelope)) {
+                                sampled!(debug, "Looks like {}", hint);
+                            }
                             continue;
                         }
 
                         let Some(event_data) = envelope.event_data else {
-                            println!("[SERVER DEBUG] Missing event_data in client envelope");
+                            sampled!(debug, "Missing event_data in client envelope");
                             continue;
                         };
 
+                        summary.record_method(&event_data.method);
+
+                        // Finer-grained than the frame limit: e.g. chat text stays small
+                        // even though frames may be large for other methods
+                        let limit = state.config.current().method_size_limits.get(&event_data.method).copied();
+                        let size = payload_size(&event_data);
+                        if let Some(limit) = limit.filter(|limit| size > *limit) {
+                            warn!(
+                                "Rejected {} from {}: payload {} bytes > {} bytes",
+                                event_data.method, peer_id, size, limit
+                            );
+                            let reply = error_notification(
+                                "payload_too_large",
+                                &format!("{} payload of {} bytes exceeds the {} byte limit", event_data.method, size, limit),
+                            );
+                            send_server_message(&client, &reply, "payload_too_large").await;
+                            continue;
+                        }
+
+                        if !features.allows(&event_data.method) {
+                            reject_disabled_feature(&client, &peer_id, &event_data.method).await;
+                            continue;
+                        }
+
                         let method = event_data.method;
                         let data = event_data.data;
+                        let items = event_data.items;
 
-                        if method == "chat_message" {
-                            let sender_display_name =
-                                data.get("displayName").cloned().unwrap_or_else(|| display_name.clone());
-                            let text = data.get("text").cloned().unwrap_or_default();
+                        // chat_message goes upstream only once it passed validation (below)
+                        if method != "chat_message" {
+                            state.upstream.forward(&method, &data, &items, &display_name, &room);
+                        }
 
-                            println!(
-                                "Received chat_message from {} ({}): {}",
-                                sender_display_name, peer_id, text
-                            );
+                        match method.as_str() {
+                            "chat_message" => {
+                                let sender_display_name =
+                                    data.get("displayName").cloned().unwrap_or_else(|| display_name.clone());
+                                let text = data.get("text").cloned().unwrap_or_default();
+
+                                // Optional direct message: only this peer gets it
+                                let to_peer_id = data.get("toPeerId").filter(|to| !to.is_empty()).cloned();
+
+                                // Optional MIME-style tag, relayed as-is (see content_type.rs)
+                                let content_type = match data.get("contentType").filter(|raw| !raw.is_empty()) {
+                                    None => None,
+                                    Some(raw) => match content_type::parse(raw) {
+                                        Ok(content_type) => Some(content_type),
+                                        Err(reason) => {
+                                            let reply = error_notification("invalid_content_type", &reason);
+                                            send_server_message(&client, &reply, "invalid_content_type").await;
+                                            continue;
+                                        }
+                                    },
+                                };
+
+                                // Optional outbound lane for the relayed copies (see priority.rs)
+                                let priority = match data.get("priority").map(String::as_str) {
+                                    None | Some("") => Priority::Normal,
+                                    Some(raw) => match Priority::parse(raw) {
+                                        Some(priority) => priority,
+                                        None => {
+                                            let reply = error_notification(
+                                                "invalid_priority",
+                                                &format!("unknown priority '{}': expected control, normal or bulk", raw),
+                                            );
+                                            send_server_message(&client, &reply, "invalid_priority").await;
+                                            continue;
+                                        }
+                                    },
+                                };
+
+                                sampled!(
+                                    info,
+                                    "Received chat_message from {} ({}): {}",
+                                    sender_display_name, peer_id, text
+                                );
 
-                            // Broadcast as notification chat_message to all OTHER peers
-                            let mut out_data = std::collections::HashMap::new();
-                            out_data.insert("fromPeerId".to_string(), peer_id.clone());
-                            out_data.insert("fromDisplayName".to_string(), sender_display_name.clone());
-                            out_data.insert("text".to_string(), text.clone());
+                                // Valid: relayed upstream as the client sent it, alongside the local fan-out
+                                state.upstream.forward(&method, &data, &items, &display_name, &room);
 
-                            let broadcast_msg = Envelope {
-                                event: "notification".to_string(),
-                                event_data: Some(EventData {
+                                // Broadcast as notification chat_message to all OTHER peers in the room.
+                                // The id is taken under the peers lock, so every peer receives
+                                // chat messages in messageId order (which replays rely on).
+                                let peers_guard = peers.lock().await;
+                                // Peers in other rooms count as offline, so their ids don't leak
+                                let in_room = |to: &String| peers_guard.get(to).is_some_and(|peer| peer.room == room);
+                                if let Some(to) = to_peer_id.as_ref().filter(|to| !in_room(to)) {
+                                    drop(peers_guard);
+                                    let reply = error_notification("peer_offline", &format!("Peer '{}' is not online", to));
+                                    send_server_message(&client, &reply, "peer_offline").await;
+                                    continue;
+                                }
+                                // The server decides all three, whatever the client sent.
+                                // messageId orders messages on this server (replays and receipts
+                                // use it); messageUuid stays unique across servers and bridges.
+                                let message_id = state.history.next_message_id();
+                                let message_uuid = uuid::Uuid::new_v4().to_string();
+                                let sent_at_ms = now_ms();
+                                let mut out_data = std::collections::HashMap::new();
+                                out_data.insert("messageId".to_string(), message_id.to_string());
+                                out_data.insert("messageUuid".to_string(), message_uuid.clone());
+                                out_data.insert("sentAtMs".to_string(), sent_at_ms.to_string());
+                                out_data.insert("fromPeerId".to_string(), peer_id.clone());
+                                out_data.insert("fromDisplayName".to_string(), sender_display_name.clone());
+                                out_data.insert("text".to_string(), text.clone());
+                                // Optional threading: relayed as-is so clients can render reply chains.
+                                // Not validated against the history, which may have dropped the parent.
+                                if let Some(reply_to) = data.get("replyToMessageId").filter(|id| !id.is_empty()) {
+                                    out_data.insert("replyToMessageId".to_string(), reply_to.clone());
+                                }
+                                if priority != Priority::Normal {
+                                    out_data.insert("priority".to_string(), priority.as_str().to_string());
+                                }
+                                if let Some(content_type) = &content_type {
+                                    out_data.insert("contentType".to_string(), content_type.clone());
+                                }
+                                if let Some(to) = &to_peer_id {
+                                    out_data.insert("toPeerId".to_string(), to.clone());
+                                }
+                                // Grouped only if nobody else spoke in between (ids are consecutive)
+                                let grouping_window = state.config.current().message_grouping_window();
+                                let grouped = last_chat.zip(grouping_window).is_some_and(|((last_id, at), window)| {
+                                    last_id + 1 == message_id && at.elapsed() <= window
+                                });
+                                if grouped {
+                                    out_data.insert("groupedWithPrevious".to_string(), "true".to_string());
+                                }
+                                last_chat = Some((message_id, Instant::now()));
+
+                                // Structured content (one item per record) is relayed
+                                // untouched, like text
+                                let mut out_event = EventData {
                                     method: "chat_message".to_string(),
                                     data: out_data,
-                                }),
-                            };
-
-                            let peers_guard = peers.lock().await;
-                            for (id, peer) in peers_guard.iter() {
-                                // Skip the sender
-                                if *id != peer_id {
-                                    let ctx = format!("chat_broadcast → {}", id);
-                                    send_server_message(&peer.sender, &broadcast_msg, &ctx).await;
+                                    items,
+                                    payload: Vec::new(),
+                                    checksum: Vec::new(),
+                                };
+                                state.transforms.apply(&mut out_event);
+
+                                // Record what was actually delivered, after transforms
+                                let field = |key: &str| out_event.data.get(key).cloned().unwrap_or_default();
+                                state.history.record(HistoryEntry {
+                                    message_id,
+                                    message_uuid,
+                                    sent_at_ms,
+                                    from_peer_id: field("fromPeerId"),
+                                    from_display_name: field("fromDisplayName"),
+                                    text: field("text"),
+                                    items: out_event.items.iter().map(|item| item.data.clone()).collect(),
+                                    reply_to_message_id: out_event.data.get("replyToMessageId").cloned(),
+                                    content_type: content_type.clone(),
+                                    to_peer_id: to_peer_id.clone(),
+                                    room: room.clone(),
+                                });
+                                state.receipts.track(message_id, peer_id.clone());
+
+                                let broadcast_msg = notification_envelope(out_event);
+                                state.shadow.observe(&broadcast_msg);
+
+                                // laterJoinersOnly=true: only peers that joined after the sender,
+                                // e.g. an onboarding message a host sends to everyone who arrives
+                                // after them, without repeating it to those already present
+                                let later_joiners_only = data.get("laterJoinersOnly").is_some_and(|flag| flag == "true");
+                                let filter_type = content_type.as_deref().unwrap_or(content_type::DEFAULT_CONTENT_TYPE);
+                                let echo_direct = state.config.current().direct_message_echo_to_sender;
+
+                                let mut delivered = 0;
+                                let mut recipients = 0;
+                                {
+                                    let mut encoded = EncodedOnce::new(&broadcast_msg);
+                                    for (id, peer) in peers_guard.iter() {
+                                        // Skip the sender
+                                        let is_recipient = peer.room == room
+                                            && match &to_peer_id {
+                                                Some(to) => id == to || (echo_direct && *id == peer_id),
+                                                None => *id != peer_id && (!later_joiners_only || peer.join_seq > join_seq),
+                                            };
+                                        if is_recipient && content_type::accepts(&peer.accepted_content_types, filter_type) {
+                                            recipients += 1;
+                                            let ctx = format!("chat_broadcast → {}", id);
+                                            let frame = encoded.get(peer.sender.encoding);
+                                            if queue_encoded(&peer.sender, frame, &ctx, priority) {
+                                                delivered += 1;
+                                            }
+                                        }
+                                    }
+                                }
+                                drop(peers_guard);
+                                state.metrics.record_fanout(recipients, received_at.elapsed());
+                                state.room_rates.record(&room);
+
+                                // Ack-capable senders learn their message was relayed
+                                if capabilities.ack {
+                                    let mut ack_data = HashMap::new();
+                                    ack_data.insert("delivered".to_string(), delivered.to_string());
+                                    if let Some(client_id) = data.get("clientMessageId") {
+                                        ack_data.insert("clientMessageId".to_string(), client_id.clone());
+                                    }
+                                    if capabilities.server_timestamp {
+                                        ack_data.insert("serverReceivedAtUs".to_string(), received_at_us.to_string());
+                                    }
+                                    if send_server_message(&client, &notification("ack", ack_data), "chat_ack").await {
+                                        summary.record_out();
+                                    }
                                 }
                             }
-                        } else {
-                            println!(
-                                "[SERVER DEBUG] Unknown client method '{}', data: {:?}",
-                                method, data
-                            );
+
+                            "encrypted_message" => {
+                                e2e::relay_encrypted(&state, &peer_id, &room, &client, &data).await;
+                            }
+
+                            "mark_read" => {
+                                send_read_receipt(&state, &peer_id, &room, &data).await;
+                            }
+
+                            "room_stats" => {
+                                let rate = state.room_rates.room(&room);
+                                let mut stats_data = HashMap::new();
+                                stats_data.insert("room".to_string(), rate.room);
+                                stats_data.insert("messagesLast10s".to_string(), rate.messages_last_10s.to_string());
+                                stats_data.insert("messagesLast60s".to_string(), rate.messages_last_60s.to_string());
+                                stats_data.insert("perSecond".to_string(), format!("{:.2}", rate.per_second));
+                                send_server_message(&client, &notification("room_stats", stats_data), "room_stats").await;
+                            }
+
+                            "typing" => {
+                                broadcast_typing(&state, &peer_id, &display_name, &room).await;
+                            }
+
+                            "delivery_ack" => {
+                                reliable::handle_ack(&client, &peer_id, &data);
+                            }
+
+                            "set_metadata" => {
+                                if !client_version::handle_set_metadata(&state, &peer_id, &client, &data).await {
+                                    break;
+                                }
+                            }
+
+                            "set_content_filter" => {
+                                content_type::handle_set_filter(&state, &peer_id, &client, &data).await;
+                            }
+
+                            "list_peers" => {
+                                peer_list::send_peer_list(&state, &client, &room, None).await;
+                            }
+
+                            "list_rooms" => {
+                                rooms::send_room_list(&state, &client).await;
+                            }
+
+                            "pin_message" | "unpin_message" => {
+                                pins::handle_pin(&state, &peer_id, &room, &client, &method, &data).await;
+                            }
+
+                            "set_groups" => {
+                                groups::handle_set_groups(&state, &peer_id, &client, &data).await;
+                            }
+
+                            "group_message" => {
+                                groups::send_group_message(&state, &peer_id, &display_name, &room, &client, &data).await;
+                            }
+
+                            "subscribe_presence" | "unsubscribe_presence" => {
+                                presence::handle_subscription(&state, &peer_id, &client, &method, &data).await;
+                            }
+
+                            _ => {
+                                sampled!(
+                                    debug,
+                                    "Unknown client method '{}', data: {:?}",
+                                    method, data
+                                );
+                            }
                         }
                     }
                     Err(e) => {
-                        println!("[SERVER] ❌ Failed to decode client message: {}", e);
+                        warn!("Failed to decode client message: {}", e);
+                        if let Some(hint) = decode_hint::wrong_type_hint(&data, None) {
+                            debug!("Looks like {}", hint);
+                        }
+                        // Includes string fields that aren't UTF-8: never passed on lossily
+                        let reply = error_notification("invalid_message", &format!("could not decode the Envelope: {}", e));
+                        send_server_message(&client, &reply, "invalid_message").await;
                     }
                 }
             }
 
-            WsMessage::Text(_) => {
-                // Legacy text support - ignore or convert
-                println!("[SERVER] ⚠️ Received text message (protobuf expected), ignoring");
+            WsMessage::Text(text) => {
+                let limit = state.config.current().max_text_frame_bytes;
+                if text.len() > limit {
+                    reject_oversized_frame(&client, "text", text.len(), limit).await;
+                    oversized_frames += 1;
+                    if too_many_oversized_frames(&state, &client, &peer_id, oversized_frames).await {
+                        break;
+                    }
+                    continue;
+                }
+                // Text frames are control commands (see control.rs), data goes in binary
+                // (JSON peers' Envelopes were turned into binary ones above)
+                let reply = control::handle(&text);
+                if client.send(WsMessage::Text(reply)).await.is_ok() {
+                    summary.record_out();
+                }
             }
 
             WsMessage::Ping(payload) => {
-                let mut locked = client.lock().await;
-                let _ = locked.send(WsMessage::Pong(payload)).await;
+                if client.send(WsMessage::Pong(payload)).await.is_ok() {
+                    summary.record_out();
+                }
             }
 
-            WsMessage::Pong(_) => {}
+            WsMessage::Pong(payload) => {
+                // Answers to connection-quality and heartbeat pings are expected
+                if quality_probe.on_pong(&payload) || heartbeat.on_pong(&payload) {
+                    continue;
+                }
+                // Anything else counts as unsolicited: a pong the client sent on its
+                // own, a repeat, or a late answer to a probe/heartbeat ping that was
+                // already replaced or given up on. (The initial-pong check's answer
+                // never gets here, await_initial_pong consumes it.) RFC 6455 allows
+                // unsolicited pongs as heartbeats, but not as a flood.
+                let limit = state.config.current().max_unsolicited_pongs_per_min;
+                if limit == 0 {
+                    continue;
+                }
+                if pong_window_start.elapsed() >= PONG_FLOOD_WINDOW {
+                    pong_window_start = Instant::now();
+                    pongs_in_window = 0;
+                }
+                pongs_in_window += 1;
+                if pongs_in_window > limit {
+                    let disconnects = Metrics::incr(&state.metrics.pong_flood_disconnects);
+                    warn!(
+                        "Disconnecting {} ({}): more than {} unsolicited pongs per minute (pong_flood_disconnects={})",
+                        display_name, peer_id, limit, disconnects
+                    );
+                    let _ = client
+                        .send(WsMessage::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "pong flood".into(),
+                        })))
+                        .await;
+                    break;
+                }
+            }
 
             WsMessage::Close(frame) => {
-                let mut locked = client.lock().await;
-                let _ = locked.send(WsMessage::Close(frame)).await;
+                let _ = client.send(WsMessage::Close(frame)).await;
                 break;
             }
         }
     }
 
+    if let Some(error) = &session_error {
+        summary.log_error(&peer_id, &display_name, error);
+    }
+
     // Remove peer from shared state on disconnect and notify others
     {
         let mut peers_guard = peers.lock().await;
-        peers_guard.remove(&peer_id);
-        println!("[SERVER] Peer disconnected: {} ({})", display_name, peer_id);
-        
-        // Broadcast \"peer_left\" notification to all remaining peers
-        let mut leave_data = std::collections::HashMap::new();
-        leave_data.insert("peerId".to_string(), peer_id.clone());
-        leave_data.insert("displayName".to_string(), display_name.clone());
-        leave_data.insert("message".to_string(), format!("{} left", display_name));
-
-        let leave_notification = Envelope {
-            event: "notification".to_string(),
-            event_data: Some(EventData {
-                method: "peer_left".to_string(),
-                data: leave_data,
-            }),
-        };
-        
-        for (id, peer) in peers_guard.iter() {
+        // The same peerId may already be connected again; that entry isn't ours
+        let still_ours = peers_guard.get(&peer_id).is_some_and(|peer| peer.join_seq == join_seq);
+        match state.config.current().reconnect_grace() {
+            _ if !still_ours => {
+                sampled!(info, "Peer disconnected (already replaced): {} ({})", display_name, peer_id);
+            }
+            Some(grace) => {
+                if let Some(peer) = peers_guard.get_mut(&peer_id) {
+                    peer.connection_state = ConnectionState::Reconnecting;
+                    let offline_limit = state.config.current().offline_queue_max_messages;
+                    if offline_limit > 0 {
+                        peer.sender.offline.start(offline_limit);
+                    }
+                }
+                sampled!(
+                    info,
+                    "Peer disconnected: {} ({}), holding its presence for {:?}",
+                    display_name, peer_id, grace
+                );
+                let expiry =
+                    expire_reconnect_grace(state.clone(), peer_id.clone(), display_name.clone(), room.clone(), join_seq, grace);
+                tokio::spawn(expiry.in_current_span());
+            }
+            None => {
+                if let Some(peer) = peers_guard.remove(&peer_id) {
+                    state.groups.remove_peer(&peer_id, &peer.groups);
+                }
+                sampled!(info, "Peer disconnected: {} ({})", display_name, peer_id);
+                announce_peer_left(&state, &peers_guard, &peer_id, &display_name, &room).await;
+            }
+        }
+    }
+
+    sampled!(info, "Client disconnected");
+}
+
+// After the reconnect grace period: if the peer didn't come back, it's gone
+async fn expire_reconnect_grace(
+    state: AppState,
+    peer_id: String,
+    display_name: String,
+    room: String,
+    join_seq: u64,
+    grace: Duration,
+) {
+    tokio::time::sleep(grace).await;
+    let mut peers_guard = state.peers.lock().await;
+    let still_away = peers_guard
+        .get(&peer_id)
+        .is_some_and(|peer| peer.join_seq == join_seq && peer.connection_state == ConnectionState::Reconnecting);
+    if still_away {
+        if let Some(peer) = peers_guard.remove(&peer_id) {
+            state.groups.remove_peer(&peer_id, &peer.groups);
+        }
+        info!("Reconnect grace period over for {} ({})", display_name, peer_id);
+        announce_peer_left(&state, &peers_guard, &peer_id, &display_name, &room).await;
+    }
+}
+
+// A peer is gone for good: remembered for away summaries, then announced
+async fn announce_peer_left(state: &AppState, peers: &HashMap<String, Peer>, peer_id: &str, display_name: &str, room: &str) {
+    state.away.record_left(peer_id, display_name, room, state.history.last_issued_id());
+    broadcast_peer_left(state, peers, peer_id, display_name, room).await;
+}
+
+// Broadcast "peer_left" to the remaining peers that get presence for it
+// (or queue it for the next presence_update when coalescing)
+async fn broadcast_peer_left(state: &AppState, peers: &HashMap<String, Peer>, peer_id: &str, display_name: &str, room: &str) {
+    if let Some(window) = state.config.current().leave_coalesce_window() {
+        presence::queue_change(state, "left", peer_id, display_name, room, window);
+        return;
+    }
+
+    let mut leave_data = HashMap::new();
+    leave_data.insert("peerId".to_string(), peer_id.to_string());
+    leave_data.insert("displayName".to_string(), display_name.to_string());
+    leave_data.insert("message".to_string(), format!("{} left", display_name));
+    if let Some(expires_at) = state.config.current().presence_expires_at() {
+        leave_data.insert("expiresAt".to_string(), expires_at.to_string());
+    }
+
+    let leave_notification = notification("peer_left", leave_data);
+    state.shadow.observe(&leave_notification);
+
+    let cross_room = state.config.current().cross_room_presence_subscriptions;
+    for (id, peer) in peers.iter() {
+        if presence::receives_presence_of(peer, peer_id, room, cross_room) {
             let ctx = format!("leave_notification → {}", id);
-            send_server_message(&peer.sender, &leave_notification, &ctx).await;
+            queue_server_message(&peer.sender, &leave_notification, &ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestClient, TestServer};
+
+    const QUIET: Duration = Duration::from_millis(300);
+
+    fn peer_ids(chunk: &EventData) -> Vec<&str> {
+        chunk.items.iter().filter_map(|item| item.data.get("peerId").map(String::as_str)).collect()
+    }
+
+    #[tokio::test]
+    async fn chat_messages_stay_in_their_room() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut amy = server.join("amy", "red").await;
+        let mut bob = server.join("bob", "blue").await;
+
+        ann.request("chat_message", &[("text", "red only")]).await;
+        let message = amy.expect("chat_message").await;
+        assert_eq!(message.data.get("text").map(String::as_str), Some("red only"));
+        bob.expect_no("chat_message", QUIET).await;
+
+        // Direct messages can't cross rooms either
+        bob.request("chat_message", &[("text", "psst"), ("toPeerId", "ann")]).await;
+        let error = bob.expect("error").await;
+        assert_eq!(error.data.get("code").map(String::as_str), Some("peer_offline"));
+        ann.expect_no("chat_message", QUIET).await;
+    }
+
+    #[tokio::test]
+    async fn joins_and_leaves_stay_in_their_room() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut bob = server.join("bob", "blue").await;
+        let mut amy = server.join("amy", "red").await;
+
+        let ann = server.join("ann", "red").await;
+        amy.expect("peer_joined").await;
+        bob.expect_no("peer_joined", QUIET).await;
+
+        drop(ann);
+        amy.expect("peer_left").await;
+        bob.expect_no("peer_left", QUIET).await;
+    }
+
+    #[tokio::test]
+    async fn peer_list_only_shows_the_same_room() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let _ann = server.join("ann", "red").await;
+        let _bob = server.join("bob", "blue").await;
+
+        let mut amy = server.connect("peerId=amy&room=red").await;
+        let roster = amy.expect("peer_list_chunk").await;
+        assert_eq!(peer_ids(&roster), vec!["ann"]);
+
+        amy.request("list_peers", &[]).await;
+        let roster = amy.expect("peer_list_chunk").await;
+        assert_eq!(peer_ids(&roster), vec!["amy", "ann"]);
+    }
+
+    // `sender` (connected as `from`) chats `text`: each of `others` gets it
+    // exactly once, `from` doesn't get it back
+    async fn assert_broadcast(from: &mut TestClient, sender: &str, others: [&mut TestClient; 2], text: &str) {
+        from.request("chat_message", &[("text", text)]).await;
+        for other in others {
+            let message = other.expect("chat_message").await;
+            assert_eq!(message.data.get("text").map(String::as_str), Some(text));
+            assert_eq!(message.data.get("fromPeerId").map(String::as_str), Some(sender));
+            assert_eq!(message.data.get("fromDisplayName").map(String::as_str), Some(sender));
+            other.expect_no("chat_message", QUIET).await;
+        }
+        from.expect_no("chat_message", QUIET).await;
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_everyone_but_the_sender() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        let mut cat = server.join("cat", "red").await;
+
+        assert_broadcast(&mut ann, "ann", [&mut bob, &mut cat], "from ann").await;
+        assert_broadcast(&mut bob, "bob", [&mut ann, &mut cat], "from bob").await;
+    }
+
+    // Many rounds of peers joining and leaving, some abruptly and some with a
+    // Close, while another peer broadcasts as fast as it can
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn broadcasts_survive_peers_churning() {
+        use tokio_tungstenite::tungstenite::Message;
+        const CHURNERS: usize = 8;
+        const ROUNDS: usize = 50;
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut zed = server.join("zed", "red").await;
+
+        let churn = |n: usize| {
+            let server = &server;
+            async move {
+                for round in 0..ROUNDS {
+                    let mut client = server.join(&format!("churner_{}_{}", n, round), "red").await;
+                    if round % 2 == 0 {
+                        client.request("chat_message", &[("text", "hi")]).await;
+                        client.send(Message::Close(None)).await;
+                    }
+                }
+            }
+        };
+        let broadcast = async {
+            for i in 0..2000 {
+                ann.request("chat_message", &[("text", &i.to_string())]).await;
+            }
+        };
+        let everything = async {
+            tokio::join!(futures_util::future::join_all((0..CHURNERS).map(churn)), broadcast);
+        };
+        tokio::time::timeout(Duration::from_secs(60), everything).await.expect("broadcast and churn deadlocked");
+
+        // Still serving: a message gets through after all that
+        ann.request("chat_message", &[("text", "done")]).await;
+        loop {
+            if zed.expect("chat_message").await.data["text"] == "done" {
+                break;
+            }
+        }
+
+        // Every connection was cleaned up, none left behind by a failed handler
+        drop((ann, zed));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let left: Vec<String> = server.state.peers.lock().await.keys().cloned().collect();
+            if left.is_empty() {
+                break;
+            }
+            assert!(tokio::time::Instant::now() < deadline, "peers left behind: {:?}", left);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_every_connection() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut readers = Vec::new();
+        for peer_id in ["ann", "bob", "cat"] {
+            let mut client = server.join(peer_id, "red").await;
+            readers.push(tokio::spawn(async move {
+                let notice = client.expect("system").await;
+                (notice, client.expect_close().await)
+            }));
+        }
+
+        assert!(server.shut_down(Duration::from_secs(10)).await, "the server task did not finish");
+        for reader in readers {
+            let (notice, close) = reader.await.expect("reader task");
+            assert!(notice.data.get("message").is_some_and(|message| message.contains("shutting down")));
+            assert_eq!(close.map(|frame| u16::from(frame.code)), Some(close_code::AWAY));
+        }
+    }
+
+    #[test]
+    fn payload_size_counts_data_and_item_keys_and_values() {
+        let mut event = EventData { method: "chat_message".to_string(), ..Default::default() };
+        event.data.insert("text".to_string(), "hello".to_string());
+        event.items.push(DataItem { data: HashMap::from([("k".to_string(), "vv".to_string())]) });
+        assert_eq!(payload_size(&event), 4 + 5 + 1 + 2);
+    }
+
+    #[tokio::test]
+    async fn each_method_is_held_to_its_size_limit() {
+        let config = ServerConfig::default();
+        let limits = config.method_size_limits.clone();
+        let server = TestServer::start(config).await;
+        let mut ann = server.join("ann", "red").await;
+        let _bob = server.join("bob", "red").await;
+
+        for (method, limit) in limits {
+            // "text" + the padding makes exactly `size` bytes
+            let padded = |size: usize| "a".repeat(size - "text".len());
+            ann.request(&method, &[("text", &padded(limit))]).await;
+            // room_stats always answers, so everything before it was handled
+            ann.request("room_stats", &[]).await;
+            loop {
+                let event = ann.next_event(QUIET * 10).await.expect("a reply");
+                match event.method.as_str() {
+                    "room_stats" => break,
+                    "error" => assert_ne!(event.data["code"], "payload_too_large", "{} at its limit", method),
+                    _ => {}
+                }
+            }
+
+            ann.request(&method, &[("text", &padded(limit + 1))]).await;
+            let error = ann.expect("error").await;
+            assert_eq!(error.data["code"], "payload_too_large", "{} over its limit", method);
+            let expected = format!("{} payload of {} bytes exceeds the {} byte limit", method, limit + 1, limit);
+            assert_eq!(error.data["message"], expected);
         }
     }
 
-    println!("[SERVER] Client disconnected");
+    #[tokio::test]
+    async fn configured_limits_apply_to_any_method() {
+        let config = ServerConfig { method_size_limits: HashMap::from([("typing".to_string(), 8)]), ..Default::default() };
+        let server = TestServer::start(config).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+
+        ann.request("typing", &[("k", "1234567")]).await;
+        bob.expect("typing").await;
+        ann.request("typing", &[("k", "12345678")]).await;
+        assert_eq!(ann.expect("error").await.data["code"], "payload_too_large");
+        bob.expect_no("typing", QUIET).await;
+        // Unlisted methods are only bound by the frame size
+        ann.request("chat_message", &[("text", &"a".repeat(10_000))]).await;
+        bob.expect("chat_message").await;
+    }
+
+    #[tokio::test]
+    async fn rapid_messages_are_grouped_and_spaced_ones_are_not() {
+        let server = TestServer::start(ServerConfig { message_grouping_window_ms: 200, ..Default::default() }).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        let mut cat = server.join("cat", "red").await;
+        let grouped = |event: &EventData| event.data.get("groupedWithPrevious").map(String::as_str) == Some("true");
+
+        ann.request("chat_message", &[("text", "one")]).await;
+        ann.request("chat_message", &[("text", "two")]).await;
+        assert!(!grouped(&bob.expect("chat_message").await));
+        assert!(grouped(&bob.expect("chat_message").await));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        ann.request("chat_message", &[("text", "later")]).await;
+        assert!(!grouped(&bob.expect("chat_message").await));
+
+        // Someone else speaking in between ends the group
+        cat.request("chat_message", &[("text", "interrupting")]).await;
+        bob.expect("chat_message").await;
+        ann.request("chat_message", &[("text", "again")]).await;
+        assert!(!grouped(&bob.expect("chat_message").await));
+    }
+
+    #[tokio::test]
+    async fn grouping_is_off_by_default() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        for text in ["one", "two"] {
+            ann.request("chat_message", &[("text", text)]).await;
+            assert_eq!(bob.expect("chat_message").await.data.get("groupedWithPrevious"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_gets_a_clean_error() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+        use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+
+        // A chat_message Envelope whose text field holds 0xFF: field 3
+        // (event_data), then field 2 (data) entry "text" => 0xFF
+        let mut request = Envelope {
+            event: "request".to_string(),
+            event_data: Some(EventData { method: "chat_message".to_string(), ..Default::default() }),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let text_entry = [0x0a, 0x04, b't', b'e', b'x', b't', 0x12, 0x01, 0xff];
+        let event_data_entry = [&[0x12, text_entry.len() as u8][..], &text_entry].concat();
+        request.extend([&[0x1a, event_data_entry.len() as u8][..], &event_data_entry].concat());
+        ann.send(Message::Binary(request)).await;
+        let error = ann.expect("error").await;
+        assert_eq!(error.data["code"], "invalid_message");
+        assert!(error.data["message"].contains("UTF-8"), "{}", error.data["message"]);
+        bob.expect_no("chat_message", QUIET).await;
+
+        // A text frame that isn't UTF-8 closes the connection with 1007
+        ann.send(Message::Frame(Frame::message(vec![b'{', 0xff, b'}'], OpCode::Data(Data::Text), true))).await;
+        let close = ann.expect_close().await.expect("a close frame");
+        assert_eq!(u16::from(close.code), 1007);
+    }
+
+    #[tokio::test]
+    async fn idle_peers_are_warned_then_disconnected() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let config = ServerConfig { idle_timeout_secs: 2, idle_warning_secs: 1, ..Default::default() };
+        let server = TestServer::start(config).await;
+        let joined = tokio::time::Instant::now();
+        let mut ann = server.join("ann", "red").await;
+
+        let warning = ann.expect("system").await;
+        assert_eq!(warning.data["message"], "You'll be disconnected in 1s for inactivity");
+        // Any frame counts as activity and restarts both timers
+        ann.send(Message::Text(r#"{"cmd":"ping"}"#.to_string())).await;
+        let active = tokio::time::Instant::now();
+
+        let warning = ann.expect("system").await;
+        assert!(warning.data["message"].contains("1s for inactivity"));
+        assert!(active.elapsed() >= Duration::from_millis(900), "warned again {:?} after activity", active.elapsed());
+        assert_eq!(ann.expect("system").await.data["message"], "Disconnected for inactivity");
+        let close = ann.expect_close().await.expect("a close frame");
+        assert_eq!(close.reason, "idle timeout");
+        // Later than the first deadline, which the activity pushed back
+        assert!(joined.elapsed() >= Duration::from_secs(3), "closed after {:?}", joined.elapsed());
+    }
+
+    #[tokio::test]
+    async fn plain_http_to_ws_gets_426_with_json() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = TestServer::start(ServerConfig::default()).await;
+        let (status, body) = server.http(axum::http::Method::GET, "/ws", None, None).await;
+        assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(body["error"]["code"], "upgrade_required");
+        assert_eq!(body["example"], "websocat 'ws://localhost/ws?displayName=Alice&room=lobby'");
+
+        // As curl sees it, headers included
+        let mut stream = tokio::net::TcpStream::connect(server.addr).await.expect("connect");
+        let request = format!("GET /ws HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", server.addr);
+        stream.write_all(request.as_bytes()).await.expect("send request");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.expect("read response");
+        let (head, body) = response.split_once("\r\n\r\n").expect("headers and body");
+        let head = head.to_ascii_lowercase();
+        assert!(head.starts_with("http/1.1 426"), "{}", head);
+        assert!(head.contains("upgrade: websocket"), "{}", head);
+        assert!(head.contains("content-type: application/json"), "{}", head);
+        let body: serde_json::Value = serde_json::from_str(body).expect("JSON body");
+        assert_eq!(body["example"], format!("websocat 'ws://{}/ws?displayName=Alice&room=lobby'", server.addr));
+    }
+
+    #[tokio::test]
+    async fn admin_api_and_websockets_share_one_port() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let admin = "admin-secret";
+        let config = ServerConfig { admin_token: Some(config::Secret::new(admin)), ..Default::default() };
+        let server = TestServer::start(config).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+
+        // Over the listener itself, not through the router, while both sockets are open
+        let mut stream = tokio::net::TcpStream::connect(server.addr).await.expect("connect");
+        let request = format!(
+            "GET /api/peers HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+            server.addr, admin
+        );
+        stream.write_all(request.as_bytes()).await.expect("send request");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.expect("read response");
+        let (head, body) = response.split_once("\r\n\r\n").expect("headers and body");
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let body: serde_json::Value = serde_json::from_str(body).expect("JSON body");
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["peers"][0]["peerId"], "ann");
+
+        // The sockets still work after the HTTP request
+        ann.request("chat_message", &[("text", "still here")]).await;
+        assert_eq!(bob.expect("chat_message").await.data["text"], "still here");
+    }
 }
+
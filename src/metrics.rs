@@ -0,0 +1,133 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds (microseconds) of the latency histogram buckets.
+// Anything slower lands in the final overflow bucket.
+const LATENCY_BUCKETS_US: [u64; 14] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000,
+];
+
+// Process-wide counters, shared through AppState
+#[derive(Default)]
+pub struct Metrics {
+    // Peers disconnected because their outbound queue stayed too deep
+    pub slow_client_evictions: AtomicU64,
+    // Peers disconnected for sending too many unsolicited pongs
+    pub pong_flood_disconnects: AtomicU64,
+    // Peers disconnected for not answering a heartbeat ping in time
+    pub heartbeat_timeouts: AtomicU64,
+    // Peers disconnected for sending too many oversized frames
+    pub oversized_frame_disconnects: AtomicU64,
+    // Receipt -> end of fan-out, keyed by recipient-count bucket.
+    // std Mutex: only held for a few arithmetic ops, never across .await
+    fanout_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    pub fn incr(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Time from reading a message off the socket until it was sent to all recipients
+    pub fn record_fanout(&self, recipients: usize, elapsed: Duration) {
+        let mut histograms = self.fanout_latency.lock().unwrap_or_else(|e| e.into_inner());
+        histograms
+            .entry(size_bucket(recipients))
+            .or_default()
+            .record(elapsed.as_micros() as u64);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let histograms = self.fanout_latency.lock().unwrap_or_else(|e| e.into_inner());
+        MetricsSnapshot {
+            slow_client_evictions: self.slow_client_evictions.load(Ordering::Relaxed),
+            pong_flood_disconnects: self.pong_flood_disconnects.load(Ordering::Relaxed),
+            heartbeat_timeouts: self.heartbeat_timeouts.load(Ordering::Relaxed),
+            oversized_frame_disconnects: self.oversized_frame_disconnects.load(Ordering::Relaxed),
+            fanout_latency: histograms
+                .iter()
+                .map(|(bucket, histogram)| histogram.summary(bucket))
+                .collect(),
+        }
+    }
+}
+
+// Groups fan-outs by how many peers they reached, to show how latency scales
+fn size_bucket(recipients: usize) -> &'static str {
+    match recipients {
+        0 => "0",
+        1 => "1",
+        2..=10 => "2-10",
+        11..=100 => "11-100",
+        101..=1000 => "101-1000",
+        _ => "1000+",
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; LATENCY_BUCKETS_US.len() + 1],
+    total: u64,
+    sum_us: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, micros: u64) {
+        let index = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.counts[index] += 1;
+        self.total += 1;
+        self.sum_us += micros;
+    }
+
+    // Percentiles are reported as the upper bound of the bucket they fall in
+    fn percentile_us(&self, percentile: f64) -> u64 {
+        let rank = ((self.total as f64) * percentile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_US.get(index).copied().unwrap_or(u64::MAX);
+            }
+        }
+        u64::MAX
+    }
+
+    fn summary(&self, room_size: &'static str) -> LatencySummary {
+        LatencySummary {
+            room_size,
+            count: self.total,
+            mean_us: self.sum_us / self.total.max(1),
+            p50_us: self.percentile_us(0.50),
+            p95_us: self.percentile_us(0.95),
+            p99_us: self.percentile_us(0.99),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    slow_client_evictions: u64,
+    pong_flood_disconnects: u64,
+    heartbeat_timeouts: u64,
+    oversized_frame_disconnects: u64,
+    fanout_latency: Vec<LatencySummary>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LatencySummary {
+    room_size: &'static str,
+    count: u64,
+    mean_us: u64,
+    p50_us: u64,
+    p95_us: u64,
+    p99_us: u64,
+}
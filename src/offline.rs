@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use axum::extract::ws::Message as WsMessage;
+
+use crate::priority::Priority;
+use crate::{notification, queue_server_message, Client};
+
+// Offline queue for flaky (mobile) clients, on top of reconnect_grace_secs.
+//
+// While a peer is within its grace period, what the server would have sent
+// it (chat, direct and group messages, presence, announcements, ...) is kept
+// instead of going to the dead socket, up to offline_queue_max_messages
+// frames. If the same peerId comes back in time, the kept frames are queued
+// for it first, in order, then an "offline_messages" {delivered, dropped}
+// notification; after that the connection carries on as usual. If it doesn't
+// come back, the queue goes away with the peer.
+//
+// Only data frames are kept: a Close meant for the old socket (e.g. a kick)
+// must not close the new one. Frames are kept as encoded for the old
+// connection, so coming back with another encoding (?caps=json or not)
+// drops them all. A client that also resumes with resume_from_seq gets
+// missed chat messages from both, so use one or the other.
+#[derive(Default)]
+pub struct OfflineQueue {
+    // std Mutex: never held across .await. None = not buffering.
+    state: Mutex<Option<Buffered>>,
+}
+
+#[derive(Default)]
+pub struct Buffered {
+    frames: Vec<WsMessage>,
+    limit: usize,
+    dropped: usize,
+}
+
+impl OfflineQueue {
+    // From now on data frames are kept here, up to `limit`
+    pub fn start(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = Some(Buffered { limit, ..Default::default() });
+    }
+
+    // None when not buffering: the frame should go to the socket as usual.
+    // Otherwise whether it was kept (false = over the limit, or not data).
+    pub fn push(&self, frame: &WsMessage) -> Option<bool> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let buffered = state.as_mut()?;
+        let is_data = matches!(frame, WsMessage::Text(_) | WsMessage::Binary(_));
+        if !is_data {
+            return Some(false);
+        }
+        if buffered.frames.len() >= buffered.limit {
+            buffered.dropped += 1;
+            return Some(false);
+        }
+        buffered.frames.push(frame.clone());
+        Some(true)
+    }
+
+    // Stops buffering and hands over what was kept
+    pub fn take(&self) -> Option<Buffered> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+// Queues what a resumed peer missed while away, oldest first.
+// Called under the peers lock (see handle_socket), so live traffic can't
+// overtake it; it only queues, so a client that doesn't read can't hold the
+// lock. Frames that don't fit in the outbound lane count as dropped.
+pub fn flush(client: &Client, mut buffered: Buffered, same_encoding: bool) {
+    if !same_encoding {
+        buffered.dropped += buffered.frames.len();
+        buffered.frames.clear();
+    }
+    let mut delivered = 0;
+    for frame in buffered.frames {
+        if client.try_send_with_priority(frame, Priority::Normal) {
+            delivered += 1;
+        } else {
+            buffered.dropped += 1;
+        }
+    }
+    if delivered == 0 && buffered.dropped == 0 {
+        return;
+    }
+    let mut data = HashMap::new();
+    data.insert("delivered".to_string(), delivered.to_string());
+    data.insert("dropped".to_string(), buffered.dropped.to_string());
+    queue_server_message(client, &notification("offline_messages", data), "offline_messages");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(body: &str) -> WsMessage {
+        WsMessage::Text(body.to_string())
+    }
+
+    #[test]
+    fn passes_frames_through_until_started() {
+        let queue = OfflineQueue::default();
+        assert_eq!(queue.push(&text("live")), None);
+        assert!(queue.take().is_none());
+    }
+
+    #[test]
+    fn keeps_data_frames_in_order_up_to_the_limit() {
+        let queue = OfflineQueue::default();
+        queue.start(2);
+        assert_eq!(queue.push(&text("first")), Some(true));
+        assert_eq!(queue.push(&WsMessage::Close(None)), Some(false));
+        assert_eq!(queue.push(&text("second")), Some(true));
+        assert_eq!(queue.push(&text("third")), Some(false));
+
+        let buffered = queue.take().expect("was buffering");
+        assert_eq!(buffered.frames, vec![text("first"), text("second")]);
+        assert_eq!(buffered.dropped, 1);
+        // Taking stops buffering
+        assert_eq!(queue.push(&text("live")), None);
+    }
+}
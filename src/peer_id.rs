@@ -0,0 +1,245 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::{ConfigError, ServerConfig};
+
+// Validation rules for client-supplied peer ids, built once from the config.
+// Keeps odd ids (control chars, spaces, huge strings) out of the peers map,
+// logs and anything downstream that keys on peer_id.
+pub struct PeerIdRules {
+    min_len: usize,
+    max_len: usize,
+    // Allowed on top of ASCII letters and digits
+    extra_chars: String,
+    // Optional regex the whole id must match
+    pattern: Option<Regex>,
+}
+
+impl PeerIdRules {
+    pub fn from_config(config: &ServerConfig) -> Result<Self, ConfigError> {
+        let pattern = match config.peer_id_pattern.as_deref() {
+            Some(raw) => {
+                // Anchor so the pattern has to match the whole id
+                let anchored = format!("^(?:{})$", raw);
+                Some(Regex::new(&anchored).map_err(|e| {
+                    ConfigError::Invalid(format!("peer_id_pattern '{}' is not a valid regex: {}", raw, e))
+                })?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            min_len: config.peer_id_min_len,
+            max_len: config.peer_id_max_len,
+            extra_chars: config.peer_id_extra_chars.clone(),
+            pattern,
+        })
+    }
+
+    // Err carries a message suitable for the 400 response
+    pub fn validate(&self, peer_id: &str) -> Result<(), String> {
+        let len = peer_id.chars().count();
+        if len < self.min_len || len > self.max_len {
+            return Err(format!(
+                "peerId must be {} to {} characters long",
+                self.min_len, self.max_len
+            ));
+        }
+        if let Some(bad) = peer_id
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !self.extra_chars.contains(*c))
+        {
+            return Err(format!(
+                "peerId contains '{}'; allowed are letters, digits and \"{}\"",
+                bad.escape_default(),
+                self.extra_chars
+            ));
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(peer_id) {
+                return Err(format!("peerId must match {}", pattern.as_str()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// How the server names a peer that connects without ?peerId
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerIdStrategy {
+    // "peer_" + the first 8 hex digits of a UUID, e.g. peer_1f3a9c2e
+    #[default]
+    ShortUuid,
+    // A full hyphenated UUID
+    Uuid,
+    // The configured prefix + a counter starting at 1, e.g. peer_7
+    Sequential,
+    // adjective-animal-number, e.g. brave-otter-42
+    RandomWords,
+    // No id is made up: the upgrade is rejected
+    ClientProvidedOnly,
+}
+
+// What happens when a client connects with the peerId of a peer that is
+// still connected. A peer within its reconnect grace period isn't
+// connected, so it is always taken over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePeerIdPolicy {
+    // The new connection gets a "peer_id_in_use" error and is closed
+    #[default]
+    RejectNew,
+    // The old connection is told why and closed; the new one takes its place
+    ReplaceOld,
+}
+
+const ADJECTIVES: &[&str] = &[
+    "brave", "calm", "clever", "eager", "fancy", "gentle", "happy", "jolly",
+    "kind", "lively", "lucky", "mighty", "nimble", "proud", "quick", "quiet",
+    "rapid", "shiny", "silly", "sleepy", "smart", "sunny", "swift", "tidy",
+    "wise", "witty", "bold", "bright", "cosy", "daring", "fuzzy", "merry",
+];
+
+const ANIMALS: &[&str] = &[
+    "otter", "badger", "beaver", "bison", "camel", "crane", "dingo", "eagle",
+    "falcon", "ferret", "gecko", "heron", "ibis", "koala", "lemur", "lynx",
+    "marmot", "moose", "newt", "ocelot", "panda", "puffin", "quail", "raven",
+    "seal", "sloth", "tapir", "toucan", "walrus", "wombat", "yak", "zebra",
+];
+
+// Makes ids for peers that didn't send one. Only the sequential counter is
+// state; the strategy and prefix are read from the live config per call.
+#[derive(Default)]
+pub struct PeerIdGenerator {
+    next_seq: AtomicU64,
+}
+
+impl PeerIdGenerator {
+    // None under client_provided_only. `taken` says whether an id is already
+    // in use (a client may have picked peer_3 itself); such ids are skipped.
+    pub fn generate(&self, config: &ServerConfig, taken: impl Fn(&str) -> bool) -> Option<String> {
+        loop {
+            let id = match config.peer_id_strategy {
+                PeerIdStrategy::ShortUuid => {
+                    let uuid = uuid::Uuid::new_v4().simple().to_string();
+                    format!("peer_{}", &uuid[..8])
+                }
+                PeerIdStrategy::Uuid => uuid::Uuid::new_v4().to_string(),
+                PeerIdStrategy::Sequential => {
+                    let seq = self.next_seq.fetch_add(1, Ordering::Relaxed) + 1;
+                    format!("{}{}", config.peer_id_sequential_prefix, seq)
+                }
+                PeerIdStrategy::RandomWords => random_words(),
+                PeerIdStrategy::ClientProvidedOnly => return None,
+            };
+            if !taken(&id) {
+                return Some(id);
+            }
+        }
+    }
+}
+
+// The UUID is only used as a source of random bits
+fn random_words() -> String {
+    let bytes = *uuid::Uuid::new_v4().as_bytes();
+    format!(
+        "{}-{}-{}",
+        ADJECTIVES[bytes[0] as usize % ADJECTIVES.len()],
+        ANIMALS[bytes[1] as usize % ANIMALS.len()],
+        bytes[2] % 100
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn config(strategy: PeerIdStrategy) -> ServerConfig {
+        ServerConfig { peer_id_strategy: strategy, ..Default::default() }
+    }
+
+    // `count` ids, none of them taken by an earlier one
+    fn generate_many(strategy: PeerIdStrategy, count: usize) -> Vec<String> {
+        let generator = PeerIdGenerator::default();
+        let config = config(strategy);
+        let mut taken = HashSet::new();
+        (0..count)
+            .map(|_| {
+                let id = generator.generate(&config, |id| taken.contains(id)).expect("an id");
+                taken.insert(id.clone());
+                id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_uuids_are_peer_and_eight_hex_digits() {
+        for id in generate_many(PeerIdStrategy::ShortUuid, 100) {
+            let hex = id.strip_prefix("peer_").expect("peer_ prefix");
+            assert_eq!(hex.len(), 8, "{}", id);
+            assert!(hex.chars().all(|c| c.is_ascii_hexdigit()), "{}", id);
+        }
+    }
+
+    #[test]
+    fn uuids_are_full_and_distinct() {
+        let ids = generate_many(PeerIdStrategy::Uuid, 100);
+        for id in &ids {
+            assert_eq!(uuid::Uuid::parse_str(id).expect("a UUID").hyphenated().to_string(), *id);
+        }
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+    }
+
+    #[test]
+    fn sequential_ids_count_up_with_the_prefix() {
+        let generator = PeerIdGenerator::default();
+        let config = ServerConfig { peer_id_sequential_prefix: "guest-".to_string(), ..config(PeerIdStrategy::Sequential) };
+        let ids: Vec<String> = (0..3).map(|_| generator.generate(&config, |_| false).expect("an id")).collect();
+        assert_eq!(ids, ["guest-1", "guest-2", "guest-3"]);
+    }
+
+    #[test]
+    fn sequential_ids_skip_ones_already_in_use() {
+        let generator = PeerIdGenerator::default();
+        let config = config(PeerIdStrategy::Sequential);
+        // A client picked peer_1 and peer_2 itself
+        let connected = HashSet::from(["peer_1", "peer_2"]);
+        let id = generator.generate(&config, |id| connected.contains(id)).expect("an id");
+        assert_eq!(id, "peer_3");
+    }
+
+    #[test]
+    fn random_words_are_adjective_animal_number() {
+        let ids = generate_many(PeerIdStrategy::RandomWords, 200);
+        for id in &ids {
+            let parts: Vec<&str> = id.split('-').collect();
+            assert_eq!(parts.len(), 3, "{}", id);
+            assert!(ADJECTIVES.contains(&parts[0]), "{}", id);
+            assert!(ANIMALS.contains(&parts[1]), "{}", id);
+            assert!(parts[2].parse::<u8>().is_ok_and(|number| number < 100), "{}", id);
+        }
+        // Only ~100k combinations, so uniqueness comes from skipping taken ones
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+    }
+
+    #[test]
+    fn client_provided_only_makes_up_nothing() {
+        let generator = PeerIdGenerator::default();
+        assert_eq!(generator.generate(&config(PeerIdStrategy::ClientProvidedOnly), |_| false), None);
+    }
+
+    #[test]
+    fn generated_ids_pass_the_default_rules() {
+        let config = ServerConfig::default();
+        let rules = PeerIdRules::from_config(&config).expect("valid rules");
+        let strategies = [PeerIdStrategy::ShortUuid, PeerIdStrategy::Uuid, PeerIdStrategy::Sequential, PeerIdStrategy::RandomWords];
+        for strategy in strategies {
+            for id in generate_many(strategy, 10) {
+                assert_eq!(rules.validate(&id), Ok(()), "{:?}: {}", strategy, id);
+            }
+        }
+    }
+}
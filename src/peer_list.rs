@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use crate::compression;
+use crate::generated::{DataItem, EventData};
+use crate::{notification_envelope, AppState, Client};
+
+// Streams the connected peers in `room` to one client as a series of "peer_list_chunk"
+// notifications instead of one unbounded frame. Each chunk carries up to
+// peer_list_chunk_size items ({peerId, displayName, plus appVersion and
+// outdatedClient=true when known, see client_version.rs}) and
+// data = {chunk, total, last}. Clients append items until last == "true".
+// An empty list is still one (empty, last) chunk.
+// Sent on request ("list_peers") and once right after a peer registers,
+// before the others are told it joined (excluding the peer itself).
+pub async fn send_peer_list(state: &AppState, client: &Client, room: &str, exclude_peer_id: Option<&str>) {
+    // Snapshot under the lock, send after releasing it
+    let mut peers: Vec<DataItem> = {
+        let peers_guard = state.peers.lock().await;
+        peers_guard
+            .values()
+            .filter(|peer| peer.room == room && Some(peer.peer_id.as_str()) != exclude_peer_id)
+            .map(|peer| {
+                let mut data = HashMap::new();
+                data.insert("peerId".to_string(), peer.peer_id.clone());
+                data.insert("displayName".to_string(), peer.display_name.clone());
+                if let Some(version) = &peer.app_version {
+                    data.insert("appVersion".to_string(), version.to_string());
+                }
+                if peer.outdated_client {
+                    data.insert("outdatedClient".to_string(), "true".to_string());
+                }
+                DataItem { data }
+            })
+            .collect()
+    };
+    peers.sort_by(|a, b| a.data.get("peerId").cmp(&b.data.get("peerId")));
+
+    send_chunked(state, client, "peer_list_chunk", peers).await;
+}
+
+// Sends `items` as `method` notifications of up to peer_list_chunk_size
+// items each, with data = {chunk, total, last} as described above. Also used
+// for the room list (see rooms.rs). Chunks may go out deflated, see compression.rs.
+pub async fn send_chunked(state: &AppState, client: &Client, method: &str, items: Vec<DataItem>) {
+    let total = items.len();
+    let config = state.config.current();
+    let chunk_size = config.peer_list_chunk_size.max(1);
+    let chunks: Vec<Vec<DataItem>> = if items.is_empty() {
+        vec![Vec::new()]
+    } else {
+        items.chunks(chunk_size).map(<[DataItem]>::to_vec).collect()
+    };
+    let chunk_count = chunks.len();
+
+    for (index, items) in chunks.into_iter().enumerate() {
+        let mut data = HashMap::new();
+        data.insert("chunk".to_string(), index.to_string());
+        data.insert("total".to_string(), total.to_string());
+        data.insert("last".to_string(), (index + 1 == chunk_count).to_string());
+        let chunk = notification_envelope(EventData {
+            method: method.to_string(),
+            data,
+            items,
+            payload: Vec::new(),
+            checksum: Vec::new(),
+        });
+        if !compression::send_snapshot(client, &chunk, method, config.snapshot_compression_min_bytes).await {
+            break;
+        }
+    }
+}
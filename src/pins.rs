@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use crate::generated::Envelope;
+use crate::history::{self, HistoryEntry};
+use crate::logging::sampled;
+use crate::rooms::{Pin, PinRefusal};
+use crate::{
+    error_notification, notification, notification_envelope, queue_server_message, send_server_message, AppState, Client,
+    ConnectionState,
+};
+
+// Pinned messages: "pin_message" {messageId} keeps a copy of a chat message
+// in the room's state (see rooms.rs) and tells everyone in the room, the
+// pinner included, with "message_pinned": the message as it was delivered
+// (chat_message's data and items) plus pinnedBy. "unpin_message" {messageId}
+// undoes it with "message_unpinned" {messageId, unpinnedBy}.
+//
+// A peer joining the room gets each current pin as a message_pinned with
+// snapshot=true, oldest first, right after the peer list. Pins are changed
+// under the peers lock, so a pin made while someone joins reaches them
+// either live or in the snapshot (or both).
+//
+// Only messages still in the chat history can be pinned, and only ones the
+// whole room saw (not direct messages). At most max_pinned_messages per room
+// (0 = pinning is off). Admins can limit who pins per room with
+// PUT /api/rooms/{room} {"pinners": [...]}; by default anyone in it can.
+// Refusals are "error" notifications: pin_not_allowed, message_not_found,
+// already_pinned, pin_limit_reached, not_pinned.
+pub async fn handle_pin(
+    state: &AppState,
+    peer_id: &str,
+    room: &str,
+    client: &Client,
+    method: &str,
+    data: &HashMap<String, String>,
+) {
+    let Some(message_id) = data.get("messageId").and_then(|id| id.parse::<u64>().ok()) else {
+        let reply = error_notification("message_not_found", &format!("{} requires a numeric messageId", method));
+        send_server_message(client, &reply, method).await;
+        return;
+    };
+    let limit = state.config.current().max_pinned_messages;
+    if limit == 0 || !state.rooms.may_pin(room, peer_id) {
+        let reply = error_notification("pin_not_allowed", "You can't pin messages in this room");
+        send_server_message(client, &reply, method).await;
+        return;
+    }
+
+    let peers_guard = state.peers.lock().await;
+    let outcome = if method == "pin_message" {
+        pin(state, peer_id, room, message_id, limit)
+    } else {
+        unpin(state, peer_id, room, message_id)
+    };
+    let event = match outcome {
+        Ok(event) => event,
+        Err((code, message)) => {
+            drop(peers_guard);
+            send_server_message(client, &error_notification(code, &message), method).await;
+            return;
+        }
+    };
+    for peer in peers_guard.values() {
+        if peer.room == room && peer.connection_state == ConnectionState::Connected {
+            queue_server_message(&peer.sender, &event, method);
+        }
+    }
+    drop(peers_guard);
+    state.shadow.observe(&event);
+    sampled!(info, "{} {} in room {} by {}", method, message_id, room, peer_id);
+}
+
+// Err is the error notification's code and message
+type Outcome = Result<Envelope, (&'static str, String)>;
+
+fn pin(state: &AppState, peer_id: &str, room: &str, message_id: u64, limit: usize) -> Outcome {
+    let seen_by_room = |entry: &HistoryEntry| entry.room == room && entry.to_peer_id.is_none();
+    let Some(entry) = state.history.get(message_id).filter(seen_by_room) else {
+        return Err(("message_not_found", format!("Message {} is not in this room's history", message_id)));
+    };
+    let pin = Pin { entry, pinned_by: peer_id.to_string() };
+    match state.rooms.pin(room, pin.clone(), limit) {
+        Ok(()) => Ok(pinned_event(pin, false)),
+        Err(PinRefusal::AlreadyPinned) => Err(("already_pinned", format!("Message {} is already pinned", message_id))),
+        Err(PinRefusal::LimitReached) => {
+            Err(("pin_limit_reached", format!("This room already has {} pinned messages", limit)))
+        }
+    }
+}
+
+fn unpin(state: &AppState, peer_id: &str, room: &str, message_id: u64) -> Outcome {
+    if !state.rooms.unpin(room, message_id) {
+        return Err(("not_pinned", format!("Message {} is not pinned", message_id)));
+    }
+    let mut data = HashMap::new();
+    data.insert("messageId".to_string(), message_id.to_string());
+    data.insert("unpinnedBy".to_string(), peer_id.to_string());
+    Ok(notification("message_unpinned", data))
+}
+
+// The join snapshot, see above
+pub async fn send_pins(state: &AppState, client: &Client, room: &str) {
+    for pin in state.rooms.pins(room) {
+        if !send_server_message(client, &pinned_event(pin, true), "pinned_snapshot").await {
+            return;
+        }
+    }
+}
+
+fn pinned_event(pin: Pin, snapshot: bool) -> Envelope {
+    let mut event = history::chat_event(pin.entry);
+    event.method = "message_pinned".to_string();
+    event.data.insert("pinnedBy".to_string(), pin.pinned_by);
+    if snapshot {
+        event.data.insert("snapshot".to_string(), "true".to_string());
+    }
+    notification_envelope(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Secret, ServerConfig};
+    use crate::testing::{TestClient, TestServer};
+    use axum::http::Method;
+    use serde_json::json;
+    use std::time::Duration;
+
+    // Sends a chat message from `from` and returns its messageId, as `to` saw it
+    async fn chat(from: &mut TestClient, to: &mut TestClient, text: &str) -> String {
+        from.request("chat_message", &[("text", text)]).await;
+        to.expect("chat_message").await.data["messageId"].clone()
+    }
+
+    async fn error_code(client: &mut TestClient) -> String {
+        client.expect("error").await.data["code"].clone()
+    }
+
+    #[tokio::test]
+    async fn pins_reach_the_room_and_late_joiners() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        let message_id = chat(&mut ann, &mut bob, "Agenda: ship it").await;
+
+        bob.request("pin_message", &[("messageId", &message_id)]).await;
+        for client in [&mut ann, &mut bob] {
+            let pinned = client.expect("message_pinned").await;
+            assert_eq!(pinned.data["messageId"], message_id);
+            assert_eq!(pinned.data["text"], "Agenda: ship it");
+            assert_eq!(pinned.data["fromPeerId"], "ann");
+            assert_eq!(pinned.data["pinnedBy"], "bob");
+        }
+
+        let mut cat = server.join("cat", "red").await;
+        let snapshot = cat.expect("message_pinned").await;
+        assert_eq!(snapshot.data["messageId"], message_id);
+        assert_eq!(snapshot.data["snapshot"], "true");
+
+        ann.request("unpin_message", &[("messageId", &message_id)]).await;
+        for client in [&mut ann, &mut bob, &mut cat] {
+            let unpinned = client.expect("message_unpinned").await;
+            assert_eq!(unpinned.data["messageId"], message_id);
+            assert_eq!(unpinned.data["unpinnedBy"], "ann");
+        }
+        let mut dan = server.join("dan", "red").await;
+        dan.expect_no("message_pinned", Duration::from_millis(300)).await;
+    }
+
+    #[tokio::test]
+    async fn pins_are_limited_per_room() {
+        let server = TestServer::start(ServerConfig { max_pinned_messages: 1, ..Default::default() }).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        let first = chat(&mut ann, &mut bob, "one").await;
+        let second = chat(&mut ann, &mut bob, "two").await;
+
+        ann.request("pin_message", &[("messageId", &first)]).await;
+        ann.expect("message_pinned").await;
+        ann.request("pin_message", &[("messageId", &first)]).await;
+        assert_eq!(error_code(&mut ann).await, "already_pinned");
+        ann.request("pin_message", &[("messageId", &second)]).await;
+        assert_eq!(error_code(&mut ann).await, "pin_limit_reached");
+        ann.request("unpin_message", &[("messageId", &second)]).await;
+        assert_eq!(error_code(&mut ann).await, "not_pinned");
+    }
+
+    #[tokio::test]
+    async fn only_messages_the_room_saw_can_be_pinned() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+        let mut cat = server.join("cat", "blue").await;
+        let mut dan = server.join("dan", "blue").await;
+        let other_room = chat(&mut cat, &mut dan, "elsewhere").await;
+        ann.request("chat_message", &[("text", "psst"), ("toPeerId", "bob")]).await;
+        let direct = bob.expect("chat_message").await.data["messageId"].clone();
+
+        for message_id in [other_room.as_str(), direct.as_str(), "999", "abc"] {
+            bob.request("pin_message", &[("messageId", message_id)]).await;
+            assert_eq!(error_code(&mut bob).await, "message_not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn admins_choose_who_may_pin() {
+        let admin = "admin-secret";
+        let server = TestServer::start(ServerConfig { admin_token: Some(Secret::new(admin)), ..Default::default() }).await;
+        let pinners = json!({"pinners": ["mod_*"]});
+        let (_, body) = server.http(Method::PUT, "/api/rooms/red", Some(admin), Some(pinners)).await;
+        assert_eq!(body["pinners"], json!(["mod_*"]));
+        let mut ann = server.join("ann", "red").await;
+        let mut moderator = server.join("mod_bob", "red").await;
+        let message_id = chat(&mut ann, &mut moderator, "rules").await;
+
+        ann.request("pin_message", &[("messageId", &message_id)]).await;
+        assert_eq!(error_code(&mut ann).await, "pin_not_allowed");
+        moderator.request("pin_message", &[("messageId", &message_id)]).await;
+        assert_eq!(ann.expect("message_pinned").await.data["pinnedBy"], "mod_bob");
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::info;
+
+use crate::generated::{DataItem, Envelope, EventData};
+use crate::logging::sampled;
+use crate::{
+    error_notification, notification, notification_envelope, queue_server_message, send_server_message, AppState, Client,
+    Peer,
+};
+
+// Should `peer` be told that `subject_peer_id` joined/left `subject_room`?
+// Everyone else in that room is. Peers in other rooms only are when
+// cross_room_presence_subscriptions is on and they subscribed to the subject.
+pub fn receives_presence_of(peer: &Peer, subject_peer_id: &str, subject_room: &str, cross_room: bool) -> bool {
+    if peer.peer_id == subject_peer_id {
+        return false;
+    }
+    let shares_scope = peer.room == subject_room;
+    shares_scope || (cross_room && peer.presence_subscriptions.contains(subject_peer_id))
+}
+
+// Handles "subscribe_presence" / "unsubscribe_presence" requests.
+// data.peerIds is a comma-separated list of peer ids.
+// Replies with "presence_subscriptions": the full list and which of them are online
+// (in the subscriber's room, unless cross_room_presence_subscriptions is on).
+pub async fn handle_subscription(
+    state: &AppState,
+    peer_id: &str,
+    client: &Client,
+    method: &str,
+    data: &HashMap<String, String>,
+) {
+    let requested: Vec<String> = data
+        .get("peerIds")
+        .map(|ids| {
+            ids.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let config = state.config.current();
+    let max = config.max_presence_subscriptions;
+    // The reply is built under the peers lock and sent after it is released,
+    // so a client that doesn't read can't hold the lock
+    let (reply, context) = {
+        let mut peers_guard = state.peers.lock().await;
+        let Some(my_room) = peers_guard.get(peer_id).map(|me| me.room.clone()) else {
+            return;
+        };
+        let online_ids: Vec<String> = peers_guard
+            .values()
+            .filter(|peer| config.cross_room_presence_subscriptions || peer.room == my_room)
+            .map(|peer| peer.peer_id.clone())
+            .collect();
+        let Some(me) = peers_guard.get_mut(peer_id) else {
+            return;
+        };
+        subscription_reply(me, &online_ids, method, requested, max)
+    };
+    send_server_message(client, &reply, context).await;
+}
+
+// Applies the (un)subscription to `me` and builds the reply with its context
+fn subscription_reply(
+    me: &mut Peer,
+    online_ids: &[String],
+    method: &str,
+    requested: Vec<String>,
+    max: usize,
+) -> (Envelope, &'static str) {
+    if method == "subscribe_presence" {
+        let new_ids = requested
+            .iter()
+            .filter(|id| !me.presence_subscriptions.contains(*id))
+            .count();
+        if me.presence_subscriptions.len() + new_ids > max {
+            let reply = error_notification(
+                "presence_subscription_limit",
+                &format!("At most {} presence subscriptions are allowed", max),
+            );
+            return (reply, "presence_subscription_limit");
+        }
+        me.presence_subscriptions.extend(requested);
+    } else {
+        for id in &requested {
+            me.presence_subscriptions.remove(id);
+        }
+    }
+
+    let mut subscribed: Vec<&String> = me.presence_subscriptions.iter().collect();
+    subscribed.sort();
+    let online: Vec<&String> = subscribed
+        .iter()
+        .copied()
+        .filter(|id| online_ids.contains(id))
+        .collect();
+    sampled!(
+        info,
+        "Presence subscriptions for {}: {:?} (online: {:?})",
+        me.peer_id, subscribed, online
+    );
+
+    let mut reply_data = HashMap::new();
+    reply_data.insert("peerIds".to_string(), join_ids(&subscribed));
+    reply_data.insert("online".to_string(), join_ids(&online));
+    (notification("presence_subscriptions", reply_data), "presence_subscriptions")
+}
+
+fn join_ids(ids: &[&String]) -> String {
+    ids.iter().map(|id| id.as_str()).collect::<Vec<_>>().join(",")
+}
+
+// Join/leave changes waiting for the end of the coalescing window, oldest first.
+// Each is the subject's room and the item {change: "joined"|"left", peerId,
+// displayName}. A flush task is running exactly while this is non-empty.
+#[derive(Default)]
+pub struct PresenceBatch {
+    // std Mutex: only held to push/take, never across .await
+    pending: Mutex<Vec<(String, DataItem)>>,
+}
+
+impl PresenceBatch {
+    // True when this change opened a new window (the caller starts the flush)
+    fn push(&self, room: &str, item: DataItem) -> bool {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push((room.to_string(), item));
+        pending.len() == 1
+    }
+
+    fn take(&self) -> Vec<(String, DataItem)> {
+        std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    // Puts changes that didn't fit in a flush back in front of anything
+    // queued since. True when the caller has to start the next flush.
+    fn requeue(&self, mut changes: Vec<(String, DataItem)>) -> bool {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let flush_running = !pending.is_empty();
+        changes.append(&mut pending);
+        *pending = changes;
+        !flush_running
+    }
+}
+
+// Splits off everything from the first leave past `max_leaves` on (0 = no
+// cap), so later changes stay behind the leaves they followed
+fn split_at_leave_cap(changes: &mut Vec<(String, DataItem)>, max_leaves: usize) -> Vec<(String, DataItem)> {
+    if max_leaves == 0 {
+        return Vec::new();
+    }
+    let is_leave = |(_, item): &(String, DataItem)| item.data.get("change").is_some_and(|c| c == "left");
+    let cut = changes
+        .iter()
+        .enumerate()
+        .filter(|(_, change)| is_leave(change))
+        .nth(max_leaves)
+        .map(|(index, _)| index);
+    match cut {
+        Some(index) => changes.split_off(index),
+        None => Vec::new(),
+    }
+}
+
+// With presence_coalesce_window_ms set, joins and leaves are not announced one
+// by one: they collect for one window, then each peer gets a single
+// "presence_update" with the changes it would have been told about as items
+// (in order, so a quick join+leave shows both) and data {joined, left} counts.
+// Leaves alone can be batched with leave_coalesce_window_ms. With
+// max_leaves_per_presence_update set, an update carries at most that many
+// leaves and data.more=true; the rest follow one window later.
+pub fn queue_change(state: &AppState, change: &str, peer_id: &str, display_name: &str, room: &str, window: Duration) {
+    let mut data = HashMap::new();
+    data.insert("change".to_string(), change.to_string());
+    data.insert("peerId".to_string(), peer_id.to_string());
+    data.insert("displayName".to_string(), display_name.to_string());
+    if state.presence_batch.push(room, DataItem { data }) {
+        tokio::spawn(flush_after(state.clone(), window));
+    }
+}
+
+// Keeps going, one window apart, while capped flushes leave changes behind
+async fn flush_after(state: AppState, window: Duration) {
+    loop {
+        tokio::time::sleep(window).await;
+        if !flush(&state).await {
+            break;
+        }
+    }
+}
+
+// Sends one presence_update round. True when deferred changes need another
+// round from this task.
+async fn flush(state: &AppState) -> bool {
+    let mut changes = state.presence_batch.take();
+    let config = state.config.current();
+    let deferred = split_at_leave_cap(&mut changes, config.max_leaves_per_presence_update);
+    let more = !deferred.is_empty();
+    let another_round = if more {
+        sampled!(info, "Deferring {} presence changes to the next update", deferred.len());
+        state.presence_batch.requeue(deferred)
+    } else {
+        false
+    };
+
+    let expires_at = config.presence_expires_at();
+    let update = |items: Vec<DataItem>| {
+        let count = |change: &str| items.iter().filter(|item| item.data.get("change").is_some_and(|c| c == change)).count();
+        let mut data = HashMap::new();
+        data.insert("joined".to_string(), count("joined").to_string());
+        data.insert("left".to_string(), count("left").to_string());
+        if let Some(expires_at) = expires_at {
+            data.insert("expiresAt".to_string(), expires_at.to_string());
+        }
+        if more {
+            data.insert("more".to_string(), "true".to_string());
+        }
+        notification_envelope(EventData {
+            method: "presence_update".to_string(),
+            data,
+            items,
+            payload: Vec::new(),
+            checksum: Vec::new(),
+        })
+    };
+    state.shadow.observe(&update(changes.iter().map(|(_, item)| item.clone()).collect()));
+
+    let cross_room = config.cross_room_presence_subscriptions;
+    let peers_guard = state.peers.lock().await;
+    for (id, peer) in peers_guard.iter() {
+        let visible: Vec<DataItem> = changes
+            .iter()
+            .filter(|(room, item)| {
+                item.data.get("peerId").is_some_and(|subject| receives_presence_of(peer, subject, room, cross_room))
+            })
+            .map(|(_, item)| item.clone())
+            .collect();
+        if !visible.is_empty() {
+            let ctx = format!("presence_update → {}", id);
+            queue_server_message(&peer.sender, &update(visible), &ctx);
+        }
+    }
+    info!("Sent coalesced presence_update with {} changes", changes.len());
+    another_round
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testing::TestServer;
+
+    fn changes(update: &EventData) -> Vec<(&str, &str)> {
+        update.items.iter().map(|item| (item.data["change"].as_str(), item.data["peerId"].as_str())).collect()
+    }
+
+    #[tokio::test]
+    async fn rapid_joins_arrive_as_one_update() {
+        let server = TestServer::start(ServerConfig { presence_coalesce_window_ms: 500, ..Default::default() }).await;
+        let mut ann = server.join("ann", "red").await;
+        let _bob = server.join("bob", "red").await;
+        let _cat = server.join("cat", "red").await;
+        let dan = server.join("dan", "red").await;
+        drop(dan);
+
+        let update = ann.expect("presence_update").await;
+        assert_eq!(update.data["joined"], "3");
+        assert_eq!(update.data["left"], "1");
+        assert_eq!(changes(&update), [("joined", "bob"), ("joined", "cat"), ("joined", "dan"), ("left", "dan")]);
+        ann.expect_no("presence_update", Duration::from_millis(700)).await;
+    }
+
+    #[tokio::test]
+    async fn without_a_window_joins_are_announced_one_by_one() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let _bob = server.join("bob", "red").await;
+        let _cat = server.join("cat", "red").await;
+        assert_eq!(ann.expect("peer_joined").await.data["peerId"], "bob");
+        assert_eq!(ann.expect("peer_joined").await.data["peerId"], "cat");
+        ann.expect_no("presence_update", Duration::from_millis(300)).await;
+    }
+
+    #[tokio::test]
+    async fn subscriptions_stay_inside_the_room_unless_cross_room_is_on() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.join("ann", "red").await;
+        let _bob = server.join("bob", "blue").await;
+        ann.request("subscribe_presence", &[("peerIds", "bob")]).await;
+        let reply = ann.expect("presence_subscriptions").await;
+        assert_eq!(reply.data["peerIds"], "bob");
+        assert_eq!(reply.data["online"], "");
+        let cat = server.join("cat", "blue").await;
+        ann.request("subscribe_presence", &[("peerIds", "cat")]).await;
+        ann.expect("presence_subscriptions").await;
+        drop(cat);
+        ann.expect_no("peer_left", Duration::from_millis(300)).await;
+
+        let server = TestServer::start(ServerConfig { cross_room_presence_subscriptions: true, ..Default::default() }).await;
+        let mut ann = server.join("ann", "red").await;
+        let bob = server.join("bob", "blue").await;
+        ann.request("subscribe_presence", &[("peerIds", "bob")]).await;
+        assert_eq!(ann.expect("presence_subscriptions").await.data["online"], "bob");
+        drop(bob);
+        assert_eq!(ann.expect("peer_left").await.data["peerId"], "bob");
+    }
+
+    fn change(kind: &str, peer_id: &str) -> (String, DataItem) {
+        let data = HashMap::from([("change".to_string(), kind.to_string()), ("peerId".to_string(), peer_id.to_string())]);
+        ("red".to_string(), DataItem { data })
+    }
+
+    #[test]
+    fn the_leave_cap_defers_from_the_first_extra_leave_on() {
+        let mut pending = vec![change("left", "a"), change("joined", "b"), change("left", "c"), change("left", "d")];
+        let deferred = split_at_leave_cap(&mut pending, 2);
+        let ids = |changes: &[(String, DataItem)]| {
+            changes.iter().map(|(_, item)| item.data["peerId"].clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&pending), ["a", "b", "c"]);
+        assert_eq!(ids(&deferred), ["d"]);
+        assert!(split_at_leave_cap(&mut pending, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_mass_disconnect_is_batched_without_losing_leaves() {
+        let config = ServerConfig { leave_coalesce_window_ms: 300, max_leaves_per_presence_update: 2, ..Default::default() };
+        let server = TestServer::start(config).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut leaving = Vec::new();
+        for peer_id in ["p1", "p2", "p3", "p4", "p5"] {
+            leaving.push(server.join(peer_id, "red").await);
+            // Joins still go out at once
+            assert_eq!(ann.expect("peer_joined").await.data["peerId"], peer_id);
+        }
+        drop(leaving);
+
+        let mut left = Vec::new();
+        let mut updates = 0;
+        while left.len() < 5 {
+            let update = ann.expect("presence_update").await;
+            updates += 1;
+            let batch: Vec<String> = update.items.iter().map(|item| item.data["peerId"].clone()).collect();
+            assert!(batch.len() <= 2, "{:?}", batch);
+            assert_eq!(update.data.contains_key("more"), left.len() + batch.len() < 5);
+            left.extend(batch);
+        }
+        // 5 leaves in 3 frames instead of 5 peer_left notifications
+        assert_eq!(updates, 3);
+        left.sort();
+        assert_eq!(left, ["p1", "p2", "p3", "p4", "p5"]);
+        ann.expect_no("peer_left", Duration::from_millis(500)).await;
+    }
+}
+
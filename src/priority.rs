@@ -0,0 +1,136 @@
+use futures_util::SinkExt;
+use axum::extract::ws::{Message as WsMessage, WebSocket};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+// After this many frames in a row from higher lanes, the writer serves a
+// waiting lower-lane frame first, so bulk traffic still drains under a steady
+// stream of control/normal frames (at worst 1 in BULK_TURN_EVERY)
+const BULK_TURN_EVERY: usize = 8;
+
+// Outbound lanes, highest first. Every frame to a client goes through one;
+// server traffic is Normal. Clients pick the lane for messages they relay
+// with data.priority on chat_message (see handle_socket).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    // Application control (e.g. a relayed "mute" command): jumps the queue
+    Control,
+    Normal,
+    // Large or non-urgent traffic, sent when nothing more urgent is waiting
+    Bulk,
+}
+
+impl Priority {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "control" => Some(Self::Control),
+            "normal" => Some(Self::Normal),
+            "bulk" => Some(Self::Bulk),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Control => "control",
+            Self::Normal => "normal",
+            Self::Bulk => "bulk",
+        }
+    }
+}
+
+// A frame waiting to be written, and where to report how the write went
+// (None for fan-out frames nobody waits for)
+pub type Outgoing = (WsMessage, Option<oneshot::Sender<Result<(), axum::Error>>>);
+
+// Shared by a client's handle and its writer task
+#[derive(Default)]
+pub struct QueueStats {
+    // Frames queued or being written: the peer's outbound queue depth
+    pub queued: AtomicUsize,
+    // Frames dropped because a lane was full, or that failed to be written
+    pub dropped: AtomicU64,
+    // Woken whenever the queue becomes empty (see drain_outbound)
+    pub drained: Notify,
+}
+
+impl QueueStats {
+    // Called once per frame that left the queue, written or not
+    pub fn finished(&self) {
+        if self.queued.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.drained.notify_waiters();
+        }
+    }
+}
+
+// Each lane holds at most outbound_lane_capacity frames (see config.rs)
+pub struct Lanes {
+    pub control: mpsc::Sender<Outgoing>,
+    pub normal: mpsc::Sender<Outgoing>,
+    pub bulk: mpsc::Sender<Outgoing>,
+}
+
+impl Lanes {
+    pub fn get(&self, priority: Priority) -> &mpsc::Sender<Outgoing> {
+        match priority {
+            Priority::Control => &self.control,
+            Priority::Normal => &self.normal,
+            Priority::Bulk => &self.bulk,
+        }
+    }
+}
+
+// Starts the task that owns the socket's sending half and writes queued
+// frames, highest lane first. It ends (closing the socket) once the lanes
+// are dropped, i.e. when the last handle to the client goes away.
+pub fn spawn_writer(
+    mut sink: futures_util::stream::SplitSink<WebSocket, WsMessage>,
+    capacity: usize,
+    stats: Arc<QueueStats>,
+) -> Lanes {
+    let capacity = capacity.max(1);
+    let (control, mut control_rx) = mpsc::channel::<Outgoing>(capacity);
+    let (normal, mut normal_rx) = mpsc::channel::<Outgoing>(capacity);
+    let (bulk, mut bulk_rx) = mpsc::channel::<Outgoing>(capacity);
+
+    tokio::spawn(async move {
+        let mut since_lower_turn = 0;
+        loop {
+            // Starvation guard: give the lowest waiting lane a turn
+            let lower_turn = if since_lower_turn >= BULK_TURN_EVERY {
+                bulk_rx.try_recv().or_else(|_| normal_rx.try_recv()).ok()
+            } else {
+                None
+            };
+            let next = match lower_turn {
+                Some(next) => {
+                    since_lower_turn = 0;
+                    next
+                }
+                None => {
+                    let next = tokio::select! {
+                        biased;
+                        Some(next) = control_rx.recv() => next,
+                        Some(next) = normal_rx.recv() => next,
+                        Some(next) = bulk_rx.recv() => next,
+                        else => break,
+                    };
+                    since_lower_turn += 1;
+                    next
+                }
+            };
+            let (msg, done) = next;
+            let result = sink.send(msg).await;
+            if result.is_err() {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(done) = done {
+                let _ = done.send(result);
+            }
+            stats.finished();
+        }
+    });
+
+    Lanes { control, normal, bulk }
+}
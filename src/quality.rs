@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::generated::Envelope;
+use crate::{notification, ClientSender};
+
+// Per-connection state behind the "connection_quality" report, for peers that
+// connected with ?caps=quality.
+//
+// Every report interval the server sends the report, then a ping with a fresh
+// nonce; the matching pong gives the RTT shown in the next report. So rttMs is
+// always the latest completed round trip, and is left out until there is one.
+// queueDepth is the outbound queue (same count the slow-client check uses) and
+// droppedMessages is how many frames to this peer failed to be written.
+#[derive(Default)]
+pub struct QualityProbe {
+    next_nonce: u64,
+    // Ping we are waiting on: (nonce, when it was sent)
+    outstanding: Option<(Vec<u8>, Instant)>,
+    rtt: Option<Duration>,
+}
+
+impl QualityProbe {
+    // Payload for the next ping. A ping still unanswered is forgotten, so a
+    // late pong for it counts as unsolicited.
+    pub fn start_ping(&mut self) -> Vec<u8> {
+        self.next_nonce += 1;
+        let nonce = format!("quality-{}", self.next_nonce).into_bytes();
+        self.outstanding = Some((nonce.clone(), Instant::now()));
+        nonce
+    }
+
+    // True if `payload` answers our ping (the RTT is recorded)
+    pub fn on_pong(&mut self, payload: &[u8]) -> bool {
+        match &self.outstanding {
+            Some((nonce, sent_at)) if nonce.as_slice() == payload => {
+                self.rtt = Some(sent_at.elapsed());
+                self.outstanding = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn report(&self, client: &ClientSender) -> Envelope {
+        let mut data = HashMap::new();
+        if let Some(rtt) = self.rtt {
+            data.insert("rttMs".to_string(), rtt.as_millis().to_string());
+        }
+        data.insert("queueDepth".to_string(), client.queue_depth().to_string());
+        data.insert("droppedMessages".to_string(), client.dropped_count().to_string());
+        notification("connection_quality", data)
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::capabilities::Capabilities;
+use crate::checksum::ChecksumAlgorithm;
+use crate::generated::{Envelope, EventData};
+use crate::logging::sampled;
+use crate::{error_notification, notification_envelope, queue_server_message, send_server_message, AppState, Client};
+
+// Framed binary frames, for clients that connect with ?caps=framed.
+//
+// The first byte of every binary frame such a client sends says what follows:
+//   0 = a protobuf Envelope, handled exactly like an unframed frame
+//   1 = raw application bytes, relayed untouched to the other framed peers
+//       inside a "raw_relay" notification: data {fromPeerId}, bytes in `payload`
+//   2 = like 1, but prefixed with a checksum of the bytes (peers with the
+//       `checksum` capability only, see checksum.rs)
+// Any other first byte (or an empty frame) is rejected with an error.
+// Only client -> server frames are framed; everything the server sends is a
+// plain Envelope as usual. Clients without the capability are unaffected.
+pub const KIND_ENVELOPE: u8 = 0;
+pub const KIND_RAW: u8 = 1;
+pub const KIND_RAW_CHECKSUMMED: u8 = 2;
+
+pub enum Frame {
+    Envelope(Vec<u8>),
+    // checksum is already verified when present
+    Raw { bytes: Vec<u8>, checksum: Option<(ChecksumAlgorithm, Vec<u8>)> },
+}
+
+// Strips and checks the kind byte (and the checksum, for kind 2).
+// On error the client has been told why.
+pub async fn unframe(
+    client: &Client,
+    mut frame: Vec<u8>,
+    capabilities: &Capabilities,
+    algorithm: ChecksumAlgorithm,
+) -> Option<Frame> {
+    match frame.first().copied() {
+        Some(KIND_ENVELOPE) => {
+            frame.remove(0);
+            Some(Frame::Envelope(frame))
+        }
+        Some(KIND_RAW) => {
+            frame.remove(0);
+            Some(Frame::Raw { bytes: frame, checksum: None })
+        }
+        Some(KIND_RAW_CHECKSUMMED) if capabilities.checksum => {
+            let problem = if frame.len() < 1 + algorithm.output_len() {
+                format!("frame is too short for a {} checksum", algorithm.as_str())
+            } else {
+                let bytes = frame.split_off(1 + algorithm.output_len());
+                let claimed = frame.split_off(1);
+                let actual = algorithm.compute(&bytes);
+                if claimed == actual {
+                    return Some(Frame::Raw { bytes, checksum: Some((algorithm, actual)) });
+                }
+                format!("{} checksum does not match the {} payload bytes", algorithm.as_str(), bytes.len())
+            };
+            // Never relay data that may be corrupt
+            warn!("Rejected checksummed raw frame: {}", problem);
+            let reply = error_notification("checksum_mismatch", &problem);
+            send_server_message(client, &reply, "checksum_mismatch").await;
+            None
+        }
+        kind => {
+            let message = match kind {
+                Some(KIND_RAW_CHECKSUMMED) => {
+                    "frame kind 2 (checksummed raw) needs the checksum capability".to_string()
+                }
+                Some(kind) => format!("unknown frame kind {} (expected 0 = envelope, 1 = raw)", kind),
+                None => "empty frame: missing the frame kind byte".to_string(),
+            };
+            warn!("Rejected framed binary frame: {}", message);
+            let reply = error_notification("unknown_frame_kind", &message);
+            send_server_message(client, &reply, "unknown_frame_kind").await;
+            None
+        }
+    }
+}
+
+// Sends raw bytes from `peer_id` to every other peer in `room` that declared
+// `framed`, with the sender's checksum (if any) passed along unchanged
+pub async fn relay(
+    state: &AppState,
+    peer_id: &str,
+    room: &str,
+    bytes: Vec<u8>,
+    checksum: Option<(ChecksumAlgorithm, Vec<u8>)>,
+) {
+    let len = bytes.len();
+    let mut data = HashMap::new();
+    data.insert("fromPeerId".to_string(), peer_id.to_string());
+    let checksum = match checksum {
+        Some((algorithm, checksum)) => {
+            data.insert("checksumAlgorithm".to_string(), algorithm.as_str().to_string());
+            checksum
+        }
+        None => Vec::new(),
+    };
+    let message: Envelope = notification_envelope(EventData {
+        method: "raw_relay".to_string(),
+        data,
+        items: Vec::new(),
+        payload: bytes,
+        checksum,
+    });
+    state.shadow.observe(&message);
+
+    let peers_guard = state.peers.lock().await;
+    let mut delivered = 0;
+    for (id, peer) in peers_guard.iter() {
+        if id != peer_id
+            && peer.room == room
+            && peer.capabilities.framed
+            && queue_server_message(&peer.sender, &message, "raw_relay")
+        {
+            delivered += 1;
+        }
+    }
+    sampled!(info, "Relayed {} raw bytes from {} to {} peers", len, peer_id, delivered);
+}
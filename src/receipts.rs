@@ -0,0 +1,67 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Remembers who sent each recent chat message (messageId -> sender peer id),
+// so a "mark_read" can be routed back to the sender as a "read_receipt".
+// Bounded two ways: entries older than `ttl` are dropped, and past
+// `max_entries` the oldest go first. Unknown/expired ids just get no receipt.
+pub struct ReadReceipts {
+    max_entries: usize,
+    ttl: Duration,
+    // std Mutex: only held for map operations, never across .await
+    inner: Mutex<Tracked>,
+}
+
+#[derive(Default)]
+struct Tracked {
+    senders: HashMap<u64, String>,
+    // Insertion (= time) order, for expiry and eviction from the front
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl ReadReceipts {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            max_entries,
+            ttl,
+            inner: Mutex::new(Tracked::default()),
+        }
+    }
+
+    pub fn track(&self, message_id: u64, sender_peer_id: String) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut tracked = self.lock();
+        self.expire(&mut tracked);
+        if tracked.order.len() == self.max_entries {
+            if let Some((oldest, _)) = tracked.order.pop_front() {
+                tracked.senders.remove(&oldest);
+            }
+        }
+        tracked.senders.insert(message_id, sender_peer_id);
+        tracked.order.push_back((message_id, Instant::now()));
+    }
+
+    // Peer id that sent `message_id`, if it is still tracked
+    pub fn sender_of(&self, message_id: u64) -> Option<String> {
+        let mut tracked = self.lock();
+        self.expire(&mut tracked);
+        tracked.senders.get(&message_id).cloned()
+    }
+
+    fn expire(&self, tracked: &mut Tracked) {
+        while let Some(&(message_id, tracked_at)) = tracked.order.front() {
+            if tracked_at.elapsed() < self.ttl {
+                break;
+            }
+            tracked.order.pop_front();
+            tracked.senders.remove(&message_id);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracked> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::ws::Message as WsMessage;
+use tracing::warn;
+
+use crate::logging::sampled;
+use crate::priority::Priority;
+use crate::Client;
+
+// Acknowledged delivery for important server messages (e.g. announcements
+// sent with requireAck), to peers that connected with ?caps=reliable.
+//
+// Such a message carries data {requiresAck: "true", ackId}. The client
+// answers with a "delivery_ack" {ackId} request. Without an ack within
+// ack_timeout_ms the same frame is sent again, up to ack_max_retries times,
+// then the server gives up and logs it. Retransmits are identical, so clients
+// should drop an ackId they have already seen (and ack it again).
+// Peers without the capability get the message once, as before.
+
+static NEXT_ACK_ID: AtomicU64 = AtomicU64::new(1);
+
+// Server-wide, so an ackId never means two things to one client. A broadcast
+// uses one id for all its recipients.
+pub fn next_ack_id() -> u64 {
+    NEXT_ACK_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Ack ids one connection hasn't acknowledged yet
+#[derive(Default)]
+pub struct PendingAcks {
+    // std Mutex: never held across .await
+    ids: Mutex<HashSet<u64>>,
+}
+
+impl PendingAcks {
+    fn insert(&self, ack_id: u64) {
+        self.ids.lock().unwrap_or_else(|e| e.into_inner()).insert(ack_id);
+    }
+
+    // True if the id was still pending
+    fn remove(&self, ack_id: u64) -> bool {
+        self.ids.lock().unwrap_or_else(|e| e.into_inner()).remove(&ack_id)
+    }
+
+    fn contains(&self, ack_id: u64) -> bool {
+        self.ids.lock().unwrap_or_else(|e| e.into_inner()).contains(&ack_id)
+    }
+}
+
+// Queues `frame` (already carrying requiresAck and ackId) for `client`, then
+// retransmits it in the background until acked or out of retries.
+// Returns whether the first copy was queued.
+// The retransmit task only holds a weak reference: once the connection is
+// gone (closed, or taken over by a resumed one) it stops without a warning.
+pub fn deliver(client: &Client, frame: WsMessage, ack_id: u64, timeout: Duration, max_retries: u32) -> bool {
+    client.pending_acks.insert(ack_id);
+    let queued = client.try_send_with_priority(frame.clone(), Priority::Normal);
+
+    let client = Arc::downgrade(client);
+    tokio::spawn(async move {
+        for retry in 1..=max_retries {
+            tokio::time::sleep(timeout).await;
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            if !client.pending_acks.contains(ack_id) {
+                return;
+            }
+            sampled!(debug, "No ack for ackId {} yet, retransmit {}/{}", ack_id, retry, max_retries);
+            client.try_send_with_priority(frame.clone(), Priority::Normal);
+        }
+        tokio::time::sleep(timeout).await;
+        let Some(client) = client.upgrade() else {
+            return;
+        };
+        if client.pending_acks.remove(ack_id) {
+            warn!(
+                "Giving up on ackId {}: not acknowledged after {} retransmits",
+                ack_id, max_retries
+            );
+        }
+    });
+    queued
+}
+
+// Handles a "delivery_ack" {ackId} request
+pub fn handle_ack(client: &Client, peer_id: &str, data: &HashMap<String, String>) {
+    let Some(ack_id) = data.get("ackId").and_then(|id| id.parse::<u64>().ok()) else {
+        sampled!(debug, "delivery_ack without a valid ackId from {}", peer_id);
+        return;
+    };
+    if client.pending_acks.remove(ack_id) {
+        sampled!(debug, "{} acknowledged ackId {}", peer_id, ack_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Secret, ServerConfig};
+    use crate::testing::TestServer;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn a_client_acks_after_one_retry() {
+        let token = "announce-secret";
+        let config = ServerConfig {
+            announce_token: Some(Secret::new(token)),
+            ack_timeout_ms: 200,
+            ack_max_retries: 3,
+            ..Default::default()
+        };
+        let server = TestServer::start(config).await;
+        let mut ann = server.connect("peerId=ann&caps=reliable").await;
+        ann.expect("peer_list_chunk").await;
+        let mut bob = server.join("bob", "lobby").await;
+
+        let announcement = json!({"message": "Maintenance at noon", "requireAck": true});
+        let (status, _) = server.http(Method::POST, "/api/announce", Some(token), Some(announcement)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let first = ann.expect("system").await;
+        assert_eq!(first.data["requiresAck"], "true");
+        let ack_id = first.data["ackId"].clone();
+        // Not acked: the same message comes again
+        let retry = ann.expect("system").await;
+        assert_eq!(retry.data["ackId"], ack_id);
+        assert_eq!(retry.data["message"], "Maintenance at noon");
+
+        ann.request("delivery_ack", &[("ackId", &ack_id)]).await;
+        ann.expect_no("system", Duration::from_millis(600)).await;
+
+        // Peers without the capability get it once, with nothing to ack
+        let plain = bob.expect("system").await;
+        assert_eq!(plain.data.get("ackId"), None);
+        bob.expect_no("system", Duration::from_millis(300)).await;
+    }
+
+    #[tokio::test]
+    async fn retransmits_stop_after_the_last_retry() {
+        let token = "announce-secret";
+        let config = ServerConfig {
+            announce_token: Some(Secret::new(token)),
+            ack_timeout_ms: 100,
+            ack_max_retries: 2,
+            ..Default::default()
+        };
+        let server = TestServer::start(config).await;
+        let mut ann = server.connect("peerId=ann&caps=reliable").await;
+        ann.expect("peer_list_chunk").await;
+
+        let announcement = json!({"message": "hello", "requireAck": true});
+        server.http(Method::POST, "/api/announce", Some(token), Some(announcement)).await;
+        for _ in 0..3 {
+            ann.expect("system").await;
+        }
+        ann.expect_no("system", Duration::from_millis(400)).await;
+    }
+
+    #[tokio::test]
+    async fn retransmits_stop_when_the_connection_goes_away() {
+        let token = "announce-secret";
+        let config = ServerConfig {
+            announce_token: Some(Secret::new(token)),
+            ack_timeout_ms: 100,
+            ack_max_retries: 20,
+            ..Default::default()
+        };
+        let server = TestServer::start(config).await;
+        let mut ann = server.connect("peerId=ann&caps=reliable").await;
+        ann.expect("peer_list_chunk").await;
+        let connection = Arc::downgrade(&server.state.peers.lock().await["ann"].sender);
+
+        let announcement = json!({"message": "hello", "requireAck": true});
+        server.http(Method::POST, "/api/announce", Some(token), Some(announcement)).await;
+        ann.expect("system").await;
+        ann.expect("system").await;
+        drop(ann);
+
+        // Well before the retries run out (2s), the retransmit task has let
+        // go of the closed connection and stopped
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(600);
+        while connection.upgrade().is_some() {
+            assert!(tokio::time::Instant::now() < deadline, "the retransmit task kept the connection alive");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Per-room chat message rates over sliding windows, for spotting hot rooms
+// (GET /api/stats, and the "room_stats" request for a client's own room).
+//
+// Each room keeps a ring of one-second buckets covering the longest window,
+// so its memory is fixed no matter how busy it is. A bucket is reset when
+// the ring comes back around to it. Rooms with nothing in the longest window
+// are dropped, so the map only holds recently active rooms.
+const BUCKETS: usize = 60;
+const SHORT_WINDOW_SECS: u64 = 10;
+const LONG_WINDOW_SECS: u64 = BUCKETS as u64;
+
+pub struct RoomRates {
+    started: Instant,
+    // std Mutex: never held across .await
+    rooms: Mutex<HashMap<String, BucketRing>>,
+}
+
+struct BucketRing {
+    // (second since `started` the bucket belongs to, messages in it)
+    buckets: [(u64, u32); BUCKETS],
+    last_second: u64,
+}
+
+impl BucketRing {
+    fn new() -> Self {
+        // u64::MAX: belongs to no second yet, so counts nowhere
+        Self { buckets: [(u64::MAX, 0); BUCKETS], last_second: 0 }
+    }
+
+    fn record(&mut self, second: u64) {
+        let bucket = &mut self.buckets[(second % BUCKETS as u64) as usize];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 = bucket.1.saturating_add(1);
+        self.last_second = second;
+    }
+
+    // Messages in the last `window` seconds, the current one included
+    fn count(&self, now: u64, window: u64) -> u64 {
+        self.buckets
+            .iter()
+            .filter(|(second, _)| *second <= now && now - second < window)
+            .map(|(_, count)| *count as u64)
+            .sum()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomRate {
+    pub room: String,
+    pub messages_last_10s: u64,
+    pub messages_last_60s: u64,
+    // Average over the last 60s
+    pub per_second: f64,
+}
+
+impl Default for RoomRates {
+    fn default() -> Self {
+        Self { started: Instant::now(), rooms: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl RoomRates {
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    // One chat message broadcast in `room`
+    pub fn record(&self, room: &str) {
+        let now = self.now();
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        if !rooms.contains_key(room) {
+            rooms.retain(|_, ring| now - ring.last_second < LONG_WINDOW_SECS);
+        }
+        rooms.entry(room.to_string()).or_insert_with(BucketRing::new).record(now);
+    }
+
+    pub fn room(&self, room: &str) -> RoomRate {
+        let now = self.now();
+        let rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        let ring = rooms.get(room);
+        rate(room, ring, now)
+    }
+
+    // Every recently active room, busiest (over the short window) first
+    pub fn snapshot(&self) -> Vec<RoomRate> {
+        let now = self.now();
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        rooms.retain(|_, ring| now - ring.last_second < LONG_WINDOW_SECS);
+        let mut rates: Vec<RoomRate> = rooms.iter().map(|(room, ring)| rate(room, Some(ring), now)).collect();
+        rates.sort_by(|a, b| {
+            b.messages_last_10s
+                .cmp(&a.messages_last_10s)
+                .then(b.messages_last_60s.cmp(&a.messages_last_60s))
+                .then_with(|| a.room.cmp(&b.room))
+        });
+        rates
+    }
+}
+
+fn rate(room: &str, ring: Option<&BucketRing>, now: u64) -> RoomRate {
+    let count = |window| ring.map_or(0, |ring| ring.count(now, window));
+    let messages_last_60s = count(LONG_WINDOW_SECS);
+    RoomRate {
+        room: room.to_string(),
+        messages_last_10s: count(SHORT_WINDOW_SECS),
+        messages_last_60s,
+        per_second: messages_last_60s as f64 / LONG_WINDOW_SECS as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_shows_in_both_windows_then_decays() {
+        let mut ring = BucketRing::new();
+        for _ in 0..30 {
+            ring.record(100);
+        }
+        let at = |now| rate("red", Some(&ring), now);
+        assert_eq!((at(100).messages_last_10s, at(100).messages_last_60s), (30, 30));
+        assert_eq!(at(100).per_second, 0.5);
+        // Out of the short window, still in the long one
+        assert_eq!((at(110).messages_last_10s, at(110).messages_last_60s), (0, 30));
+        assert_eq!((at(159).messages_last_10s, at(159).messages_last_60s), (0, 30));
+        assert_eq!(at(160).messages_last_60s, 0);
+    }
+
+    #[test]
+    fn a_reused_bucket_forgets_its_old_second() {
+        let mut ring = BucketRing::new();
+        ring.record(5);
+        ring.record(5);
+        // Same bucket, one lap later
+        ring.record(65);
+        assert_eq!(ring.count(65, LONG_WINDOW_SECS), 1);
+    }
+
+    #[test]
+    fn busiest_rooms_come_first() {
+        let rates = RoomRates::default();
+        rates.record("quiet");
+        for _ in 0..3 {
+            rates.record("busy");
+        }
+        let rooms: Vec<(String, u64)> =
+            rates.snapshot().into_iter().map(|rate| (rate.room, rate.messages_last_10s)).collect();
+        assert_eq!(rooms, [("busy".to_string(), 3), ("quiet".to_string(), 1)]);
+        assert_eq!(rates.room("nowhere").messages_last_60s, 0);
+    }
+}
@@ -0,0 +1,580 @@
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::features;
+use crate::generated::DataItem;
+use crate::history::HistoryEntry;
+use crate::{peer_list, AppState, Client};
+
+// State a room keeps beyond its peers (which live in AppState::peers, by
+// Peer::room): password, topic and pinned messages (see pins.rs). An entry
+// is created when the first peer registers in the room, or when an admin
+// configures it (PUT /api/rooms/{room}).
+//
+// Password-protected rooms: whoever joins an empty, unprotected room with
+// ?room_password= protects it with that password; after that every join
+// must bring it. Joining an open room with a password is fine, the password
+// is just ignored. Only a PBKDF2 hash is kept. The check is split in two so
+// the slow hashing stays outside the peers lock:
+//   1. ws_handler: `check` compares the password (403 before the upgrade).
+//      PBKDF2 runs on the blocking pool, at most MAX_CONCURRENT_HASHING at
+//      a time, and only when the password counts: the room is protected, or
+//      it is empty and the join may protect it.
+//   2. handle_socket, under the peers lock: `admit` confirms the room still
+//      has the password that was checked, and protects a new room
+// A join that passed 1 but finds the password changed at 2 (an admin reset
+// it, or another first joiner protected the room in between) is turned
+// away with a "room_password_invalid" error and a Close.
+//
+// Lobby: a "list_rooms" request gets the rooms that have peers in them as
+// "room_list_chunk" notifications, chunked like the peer list (see
+// peer_list.rs), items {name, peerCount, plus topic when set and
+// protected=true for password-protected rooms}, sorted by name. With
+// hide_protected_room_topics, protected rooms are listed without their topic.
+// Topics are set by admins (PUT /api/rooms/{room}).
+//
+// Empty rooms are swept (see sweep_empty_rooms): a room nobody has been in
+// for empty_room_grace_secs loses its state, so a room that empties only
+// briefly keeps it. Rooms an admin configured are never swept: their
+// password, topic and pinners stay until an admin changes them. Sweeps hold the peers lock like `admit` does, so a room
+// can't be dropped while a peer is registering into it. Chat history is a
+// server-wide ring buffer, so it isn't touched; it ages out by itself.
+pub struct RoomRegistry {
+    // std Mutex: never held across .await
+    rooms: Mutex<HashMap<String, RoomState>>,
+    // Permits for PBKDF2 runs started by joins
+    hashing: Arc<Semaphore>,
+}
+
+impl Default for RoomRegistry {
+    fn default() -> Self {
+        Self { rooms: Mutex::default(), hashing: Arc::new(Semaphore::new(MAX_CONCURRENT_HASHING)) }
+    }
+}
+
+#[derive(Default)]
+struct RoomState {
+    password: Option<Arc<PasswordHash>>,
+    topic: Option<String>,
+    // Oldest first (see pins.rs)
+    pins: Vec<Pin>,
+    // Peer id patterns allowed to pin; None = everyone in the room
+    pinners: Option<Vec<String>>,
+    // When a sweep first found the room empty; None while occupied
+    empty_since: Option<Instant>,
+    // Set up through PUT /api/rooms/{room}, so never swept
+    configured: bool,
+}
+
+#[derive(Clone)]
+pub struct Pin {
+    pub entry: HistoryEntry,
+    pub pinned_by: String,
+}
+
+// Why `pin` refused
+#[derive(Debug, PartialEq, Eq)]
+pub enum PinRefusal {
+    AlreadyPinned,
+    LimitReached,
+}
+
+// Longest topic an admin may set, in characters
+pub const MAX_TOPIC_CHARS: usize = 200;
+
+const PBKDF2_ITERATIONS: u32 = 10_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+// PBKDF2 runs joins may have going at once; more wait for a permit
+const MAX_CONCURRENT_HASHING: usize = 4;
+
+pub struct PasswordHash {
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Self {
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).expect("system random number generator");
+        let mut hash = [0; HASH_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &salt, password.as_bytes(), &mut hash);
+        Self { salt, hash }
+    }
+
+    // pbkdf2::verify compares in constant time
+    pub fn matches(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PBKDF2_ITERATIONS).expect("non-zero")
+}
+
+// The outcome of `check`, handed to `admit`
+pub enum Admission {
+    // Open room, nothing to confirm
+    Open,
+    // The room had no password; protects it if this peer turns out to be first
+    Protect(Arc<PasswordHash>),
+    // The password matched the room's current one
+    Verified(Arc<PasswordHash>),
+}
+
+impl RoomRegistry {
+    // Err is the message for the rejected upgrade. `occupied` = someone else
+    // is in the room, so a password for an open room would be ignored anyway.
+    pub async fn check(&self, room: &str, password: Option<&str>, occupied: bool) -> Result<Admission, &'static str> {
+        let current = self.lock().get(room).and_then(|state| state.password.clone());
+        let password = password.filter(|password| !password.is_empty()).map(str::to_string);
+        match (current, password) {
+            (None, None) => Ok(Admission::Open),
+            (None, Some(_)) if occupied => Ok(Admission::Open),
+            (None, Some(password)) => {
+                let hash = self.hash_off_executor(move || PasswordHash::new(&password)).await;
+                Ok(Admission::Protect(Arc::new(hash)))
+            }
+            (Some(_), None) => Err("this room requires room_password"),
+            (Some(current), Some(password)) => {
+                let checked = current.clone();
+                if self.hash_off_executor(move || checked.matches(&password)).await {
+                    Ok(Admission::Verified(current))
+                } else {
+                    Err("wrong room_password")
+                }
+            }
+        }
+    }
+
+    // Runs PBKDF2 work on the blocking pool once a permit is free. The permit
+    // goes with the work, so an upgrade given up on can't free it early.
+    async fn hash_off_executor<T: Send + 'static>(&self, work: impl FnOnce() -> T + Send + 'static) -> T {
+        let permit = self.hashing.clone().acquire_owned().await.expect("the hashing semaphore is never closed");
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        })
+        .await
+        .expect("password hashing panicked")
+    }
+
+    // Called under the peers lock as the peer registers. `first` = nobody
+    // else is in the room. False when the room's password changed since `check`.
+    pub fn admit(&self, room: &str, admission: Admission, first: bool) -> bool {
+        let mut rooms = self.lock();
+        let state = rooms.entry(room.to_string()).or_default();
+        state.empty_since = None;
+        match (&state.password, admission) {
+            (None, Admission::Protect(hash)) if first => {
+                state.password = Some(hash);
+                true
+            }
+            (None, _) => true,
+            (Some(current), Admission::Verified(checked)) => Arc::ptr_eq(current, &checked),
+            (Some(_), _) => false,
+        }
+    }
+
+    // Admin override; None (or "") opens the room
+    pub fn set_password(&self, room: &str, password: Option<&str>) {
+        let hash = password.filter(|password| !password.is_empty()).map(|password| Arc::new(PasswordHash::new(password)));
+        self.configure(room, |state| state.password = hash);
+    }
+
+    pub fn is_protected(&self, room: &str) -> bool {
+        self.lock().get(room).is_some_and(|state| state.password.is_some())
+    }
+
+    // Admin override; None (or "") clears it
+    pub fn set_topic(&self, room: &str, topic: Option<&str>) {
+        let topic = topic.filter(|topic| !topic.is_empty()).map(str::to_string);
+        self.configure(room, |state| state.topic = topic);
+    }
+
+    pub fn topic(&self, room: &str) -> Option<String> {
+        self.lock().get(room).and_then(|state| state.topic.clone())
+    }
+
+    // Admin override; None (or an empty list) lets everyone in the room pin.
+    // Patterns match like feature_rules ("mod_*" by prefix).
+    pub fn set_pinners(&self, room: &str, pinners: Option<Vec<String>>) {
+        let pinners = pinners.filter(|pinners| !pinners.is_empty());
+        self.configure(room, |state| state.pinners = pinners);
+    }
+
+    pub fn pinners(&self, room: &str) -> Option<Vec<String>> {
+        self.lock().get(room).and_then(|state| state.pinners.clone())
+    }
+
+    pub fn may_pin(&self, room: &str, peer_id: &str) -> bool {
+        match self.lock().get(room).and_then(|state| state.pinners.as_ref()) {
+            Some(pinners) => pinners.iter().any(|pattern| features::matches(pattern, peer_id)),
+            None => true,
+        }
+    }
+
+    pub fn pin(&self, room: &str, pin: Pin, limit: usize) -> Result<(), PinRefusal> {
+        let mut rooms = self.lock();
+        let pins = &mut rooms.entry(room.to_string()).or_default().pins;
+        if pins.iter().any(|pinned| pinned.entry.message_id == pin.entry.message_id) {
+            return Err(PinRefusal::AlreadyPinned);
+        }
+        if pins.len() >= limit {
+            return Err(PinRefusal::LimitReached);
+        }
+        pins.push(pin);
+        Ok(())
+    }
+
+    // False when the message wasn't pinned
+    pub fn unpin(&self, room: &str, message_id: u64) -> bool {
+        let mut rooms = self.lock();
+        let Some(state) = rooms.get_mut(room) else {
+            return false;
+        };
+        let before = state.pins.len();
+        state.pins.retain(|pinned| pinned.entry.message_id != message_id);
+        state.pins.len() != before
+    }
+
+    pub fn pins(&self, room: &str) -> Vec<Pin> {
+        self.lock().get(room).map(|state| state.pins.clone()).unwrap_or_default()
+    }
+
+    // One room_list_chunk item per room in `peer_counts`
+    fn list(&self, peer_counts: &HashMap<&str, usize>, hide_protected_topics: bool) -> Vec<DataItem> {
+        let rooms = self.lock();
+        let mut names: Vec<&str> = peer_counts.keys().copied().collect();
+        names.sort_unstable();
+        names
+            .into_iter()
+            .map(|name| {
+                let mut data = HashMap::new();
+                data.insert("name".to_string(), name.to_string());
+                data.insert("peerCount".to_string(), peer_counts[name].to_string());
+                if let Some(state) = rooms.get(name) {
+                    let protected = state.password.is_some();
+                    if protected {
+                        data.insert("protected".to_string(), "true".to_string());
+                    }
+                    if let Some(topic) = state.topic.as_ref().filter(|_| !(protected && hide_protected_topics)) {
+                        data.insert("topic".to_string(), topic.clone());
+                    }
+                }
+                DataItem { data }
+            })
+            .collect()
+    }
+
+    // Drops rooms that were empty on every sweep for `grace` and returns
+    // their names. `occupied`: rooms with peers in them, taken under the
+    // peers lock, which must still be held.
+    fn sweep(&self, occupied: &HashSet<&str>, grace: Duration) -> Vec<String> {
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+        self.lock().retain(|room, state| {
+            if state.configured || occupied.contains(room.as_str()) {
+                state.empty_since = None;
+                return true;
+            }
+            let empty_since = *state.empty_since.get_or_insert(now);
+            if now.duration_since(empty_since) < grace {
+                return true;
+            }
+            reaped.push(room.clone());
+            false
+        });
+        reaped
+    }
+
+    // Applies an admin change; the room is never swept after one
+    fn configure(&self, room: &str, change: impl FnOnce(&mut RoomState)) {
+        let mut rooms = self.lock();
+        let state = rooms.entry(room.to_string()).or_default();
+        state.configured = true;
+        change(state);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, RoomState>> {
+        self.rooms.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Answers "list_rooms"
+pub async fn send_room_list(state: &AppState, client: &Client) {
+    let hide_protected_topics = state.config.current().hide_protected_room_topics;
+    // Snapshot under the lock, send after releasing it
+    let rooms = {
+        let peers_guard = state.peers.lock().await;
+        let mut peer_counts: HashMap<&str, usize> = HashMap::new();
+        for peer in peers_guard.values() {
+            *peer_counts.entry(peer.room.as_str()).or_default() += 1;
+        }
+        state.rooms.list(&peer_counts, hide_protected_topics)
+    };
+    peer_list::send_chunked(state, client, "room_list_chunk", rooms).await;
+}
+
+// Background task (see run), when empty_room_sweep_interval_secs > 0
+pub async fn sweep_empty_rooms(state: AppState, interval: Duration) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        sweep(&state).await;
+    }
+}
+
+async fn sweep(state: &AppState) {
+    let grace = Duration::from_secs(state.config.current().empty_room_grace_secs);
+    let peers_guard = state.peers.lock().await;
+    let occupied: HashSet<&str> = peers_guard.values().map(|peer| peer.room.as_str()).collect();
+    for room in state.rooms.sweep(&occupied, grace) {
+        debug!("Reaped empty room {}", room);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Secret, ServerConfig};
+    use crate::testing::TestServer;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+
+    async fn rejected_status(server: &TestServer, query: &str) -> Option<StatusCode> {
+        match server.try_connect(query).await {
+            Err(WsError::Http(response)) => Some(response.status()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn first_joiner_protects_the_room() {
+        let rooms = RoomRegistry::default();
+        let admission = rooms.check("red", Some("hunter2"), false).await.expect("open room");
+        assert!(rooms.admit("red", admission, true));
+        assert!(rooms.is_protected("red"));
+
+        assert_eq!(rooms.check("red", None, true).await.err(), Some("this room requires room_password"));
+        assert_eq!(rooms.check("red", Some("hunter3"), true).await.err(), Some("wrong room_password"));
+        let admission = rooms.check("red", Some("hunter2"), true).await.expect("right password");
+        assert!(rooms.admit("red", admission, false));
+    }
+
+    #[tokio::test]
+    async fn open_rooms_ignore_a_late_password() {
+        let rooms = RoomRegistry::default();
+        assert!(rooms.admit("red", rooms.check("red", None, false).await.unwrap(), true));
+        // Someone is already in: their room stays open, and nothing is hashed
+        let admission = rooms.check("red", Some("mine"), true).await.unwrap();
+        assert!(matches!(admission, Admission::Open));
+        assert!(rooms.admit("red", admission, false));
+        assert!(!rooms.is_protected("red"));
+    }
+
+    #[tokio::test]
+    async fn hashing_is_bounded() {
+        let rooms = RoomRegistry::default();
+        let _busy = rooms.hashing.clone().acquire_many_owned(MAX_CONCURRENT_HASHING as u32).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(100), rooms.check("red", Some("hunter2"), false));
+        assert!(waiting.await.is_err(), "hashed without a permit");
+        // A password that would be ignored needs no permit
+        let ignored = tokio::time::timeout(Duration::from_millis(100), rooms.check("red", Some("hunter2"), true));
+        assert!(matches!(ignored.await, Ok(Ok(Admission::Open))));
+    }
+
+    #[tokio::test]
+    async fn a_password_change_after_the_check_is_caught() {
+        let rooms = RoomRegistry::default();
+        rooms.set_password("red", Some("old"));
+        let admission = rooms.check("red", Some("old"), false).await.expect("right password");
+        rooms.set_password("red", Some("new"));
+        assert!(!rooms.admit("red", admission, false));
+
+        // Protected by another first joiner in between
+        let admission = rooms.check("blue", None, false).await.expect("open room");
+        assert!(rooms.admit("blue", rooms.check("blue", Some("theirs"), false).await.unwrap(), true));
+        assert!(!rooms.admit("blue", admission, false));
+    }
+
+    #[tokio::test]
+    async fn joining_a_protected_room_needs_its_password() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut ann = server.connect("peerId=ann&room=red&room_password=hunter2").await;
+        ann.expect("peer_list_chunk").await;
+
+        assert_eq!(rejected_status(&server, "peerId=bob&room=red").await, Some(StatusCode::FORBIDDEN));
+        assert_eq!(
+            rejected_status(&server, "peerId=bob&room=red&room_password=hunter3").await,
+            Some(StatusCode::FORBIDDEN)
+        );
+        let mut bob = server.connect("peerId=bob&room=red&room_password=hunter2").await;
+        bob.expect("peer_list_chunk").await;
+        // Other rooms are unaffected
+        server.join("cat", "blue").await;
+    }
+
+    #[tokio::test]
+    async fn admins_set_and_clear_room_passwords() {
+        let admin = "admin-secret";
+        let server = TestServer::start(ServerConfig { admin_token: Some(Secret::new(admin)), ..Default::default() }).await;
+
+        let (status, body) =
+            server.http(Method::PUT, "/api/rooms/red", Some(admin), Some(json!({"password": "hunter2"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"room": "red", "protected": true, "peerCount": 0}));
+        assert_eq!(rejected_status(&server, "peerId=ann&room=red").await, Some(StatusCode::FORBIDDEN));
+        let mut ann = server.connect("peerId=ann&room=red&room_password=hunter2").await;
+        ann.expect("peer_list_chunk").await;
+
+        let (_, body) = server.http(Method::PUT, "/api/rooms/red", Some(admin), Some(json!({"password": ""}))).await;
+        assert_eq!(body, json!({"room": "red", "protected": false, "peerCount": 1}));
+        server.join("bob", "red").await;
+    }
+
+    // What a first joiner with ?room_password= leaves behind
+    fn protected_by_first_joiner(rooms: &RoomRegistry, room: &str) -> Arc<PasswordHash> {
+        let hash = Arc::new(PasswordHash::new("hunter2"));
+        assert!(rooms.admit(room, Admission::Protect(hash.clone()), true));
+        hash
+    }
+
+    #[test]
+    fn sweeps_rooms_empty_for_the_grace_period() {
+        let rooms = RoomRegistry::default();
+        protected_by_first_joiner(&rooms, "red");
+        protected_by_first_joiner(&rooms, "blue");
+        let grace = Duration::from_millis(50);
+
+        // First sweep only notes that red is empty
+        assert!(rooms.sweep(&HashSet::from(["blue"]), grace).is_empty());
+        std::thread::sleep(grace);
+        assert_eq!(rooms.sweep(&HashSet::from(["blue"]), grace), vec!["red".to_string()]);
+        assert!(!rooms.is_protected("red"));
+        assert!(rooms.is_protected("blue"));
+    }
+
+    #[test]
+    fn a_join_restarts_the_grace_period() {
+        let rooms = RoomRegistry::default();
+        let hash = protected_by_first_joiner(&rooms, "red");
+        let grace = Duration::from_millis(50);
+        assert!(rooms.sweep(&HashSet::new(), grace).is_empty());
+        std::thread::sleep(grace);
+
+        assert!(rooms.admit("red", Admission::Verified(hash), true));
+        assert!(rooms.sweep(&HashSet::new(), grace).is_empty());
+        assert!(rooms.is_protected("red"));
+    }
+
+    #[tokio::test]
+    async fn the_sweeper_keeps_occupied_rooms() {
+        let server = TestServer::start(ServerConfig { empty_room_grace_secs: 0, ..Default::default() }).await;
+        let mut ann = server.connect("peerId=ann&room=red&room_password=hunter2").await;
+        ann.expect("peer_list_chunk").await;
+        let mut bob = server.connect("peerId=bob&room=blue&room_password=hunter2").await;
+        bob.expect("peer_list_chunk").await;
+        assert!(server.state.rooms.is_protected("blue"));
+        drop(bob);
+        while server.state.peers.lock().await.contains_key("bob") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        sweep(&server.state).await;
+        sweep(&server.state).await;
+        assert!(server.state.rooms.is_protected("red"));
+        assert!(!server.state.rooms.is_protected("blue"));
+    }
+
+    #[tokio::test]
+    async fn rooms_an_admin_configured_are_never_swept() {
+        let admin = "admin-secret";
+        let config = ServerConfig { admin_token: Some(Secret::new(admin)), empty_room_grace_secs: 0, ..Default::default() };
+        let server = TestServer::start(config).await;
+        let settings = json!({"password": "hunter2", "topic": "Board meeting"});
+        let (status, _) = server.http(Method::PUT, "/api/rooms/board", Some(admin), Some(settings)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Nobody has joined yet; the grace period is long over
+        sweep(&server.state).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        sweep(&server.state).await;
+        assert_eq!(rejected_status(&server, "peerId=ann&room=board").await, Some(StatusCode::FORBIDDEN));
+        assert_eq!(server.state.rooms.topic("board").as_deref(), Some("Board meeting"));
+    }
+
+    fn listed(items: &[DataItem]) -> Vec<HashMap<&str, &str>> {
+        items.iter().map(|item| item.data.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()).collect()
+    }
+
+    #[test]
+    fn lists_occupied_rooms_by_name() {
+        let rooms = RoomRegistry::default();
+        rooms.set_topic("red", Some("Standup"));
+        rooms.set_topic("blue", Some("Secret plans"));
+        rooms.set_password("blue", Some("hunter2"));
+        rooms.set_topic("empty", Some("Nobody here"));
+        let peer_counts = HashMap::from([("red", 2), ("blue", 1), ("green", 1)]);
+
+        assert_eq!(
+            listed(&rooms.list(&peer_counts, false)),
+            vec![
+                HashMap::from([("name", "blue"), ("peerCount", "1"), ("protected", "true"), ("topic", "Secret plans")]),
+                HashMap::from([("name", "green"), ("peerCount", "1")]),
+                HashMap::from([("name", "red"), ("peerCount", "2"), ("topic", "Standup")]),
+            ]
+        );
+        let hidden = rooms.list(&peer_counts, true);
+        assert_eq!(listed(&hidden)[0], HashMap::from([("name", "blue"), ("peerCount", "1"), ("protected", "true")]));
+        assert_eq!(listed(&hidden)[2]["topic"], "Standup");
+    }
+
+    #[tokio::test]
+    async fn list_rooms_streams_the_lobby_in_chunks() {
+        let admin = "admin-secret";
+        let config = ServerConfig { admin_token: Some(Secret::new(admin)), peer_list_chunk_size: 2, ..Default::default() };
+        let server = TestServer::start(config).await;
+        let (status, body) = server.http(Method::PUT, "/api/rooms/red", Some(admin), Some(json!({"topic": "Standup"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"room": "red", "protected": false, "peerCount": 0, "topic": "Standup"}));
+        let _bob = server.join("bob", "blue").await;
+        let _cat = server.join("cat", "green").await;
+        let mut ann = server.join("ann", "red").await;
+
+        ann.request("list_rooms", &[]).await;
+        let first = ann.expect("room_list_chunk").await;
+        assert_eq!(first.data["total"], "3");
+        assert_eq!(first.data["last"], "false");
+        let second = ann.expect("room_list_chunk").await;
+        assert_eq!(second.data["chunk"], "1");
+        assert_eq!(second.data["last"], "true");
+        let items: Vec<DataItem> = first.items.into_iter().chain(second.items).collect();
+        assert_eq!(
+            listed(&items),
+            vec![
+                HashMap::from([("name", "blue"), ("peerCount", "1")]),
+                HashMap::from([("name", "green"), ("peerCount", "1")]),
+                HashMap::from([("name", "red"), ("peerCount", "1"), ("topic", "Standup")]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn room_topics_have_a_length_limit() {
+        let admin = "admin-secret";
+        let server = TestServer::start(ServerConfig { admin_token: Some(Secret::new(admin)), ..Default::default() }).await;
+        let topic = "x".repeat(MAX_TOPIC_CHARS + 1);
+        let (status, body) = server.http(Method::PUT, "/api/rooms/red", Some(admin), Some(json!({"topic": topic}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_topic");
+        assert_eq!(server.state.rooms.topic("red"), None);
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+// Tokio runtime internals for GET /api/debug/runtime: tells a task-starved
+// server (deep queues, idle workers) from a thread-saturated one (every
+// worker busy). Complements the application counters in metrics.rs.
+//
+// Worker count, alive tasks, global queue depth and per-worker busy time are
+// always reported. Blocking-pool threads, per-worker local queue depths,
+// polls and steals need tokio's unstable metrics API, compiled in with
+// RUSTFLAGS="--cfg tokio_unstable"; without it `detailed` is "unavailable".
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeSnapshot {
+    flavor: &'static str,
+    workers: usize,
+    alive_tasks: usize,
+    global_queue_depth: usize,
+    worker_busy_ms: Vec<u64>,
+    worker_park_counts: Vec<u64>,
+    detailed: Detailed,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Detailed {
+    #[cfg_attr(tokio_unstable, allow(dead_code))]
+    Unavailable(&'static str),
+    #[cfg_attr(not(tokio_unstable), allow(dead_code))]
+    Available(DetailedMetrics),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DetailedMetrics {
+    blocking_threads: usize,
+    idle_blocking_threads: usize,
+    blocking_queue_depth: usize,
+    spawned_tasks: u64,
+    remote_schedules: u64,
+    budget_forced_yields: u64,
+    worker_local_queue_depths: Vec<usize>,
+    worker_poll_counts: Vec<u64>,
+    worker_steal_counts: Vec<u64>,
+    worker_mean_poll_us: Vec<u64>,
+}
+
+// Reads everything in one pass; each call is a handful of atomic loads
+pub fn snapshot() -> RuntimeSnapshot {
+    let handle = Handle::current();
+    let metrics = handle.metrics();
+    let workers = metrics.num_workers();
+    RuntimeSnapshot {
+        flavor: match handle.runtime_flavor() {
+            RuntimeFlavor::CurrentThread => "current_thread",
+            RuntimeFlavor::MultiThread => "multi_thread",
+            _ => "other",
+        },
+        workers,
+        alive_tasks: metrics.num_alive_tasks(),
+        global_queue_depth: metrics.global_queue_depth(),
+        worker_busy_ms: (0..workers)
+            .map(|worker| metrics.worker_total_busy_duration(worker).as_millis() as u64)
+            .collect(),
+        worker_park_counts: (0..workers).map(|worker| metrics.worker_park_count(worker)).collect(),
+        detailed: detailed(&metrics),
+    }
+}
+
+#[cfg(tokio_unstable)]
+fn detailed(metrics: &tokio::runtime::RuntimeMetrics) -> Detailed {
+    let workers = metrics.num_workers();
+    Detailed::Available(DetailedMetrics {
+        blocking_threads: metrics.num_blocking_threads(),
+        idle_blocking_threads: metrics.num_idle_blocking_threads(),
+        blocking_queue_depth: metrics.blocking_queue_depth(),
+        spawned_tasks: metrics.spawned_tasks_count(),
+        remote_schedules: metrics.remote_schedule_count(),
+        budget_forced_yields: metrics.budget_forced_yield_count(),
+        worker_local_queue_depths: (0..workers).map(|w| metrics.worker_local_queue_depth(w)).collect(),
+        worker_poll_counts: (0..workers).map(|w| metrics.worker_poll_count(w)).collect(),
+        worker_steal_counts: (0..workers).map(|w| metrics.worker_steal_count(w)).collect(),
+        worker_mean_poll_us: (0..workers)
+            .map(|w| metrics.worker_mean_poll_time(w).as_micros() as u64)
+            .collect(),
+    })
+}
+
+#[cfg(not(tokio_unstable))]
+fn detailed(_metrics: &tokio::runtime::RuntimeMetrics) -> Detailed {
+    Detailed::Unavailable("unavailable")
+}
@@ -0,0 +1,67 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::{broadcast_system, now_ms, AppState};
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledAnnouncement {
+    pub id: String,
+    pub message: String,
+    // When it will be broadcast, ms since epoch
+    pub send_at_ms: u64,
+}
+
+// System broadcasts waiting for their time. Each one has its own timer task;
+// cancelling removes the entry and aborts the task.
+// Kept in memory only: anything still pending is lost on restart.
+#[derive(Default)]
+pub struct Scheduler {
+    // std Mutex: only held to insert/remove, never across .await
+    pending: Mutex<HashMap<String, (ScheduledAnnouncement, JoinHandle<()>)>>,
+}
+
+impl Scheduler {
+    pub fn schedule(self: &Arc<Self>, state: AppState, message: String, send_at_ms: u64) -> ScheduledAnnouncement {
+        let id = format!("sched_{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        let announcement = ScheduledAnnouncement { id: id.clone(), message, send_at_ms };
+
+        // Hold the lock while spawning so the task can't fire (and look itself
+        // up) before it has been inserted
+        let mut pending = self.lock();
+        let scheduler = Arc::clone(self);
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(send_at_ms.saturating_sub(now_ms()))).await;
+            // Whoever removes the entry owns it: if a cancel got there first, stay quiet
+            let Some((announcement, _)) = scheduler.lock().remove(&id) else {
+                return;
+            };
+            info!("Firing scheduled announcement {}", announcement.id);
+            broadcast_system(&state, &announcement.message, false).await;
+        });
+        pending.insert(announcement.id.clone(), (announcement.clone(), task));
+        announcement
+    }
+
+    // Returns the cancelled announcement, or None if it doesn't exist or already fired
+    pub fn cancel(&self, id: &str) -> Option<ScheduledAnnouncement> {
+        let (announcement, task) = self.lock().remove(id)?;
+        task.abort();
+        Some(announcement)
+    }
+
+    // Pending announcements, soonest first
+    pub fn list(&self) -> Vec<ScheduledAnnouncement> {
+        let mut list: Vec<_> = self.lock().values().map(|(announcement, _)| announcement.clone()).collect();
+        list.sort_by_key(|announcement| announcement.send_at_ms);
+        list
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (ScheduledAnnouncement, JoinHandle<()>)>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
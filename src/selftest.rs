@@ -0,0 +1,207 @@
+use futures_util::{SinkExt, StreamExt};
+use prost::Message;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+
+use crate::auth;
+use crate::config::ServerConfig;
+use crate::generated::{Envelope, EventData};
+use crate::now_ms;
+use crate::shadow::ShadowPeer;
+
+// Upper bound for the whole self-test, so a wedged server can't hang the request
+const SELFTEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Lifetime of the client tokens minted for the selftest peers
+const SELFTEST_TOKEN_TTL_SECS: u64 = 30;
+
+type TestSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestReport {
+    passed: bool,
+    total_ms: u128,
+    checks: Vec<CheckResult>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckResult {
+    name: &'static str,
+    passed: bool,
+    ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// End-to-end check of the realtime path: two loopback clients connect to our
+// own /ws listener, one sees the other join, then receives its chat message,
+// and the shadow peer saw the same broadcast server-side.
+// Stops at the first failing check. The selftest peers get a room of their
+// own, so other peers don't see them; they connect the way real clients must
+// under `config` (short-lived token, answering the initial ping).
+pub async fn run(addr: SocketAddr, shadow: &ShadowPeer, config: &ServerConfig) -> SelfTestReport {
+    let started = Instant::now();
+    let mut checks = Vec::new();
+
+    let finished = tokio::time::timeout(SELFTEST_TIMEOUT, run_checks(addr, shadow, config, &mut checks)).await;
+    if finished.is_err() {
+        checks.push(CheckResult {
+            name: "timeout",
+            passed: false,
+            ms: started.elapsed().as_millis(),
+            error: Some(format!("self-test did not finish within {:?}", SELFTEST_TIMEOUT)),
+        });
+    }
+
+    SelfTestReport {
+        passed: !checks.is_empty() && checks.iter().all(|check| check.passed),
+        total_ms: started.elapsed().as_millis(),
+        checks,
+    }
+}
+
+async fn run_checks(addr: SocketAddr, shadow: &ShadowPeer, config: &ServerConfig, checks: &mut Vec<CheckResult>) {
+    let run_id = uuid::Uuid::new_v4().simple().to_string();
+    let listener_id = format!("selftest_{}_a", &run_id[..8]);
+    let sender_id = format!("selftest_{}_b", &run_id[..8]);
+    let room = format!("selftest_{}", &run_id[..8]);
+
+    let Some(mut listener) = timed(checks, "connect_listener", connect(addr, &listener_id, &room, config)).await
+    else {
+        return;
+    };
+    let Some(mut sender) = timed(checks, "connect_sender", connect(addr, &sender_id, &room, config)).await else {
+        return;
+    };
+
+    let joined = timed(checks, "join_notification", async {
+        wait_for(&mut listener, |event| {
+            event.method == "peer_joined" && event.data.get("peerId") == Some(&sender_id)
+        })
+        .await
+    })
+    .await;
+    if joined.is_none() {
+        return;
+    }
+
+    let mut shadow_copies = shadow.subscribe();
+    let broadcast = timed(checks, "broadcast", async {
+        let mut data = HashMap::new();
+        data.insert("text".to_string(), run_id.clone());
+        let request = Envelope {
+            event: "request".to_string(),
+            event_data: Some(EventData {
+                method: "chat_message".to_string(),
+                data,
+                items: Vec::new(),
+                payload: Vec::new(),
+                checksum: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        sender
+            .send(TungsteniteMessage::Binary(request.encode_to_vec()))
+            .await
+            .map_err(|e| format!("send failed: {}", e))?;
+        wait_for(&mut listener, |event| {
+            event.method == "chat_message" && event.data.get("text") == Some(&run_id)
+        })
+        .await
+    })
+    .await;
+
+    if broadcast.is_some() {
+        timed(checks, "shadow_copy", async {
+            loop {
+                let copy = shadow_copies
+                    .recv()
+                    .await
+                    .map_err(|e| format!("shadow receive failed: {}", e))?;
+                let event = copy.event_data.unwrap_or_default();
+                if event.method == "chat_message" && event.data.get("text") == Some(&run_id) {
+                    return Ok(());
+                }
+            }
+        })
+        .await;
+    }
+
+    let _ = sender.close(None).await;
+    let _ = listener.close(None).await;
+}
+
+// Runs one sub-check, records its outcome and duration
+async fn timed<T>(
+    checks: &mut Vec<CheckResult>,
+    name: &'static str,
+    check: impl Future<Output = Result<T, String>>,
+) -> Option<T> {
+    let started = Instant::now();
+    let result = check.await;
+    let ms = started.elapsed().as_millis();
+    match result {
+        Ok(value) => {
+            checks.push(CheckResult { name, passed: true, ms, error: None });
+            Some(value)
+        }
+        Err(error) => {
+            checks.push(CheckResult { name, passed: false, ms, error: Some(error) });
+            None
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr, peer_id: &str, room: &str, config: &ServerConfig) -> Result<TestSocket, String> {
+    let mut url = format!("ws://{}/ws?peerId={}&displayName=selftest&room={}", addr, peer_id, room);
+    if let Some(secret) = &config.client_token_secret {
+        let expires_at = now_ms() / 1000 + SELFTEST_TOKEN_TTL_SECS;
+        url.push_str("&token=");
+        url.push_str(&auth::issue(secret, peer_id, expires_at));
+    }
+    let (mut socket, _response) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| format!("connect failed: {}", e))?;
+
+    // The peer is only registered once it answers the initial ping
+    if config.require_initial_pong {
+        loop {
+            match socket.next().await {
+                Some(Ok(TungsteniteMessage::Ping(_))) => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(format!("receive failed: {}", e)),
+                None => return Err("connection closed before the initial ping".to_string()),
+            }
+        }
+        // tungstenite queued the pong when it read the ping; push it out
+        socket.flush().await.map_err(|e| format!("pong failed: {}", e))?;
+    }
+    Ok(socket)
+}
+
+// Reads frames until a notification matches, skipping unrelated traffic
+async fn wait_for(
+    socket: &mut TestSocket,
+    matches: impl Fn(&EventData) -> bool,
+) -> Result<(), String> {
+    while let Some(frame) = socket.next().await {
+        let frame = frame.map_err(|e| format!("receive failed: {}", e))?;
+        let TungsteniteMessage::Binary(bytes) = frame else {
+            continue;
+        };
+        let Ok(envelope) = Envelope::decode(bytes.as_ref()) else {
+            continue;
+        };
+        if envelope.event_data.as_ref().is_some_and(&matches) {
+            return Ok(());
+        }
+    }
+    Err("connection closed before the expected notification".to_string())
+}
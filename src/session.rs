@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+use tracing::warn;
+
+// How many recent inbound message types to remember for the summary
+const RECENT_TYPES: usize = 5;
+
+// Per-connection counters accumulated in handle_socket.
+// Only reported when the session ends with an error, as one searchable line,
+// so normal disconnects stay quiet.
+pub struct SessionSummary {
+    started_at: Instant,
+    messages_in: u64,
+    bytes_in: u64,
+    messages_out: u64, // frames this connection's task wrote to its own client
+    recent_types: VecDeque<String>,
+}
+
+impl SessionSummary {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            messages_in: 0,
+            bytes_in: 0,
+            messages_out: 0,
+            recent_types: VecDeque::with_capacity(RECENT_TYPES),
+        }
+    }
+
+    // Record one inbound frame ("binary", "text", "ping", ...)
+    pub fn record_in(&mut self, kind: &str, bytes: usize) {
+        self.messages_in += 1;
+        self.bytes_in += bytes as u64;
+        if self.recent_types.len() == RECENT_TYPES {
+            self.recent_types.pop_front();
+        }
+        self.recent_types.push_back(kind.to_string());
+    }
+
+    // Refine the last recorded type with the decoded method, e.g. "binary:chat_message"
+    pub fn record_method(&mut self, method: &str) {
+        if let Some(last) = self.recent_types.back_mut() {
+            last.push(':');
+            last.push_str(method);
+        }
+    }
+
+    pub fn record_out(&mut self) {
+        self.messages_out += 1;
+    }
+
+    // The single log record for a session that ended in an error
+    pub fn log_error(&self, peer_id: &str, display_name: &str, error: &str) {
+        warn!(
+            "Session error: peer_id={} display_name={} duration={:.1}s messages_in={} bytes_in={} messages_out={} recent={:?} error={}",
+            peer_id,
+            display_name,
+            self.started_at.elapsed().as_secs_f64(),
+            self.messages_in,
+            self.bytes_in,
+            self.messages_out,
+            self.recent_types,
+            error
+        );
+    }
+}
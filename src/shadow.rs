@@ -0,0 +1,56 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+
+use crate::generated::Envelope;
+
+// How many copies a slow shadow subscriber may fall behind before it
+// starts missing some (it is told how many it lost)
+const SHADOW_BUFFER: usize = 256;
+
+// The "shadow peer": an in-process subscriber that gets a copy of every
+// broadcast (chat, system, join/leave) without being a connection. It is
+// never in the peers map, so it never shows up in peer lists, counts or
+// presence. Used by the self-test and GET /api/shadow to check broadcast
+// behaviour from the server side. With no subscribers, observing is a no-op.
+pub struct ShadowPeer {
+    copies: broadcast::Sender<Envelope>,
+}
+
+impl Default for ShadowPeer {
+    fn default() -> Self {
+        Self {
+            copies: broadcast::channel(SHADOW_BUFFER).0,
+        }
+    }
+}
+
+impl ShadowPeer {
+    // Called on the broadcast paths with the envelope that went out
+    pub fn observe(&self, envelope: &Envelope) {
+        if self.copies.receiver_count() > 0 {
+            let _ = self.copies.send(envelope.clone());
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.copies.subscribe()
+    }
+}
+
+// JSON view of a captured broadcast
+#[derive(Serialize)]
+pub struct ShadowCopy {
+    method: String,
+    data: HashMap<String, String>,
+}
+
+impl From<Envelope> for ShadowCopy {
+    fn from(envelope: Envelope) -> Self {
+        let event_data = envelope.event_data.unwrap_or_default();
+        Self {
+            method: event_data.method,
+            data: event_data.data,
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use futures_util::{SinkExt, StreamExt};
+use prost::Message as _;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::bridge::UpstreamBridge;
+use crate::config::ServerConfig;
+use crate::generated::{Envelope, EventData};
+use crate::peer_id::PeerIdRules;
+use crate::{app, shut_down, AppState};
+
+// In-process server and clients for tests that need real connections.
+// Each TestServer listens on its own ephemeral port with exactly the config
+// it was given (no env overrides), and shuts down like on SIGTERM when asked.
+
+// How long a test waits for something it expects to arrive
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+pub struct TestServer {
+    pub state: AppState,
+    pub addr: SocketAddr,
+    app: Router,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start(config: ServerConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind an ephemeral port");
+        let addr = listener.local_addr().expect("local address");
+        let peer_id_rules = PeerIdRules::from_config(&config).expect("valid peer id rules");
+        let (upstream, _) = UpstreamBridge::new(&config);
+        let state = AppState::new(config, peer_id_rules, addr, upstream);
+        let app = app(state.clone());
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown = {
+            let state = state.clone();
+            async move {
+                let _ = stopped.await;
+                shut_down(&state).await;
+            }
+        };
+        let service = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown)
+                .await
+                .expect("test server failed");
+        });
+        Self { state, addr, app, stop, task }
+    }
+
+    // `query` is the /ws query string, e.g. "peerId=alice&room=red"
+    pub async fn connect(&self, query: &str) -> TestClient {
+        self.try_connect(query)
+            .await
+            .unwrap_or_else(|e| panic!("connect with '{}' failed: {}", query, e))
+    }
+
+    pub async fn try_connect(&self, query: &str) -> Result<TestClient, tokio_tungstenite::tungstenite::Error> {
+        let url = format!("ws://{}/ws?{}", self.addr, query);
+        let (socket, _response) = tokio_tungstenite::connect_async(url).await?;
+        Ok(TestClient { socket })
+    }
+
+    // Connects and waits until the server has registered the peer, which the
+    // peer list sent right after registration tells
+    pub async fn join(&self, peer_id: &str, room: &str) -> TestClient {
+        let mut client = self.connect(&format!("peerId={}&displayName={}&room={}", peer_id, peer_id, room)).await;
+        client.expect("peer_list_chunk").await;
+        client
+    }
+
+    // One HTTP request through the router, as if it came in on the listener.
+    // The body is JSON when given; so is the response body, when there is one.
+    pub async fn http(
+        &self,
+        method: Method,
+        path: &str,
+        bearer: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(token) = bearer {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let body = match body {
+            Some(json) => {
+                request = request.header("content-type", "application/json");
+                Body::from(json.to_string())
+            }
+            None => Body::empty(),
+        };
+        let mut request = request.body(body).expect("valid request");
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+        let response = tower_service::Service::call(&mut self.app.clone(), request)
+            .await
+            .expect("router is infallible");
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.expect("response body");
+        let json = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        (status, json)
+    }
+
+    // Triggers the shutdown and waits for the server task. False when it
+    // didn't finish within `within`.
+    pub async fn shut_down(self, within: Duration) -> bool {
+        let _ = self.stop.send(());
+        tokio::time::timeout(within, self.task).await.is_ok()
+    }
+}
+
+pub struct TestClient {
+    socket: Socket,
+}
+
+impl TestClient {
+    // Sends a request the way protobuf clients do
+    pub async fn request(&mut self, method: &str, data: &[(&str, &str)]) {
+        let data: HashMap<String, String> = data.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let request = Envelope {
+            event: "request".to_string(),
+            event_data: Some(EventData {
+                method: method.to_string(),
+                data,
+                items: Vec::new(),
+                payload: Vec::new(),
+                checksum: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        self.send(Message::Binary(request.encode_to_vec())).await;
+    }
+
+    pub async fn send(&mut self, frame: Message) {
+        self.socket.send(frame).await.expect("send to the test server");
+    }
+
+    // The next frame of any kind, None when the connection ended or nothing
+    // came within `within`
+    pub async fn next_frame(&mut self, within: Duration) -> Option<Message> {
+        match tokio::time::timeout(within, self.socket.next()).await {
+            Ok(Some(Ok(frame))) => Some(frame),
+            _ => None,
+        }
+    }
+
+    // The next notification, skipping frames that aren't one
+    pub async fn next_event(&mut self, within: Duration) -> Option<EventData> {
+        let deadline = tokio::time::Instant::now() + within;
+        loop {
+            let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+            match self.next_frame(left).await? {
+                Message::Binary(bytes) => {
+                    if let Some(event) = Envelope::decode(bytes.as_ref()).ok().and_then(|envelope| envelope.event_data) {
+                        return Some(event);
+                    }
+                }
+                Message::Close(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    // Waits for a `method` notification, skipping others
+    pub async fn expect(&mut self, method: &str) -> EventData {
+        loop {
+            match self.next_event(RECEIVE_TIMEOUT).await {
+                Some(event) if event.method == method => return event,
+                Some(_) => {}
+                None => panic!("no '{}' notification arrived", method),
+            }
+        }
+    }
+
+    // Asserts no `method` notification arrives within `within`
+    pub async fn expect_no(&mut self, method: &str, within: Duration) {
+        let deadline = tokio::time::Instant::now() + within;
+        loop {
+            let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+            match self.next_event(left).await {
+                Some(event) if event.method == method => panic!("unexpected '{}': {:?}", method, event.data),
+                Some(_) => {}
+                None => return,
+            }
+        }
+    }
+
+    // Waits for the server's Close frame, skipping everything before it
+    pub async fn expect_close(&mut self) -> Option<CloseFrame<'static>> {
+        loop {
+            match self.next_frame(RECEIVE_TIMEOUT).await {
+                Some(Message::Close(frame)) => return frame,
+                Some(_) => {}
+                None => panic!("the connection was not closed"),
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, RootCertStore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+use x509_parser::extensions::GeneralName;
+
+use crate::config::ServerConfig;
+
+// Optional TLS, and mutual TLS on top of it (clients must present a
+// certificate signed by our CA). Off unless tls_cert_path and tls_key_path
+// are set; client certificates are required once tls_client_ca_path is set.
+//
+// With mTLS, the certificate is the peer's identity: its subject CN (or, when
+// there is none, its first DNS/email/URI SAN) becomes the peerId, and any
+// ?peerId= is ignored. It must still pass the peerId rules, so e.g. email
+// identities need PEER_ID_EXTRA_CHARS="-_@.".
+//
+// Setup, with a private CA (PEM files throughout):
+//   openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=chat-ca" \
+//     -keyout ca.key -out ca.pem
+//   # server certificate, with the host name clients connect to
+//   openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" -keyout server.key -out server.csr
+//   openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 365 \
+//     -extfile <(printf "subjectAltName=DNS:localhost,IP:127.0.0.1") -out server.pem
+//   # one certificate per client; its CN is the peerId
+//   openssl req -newkey rsa:2048 -nodes -subj "/CN=alice" -keyout alice.key -out alice.csr
+//   openssl x509 -req -in alice.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 365 \
+//     -extfile <(printf "extendedKeyUsage=clientAuth") -out alice.pem
+//   TLS_CERT_PATH=server.pem TLS_KEY_PATH=server.key TLS_CLIENT_CA_PATH=ca.pem cargo run
+// Clients then connect to wss://localhost:7878/ws with alice.pem/alice.key.
+// The self-test (GET /api/selftest) connects over plain ws:// and so
+// doesn't work while TLS is on.
+
+// A client that opens a TCP connection but never finishes the handshake
+// holds a task and a socket until this runs out
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The verified certificate identity of the connection a request came in on,
+// attached to every request (see serve)
+#[derive(Clone, Debug)]
+pub struct ClientCertIdentity(pub String);
+
+// The acceptor for the configured certificates, None when TLS is off
+pub fn load_acceptor(config: &ServerConfig) -> Result<Option<TlsAcceptor>, String> {
+    let (cert_path, key_path) = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) if config.tls_client_ca_path.is_some() => {
+            return Err("tls_client_ca_path needs tls_cert_path and tls_key_path".to_string());
+        }
+        (None, None) => return Ok(None),
+        _ => return Err("tls_cert_path and tls_key_path must be set together".to_string()),
+    };
+
+    let certs = read_certs(cert_path)?;
+    let key = read_key(key_path)?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match &config.tls_client_ca_path {
+        None => builder.with_no_client_auth(),
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca_path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("unusable CA certificate in '{}': {}", ca_path, e))?;
+            }
+            // Rejects handshakes without a valid client certificate
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| format!("cannot use the CA in '{}': {}", ca_path, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("cannot use the certificate in '{}': {}", cert_path, e))?;
+    // WebSocket upgrades are HTTP/1.1 only
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+// The TLS version of axum::serve: a handshake per accepted connection, then
+// HTTP/1.1 with upgrades. Stops accepting once `shutdown` resolves.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    acceptor: TlsAcceptor,
+    tcp_nodelay: bool,
+    shutdown: impl Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return,
+        };
+        let (tcp, remote) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // e.g. out of file descriptors: back off like axum::serve does
+                warn!("Accept failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let _ = tcp.set_nodelay(tcp_nodelay);
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("TLS handshake with {} failed: {}", remote, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {} timed out after {:?}", remote, TLS_HANDSHAKE_TIMEOUT);
+                    return;
+                }
+            };
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|cert| certificate_identity(cert));
+            let service = hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+                // What into_make_service_with_connect_info provides on the plain path
+                request.extensions_mut().insert(ConnectInfo(remote));
+                if let Some(identity) = &identity {
+                    request.extensions_mut().insert(ClientCertIdentity(identity.clone()));
+                }
+                tower_service::Service::call(&mut app.clone(), request)
+            });
+            let connection = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            if let Err(e) = connection.await {
+                debug!("TLS connection with {} ended: {}", remote, e);
+            }
+        });
+    }
+}
+
+// Subject CN, else the first DNS/email/URI subject alternative name
+fn certificate_identity(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    if let Some(cn) = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+        return Some(cn.to_string());
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+            Some(name.to_string())
+        }
+        _ => None,
+    })
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("cannot read '{}': {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid PEM in '{}': {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in '{}'", path));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("cannot read '{}': {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid PEM in '{}': {}", path, e))?
+        .ok_or_else(|| format!("no private key in '{}'", path))
+}
@@ -0,0 +1,59 @@
+use tracing::debug;
+
+use crate::generated::EventData;
+
+// A server-side rewrite applied to a chat message before it is broadcast
+// (link shortening, emoji replacement, content rewriting, ...).
+// Operates on the outbound notification's EventData so it can touch any key.
+pub trait MessageTransform: Send + Sync {
+    // Short name used in logs
+    fn name(&self) -> &str;
+
+    fn transform(&self, event_data: &mut EventData);
+}
+
+// Leaves the message untouched. The default pipeline.
+pub struct IdentityTransform;
+
+impl MessageTransform for IdentityTransform {
+    fn name(&self) -> &str {
+        "identity"
+    }
+
+    fn transform(&self, _event_data: &mut EventData) {}
+}
+
+// Ordered list of transforms shared through AppState.
+// Ordering: transforms run in the order they were added, and each one sees
+// the output of the previous one. The result is built once per message and
+// the same transformed message goes to every recipient.
+pub struct TransformPipeline {
+    transforms: Vec<Box<dyn MessageTransform>>,
+}
+
+impl Default for TransformPipeline {
+    fn default() -> Self {
+        Self {
+            transforms: vec![Box::new(IdentityTransform)],
+        }
+    }
+}
+
+impl TransformPipeline {
+    #[allow(dead_code)] // extension point for custom transforms
+    pub fn with(mut self, transform: Box<dyn MessageTransform>) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+
+    pub fn apply(&self, event_data: &mut EventData) {
+        for transform in &self.transforms {
+            transform.transform(event_data);
+            debug!(
+                "Applied transform '{}' to {}",
+                transform.name(),
+                event_data.method
+            );
+        }
+    }
+}
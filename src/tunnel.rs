@@ -0,0 +1,93 @@
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use axum::extract::ws::{Message as WsMessage, WebSocket};
+
+use crate::config::DEFAULT_OUTBOUND_LANE_CAPACITY;
+use crate::encoding::Encoding;
+use crate::{Client, ClientSender};
+
+// Tunnel mode: two connections that share a `tunnel_id` query param are
+// paired, and every frame (text, binary, ping, pong, close) from one is
+// forwarded verbatim to the other. No protobuf decoding, no peers map,
+// no presence: the server is just a pipe.
+//
+// Key: tunnel_id, Value: the (at most two) connected ends
+pub type Tunnels = Arc<Mutex<HashMap<String, Vec<TunnelEnd>>>>;
+
+// A tunnel never has more than two ends
+pub const MAX_TUNNEL_ENDS: usize = 2;
+
+pub struct TunnelEnd {
+    conn_id: uuid::Uuid,
+    sender: Client,
+}
+
+// Early check in ws_handler so a third peer is refused before upgrading
+pub async fn is_full(tunnels: &Tunnels, tunnel_id: &str) -> bool {
+    tunnels
+        .lock()
+        .await
+        .get(tunnel_id)
+        .is_some_and(|ends| ends.len() >= MAX_TUNNEL_ENDS)
+}
+
+pub async fn handle_tunnel(socket: WebSocket, tunnels: Tunnels, tunnel_id: String) {
+    let (sender, mut receiver) = socket.split();
+    let client: Client = Arc::new(ClientSender::new(sender, Encoding::Protobuf, false, DEFAULT_OUTBOUND_LANE_CAPACITY));
+    let conn_id = uuid::Uuid::new_v4();
+
+    // Re-check under the lock: another peer may have joined since ws_handler looked
+    {
+        let mut tunnels_guard = tunnels.lock().await;
+        let ends = tunnels_guard.entry(tunnel_id.clone()).or_default();
+        if ends.len() >= MAX_TUNNEL_ENDS {
+            drop(tunnels_guard);
+            warn!("Tunnel '{}' already has two peers, closing", tunnel_id);
+            let _ = client.send(WsMessage::Close(None)).await;
+            return;
+        }
+        ends.push(TunnelEnd {
+            conn_id,
+            sender: client.clone(),
+        });
+        info!("Tunnel '{}' now has {} end(s)", tunnel_id, ends.len());
+    }
+
+    while let Some(Ok(msg)) = receiver.next().await {
+        let is_close = matches!(msg, WsMessage::Close(_));
+
+        // Look up the partner, then send without holding the tunnels lock
+        let partner = tunnels.lock().await.get(&tunnel_id).and_then(|ends| {
+            ends.iter()
+                .find(|end| end.conn_id != conn_id)
+                .map(|end| end.sender.clone())
+        });
+        match partner {
+            Some(partner) => {
+                let _ = partner.send(msg).await;
+            }
+            None => debug!(
+                "Tunnel '{}' has no partner yet, dropping frame",
+                tunnel_id
+            ),
+        }
+
+        if is_close {
+            break;
+        }
+    }
+
+    // Unpair; the remaining end stays and can be paired again
+    let mut tunnels_guard = tunnels.lock().await;
+    if let Some(ends) = tunnels_guard.get_mut(&tunnel_id) {
+        ends.retain(|end| end.conn_id != conn_id);
+        if ends.is_empty() {
+            tunnels_guard.remove(&tunnel_id);
+        }
+    }
+    info!("Tunnel '{}' end disconnected", tunnel_id);
+}
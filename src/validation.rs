@@ -0,0 +1,152 @@
+use serde::Deserialize;
+
+use crate::generated::Envelope;
+
+// How picky the server is about frames that decode fine but look wrong.
+// Checked right after a binary frame decodes, before dispatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    // Accept what can be used, fill gaps with defaults and ignore the rest
+    // (e.g. a chat_message without text is relayed as empty text)
+    #[default]
+    Lenient,
+    // Reject only what the server doesn't know: an event other than
+    // "request" or an unknown method. Gaps are still filled with defaults.
+    RejectUnknown,
+    // RejectUnknown, plus reject missing event_data, an empty method and
+    // methods missing a required data key (or sending it empty)
+    Strict,
+}
+
+// Methods a client may send, with the data keys each one needs to be useful.
+// Keep in sync with the dispatch in handle_socket.
+const KNOWN_METHODS: &[(&str, &[&str])] = &[
+    ("chat_message", &["text"]),
+    ("encrypted_message", &["payload"]),
+    ("mark_read", &["messageId"]),
+    ("list_peers", &[]),
+    ("list_rooms", &[]),
+    ("pin_message", &["messageId"]),
+    ("unpin_message", &["messageId"]),
+    ("subscribe_presence", &["peerIds"]),
+    ("unsubscribe_presence", &["peerIds"]),
+    ("set_groups", &[]),
+    ("group_message", &["group", "text"]),
+    ("set_metadata", &["appVersion"]),
+    ("set_content_filter", &[]),
+    ("delivery_ack", &["ackId"]),
+    ("typing", &[]),
+    ("room_stats", &[]),
+];
+
+// Why a frame was rejected, sent back as an "invalid_message" error
+pub fn check(mode: ValidationMode, envelope: &Envelope) -> Result<(), String> {
+    if mode == ValidationMode::Lenient {
+        return Ok(());
+    }
+    if envelope.event != "request" {
+        return Err(format!("unexpected event '{}', clients send \"request\"", envelope.event));
+    }
+    let Some(event_data) = &envelope.event_data else {
+        return match mode {
+            ValidationMode::Strict => Err("missing event_data".to_string()),
+            _ => Ok(()),
+        };
+    };
+    if event_data.method.is_empty() {
+        return match mode {
+            ValidationMode::Strict => Err("empty method".to_string()),
+            _ => Ok(()),
+        };
+    }
+    let Some((_, required)) = KNOWN_METHODS.iter().find(|(method, _)| *method == event_data.method) else {
+        return Err(format!("unknown method '{}'", event_data.method));
+    };
+    if mode == ValidationMode::Strict {
+        let missing = required
+            .iter()
+            .find(|key| event_data.data.get(**key).is_none_or(|value| value.is_empty()));
+        if let Some(key) = missing {
+            return Err(format!("{} requires a non-empty '{}'", event_data.method, key));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::generated::EventData;
+    use crate::testing::TestServer;
+    use std::collections::HashMap;
+
+    fn envelope(event: &str, method: Option<&str>, data: &[(&str, &str)]) -> Envelope {
+        Envelope {
+            event: event.to_string(),
+            event_data: method.map(|method| EventData {
+                method: method.to_string(),
+                data: data.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    // What each mode makes of `envelope`: None = accepted
+    fn verdicts(envelope: &Envelope) -> [Option<String>; 3] {
+        let modes = [ValidationMode::Lenient, ValidationMode::RejectUnknown, ValidationMode::Strict];
+        modes.map(|mode| check(mode, envelope).err())
+    }
+
+    #[test]
+    fn well_formed_requests_pass_every_mode() {
+        assert_eq!(verdicts(&envelope("request", Some("chat_message"), &[("text", "hi")])), [None, None, None]);
+        assert_eq!(verdicts(&envelope("request", Some("list_peers"), &[])), [None, None, None]);
+    }
+
+    #[test]
+    fn unknown_events_and_methods_are_rejected_unless_lenient() {
+        let wrong_event = envelope("response", Some("chat_message"), &[("text", "hi")]);
+        let [lenient, reject_unknown, strict] = verdicts(&wrong_event);
+        assert_eq!(lenient, None);
+        assert_eq!(reject_unknown.as_deref(), Some("unexpected event 'response', clients send \"request\""));
+        assert_eq!(strict, reject_unknown);
+
+        let [lenient, reject_unknown, strict] = verdicts(&envelope("request", Some("chat_messages"), &[]));
+        assert_eq!(lenient, None);
+        assert_eq!(reject_unknown.as_deref(), Some("unknown method 'chat_messages'"));
+        assert_eq!(strict, reject_unknown);
+    }
+
+    #[test]
+    fn gaps_are_only_rejected_when_strict() {
+        let missing_event_data = verdicts(&envelope("request", None, &[]));
+        assert_eq!(missing_event_data, [None, None, Some("missing event_data".to_string())]);
+        let empty_method = verdicts(&envelope("request", Some(""), &[]));
+        assert_eq!(empty_method, [None, None, Some("empty method".to_string())]);
+
+        let missing_text = Some("chat_message requires a non-empty 'text'".to_string());
+        assert_eq!(verdicts(&envelope("request", Some("chat_message"), &[])), [None, None, missing_text.clone()]);
+        assert_eq!(verdicts(&envelope("request", Some("chat_message"), &[("text", "")])), [None, None, missing_text]);
+        // Extra keys are fine
+        assert_eq!(verdicts(&envelope("request", Some("typing"), &[("extra", "")])), [None, None, None]);
+    }
+
+    #[tokio::test]
+    async fn strict_servers_answer_with_invalid_message() {
+        let config = ServerConfig { validation_mode: ValidationMode::Strict, ..Default::default() };
+        let server = TestServer::start(config).await;
+        let mut ann = server.join("ann", "red").await;
+        let mut bob = server.join("bob", "red").await;
+
+        ann.request("chat_message", &[("text", "")]).await;
+        let error = ann.expect("error").await;
+        assert_eq!(error.data["code"], "invalid_message");
+        assert_eq!(error.data["message"], "chat_message requires a non-empty 'text'");
+
+        ann.request("chat_message", &[("text", "hi")]).await;
+        assert_eq!(bob.expect("chat_message").await.data["text"], "hi");
+    }
+}